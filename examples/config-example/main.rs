@@ -1,5 +1,5 @@
 use log::info;
-use yeerugina::structs::Config;
+use yeerugina::config::{Config, TopicMode};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 	env_logger::init();
@@ -15,6 +15,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 	println!("{my_config:?}");
 
+	for lamp in my_config.lamps.iter() {
+		let topic = match lamp.topic_mode {
+			TopicMode::Single => my_config.mqtt.command_topic(&lamp.id),
+			TopicMode::PerProperty => my_config.mqtt.property_topic(&lamp.id, "+"),
+		};
+		println!("Lamp {} ({}) listens on {topic}", lamp.name, lamp.id);
+	}
+
+	for group in my_config.groups.keys() {
+		println!(
+			"Group {group} contains {:?}",
+			my_config.group_members(group)?
+		);
+	}
+
 	println!(
 		"When we lose connection, we say {} and that's that!",
 		my_config.mqtt.lwt_payload
@@ -1,27 +1,43 @@
-use crate::lamp::Lamp;
-use crate::structs::Effect;
 use log::debug;
+use std::io;
 use std::time::Duration;
+use yeerugina::lamp::Lamp;
+use yeerugina::mock::{MockLamp, MockState};
+use yeerugina::structs::{ConnectionSettings, Effect};
 
+/// A Lamp connected to a MockLamp running in the same process.
+#[derive(Debug)]
 pub struct DummyLamp {
+	// Keep the mock alive as long as the lamp
+	pub mock: MockLamp,
 	// Expose the inner Lamp
 	pub lamp: Lamp,
 }
 
 impl DummyLamp {
-	pub fn new() -> Self {
-		Self {
-			lamp: Lamp::new(
-				String::from("dummylamp"),
-				String::from("127.0.0.1:6666"),
-				Effect::default(),
-				Duration::from_millis(1500),
-			),
-		}
+	pub fn new() -> io::Result<Self> {
+		let mock = MockLamp::start(MockState::default())?;
+		let lamp = Lamp::new(
+			String::from("dummylamp"),
+			mock.addr().to_string(),
+			Effect::default(),
+			Duration::from_millis(1500),
+		)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		Ok(Self { mock, lamp })
 	}
 
-	// Dummy connect method
-	pub fn connect(&mut self) -> () {
-		debug!("Dummy connect called");
+	// Connect the lamp to the mock
+	pub fn connect(&mut self) -> io::Result<()> {
+		debug!("Connecting to mock lamp at {}", self.mock.addr());
+		let timeout = Duration::from_secs(1);
+		self.lamp.connect(ConnectionSettings {
+			read_timeout: Some(timeout),
+			write_timeout: Some(timeout),
+			conn_timeout: timeout,
+			conn_tries: 1,
+			conn_wait: timeout,
+		})?;
+		Ok(())
 	}
 }
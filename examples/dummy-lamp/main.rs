@@ -1,10 +1,25 @@
 pub mod dummy;
 
 use crate::dummy::DummyLamp;
+use yeerugina::lamp::Lamp;
+use yeerugina::structs::Command;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-	let mut my_lamp = DummyLamp::new();
-	println!("{:?}", &my_lamp);
+	env_logger::init();
+	let mut my_lamp = DummyLamp::new()?;
+	my_lamp.connect()?;
+	let mut reader = my_lamp.lamp.reader()?;
+
+	// Send a few commands; the last one is rejected by the mock
+	my_lamp.lamp.send_cmd(Command::Toggle)?;
+	my_lamp.lamp.send_cmd(Command::SetRgb(0xff8800))?;
+	my_lamp.lamp.send_cmd(Command::SetBright(0))?;
+
+	// Print the replies and notifications until the mock goes quiet
+	while let Some(line) = reader.read_line()? {
+		println!("{:?}", Lamp::parse_response(&line)?);
+	}
+	println!("Final state: {:?}", my_lamp.mock.state());
 
 	Ok(())
 }
@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+
+/// Usage text printed by --help.
+pub const USAGE: &str = "\
+Usage: mock-lamps [OPTIONS]
+
+Runs simulated Yeelight lamps and a discovery responder that announces them.
+
+Options:
+  --lamps <n>       Number of lamps to simulate (default: 1)
+  --bind <ip>       Address the lamps and the responder listen on (default: 127.0.0.1)
+  --port <port>     Port of the first lamp; the others use the following ports
+                    (default: 55443, 0 picks free ports)
+  --ssdp-port <port>
+                    Port of the discovery responder (default: 1982)
+  --multicast       Also answer requests sent to the multicast group of real lamps
+  --help            Print this text and exit";
+
+/// Command line arguments of the program.
+#[derive(Debug)]
+pub struct Args {
+	/// Number of lamps to simulate.
+	pub lamps: u16,
+	/// Address to listen on.
+	pub bind: IpAddr,
+	/// Port of the first lamp.
+	pub port: u16,
+	/// Port of the discovery responder.
+	pub ssdp_port: u16,
+	/// Join the multicast group.
+	pub multicast: bool,
+	/// Only print the usage.
+	pub help: bool,
+}
+
+impl Default for Args {
+	fn default() -> Self {
+		Self {
+			lamps: 1,
+			bind: IpAddr::from([127, 0, 0, 1]),
+			port: 55443,
+			ssdp_port: 1982,
+			multicast: false,
+			help: false,
+		}
+	}
+}
+
+impl Args {
+	/// Parse the arguments given to the program (without the program name).
+	pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+		let mut parsed = Self::default();
+		while let Some(arg) = args.next() {
+			let mut value = |name: &str| {
+				let val = args.next().ok_or(format!("{name} requires a value"))?;
+				Ok::<String, String>(val)
+			};
+			match arg.as_str() {
+				"--lamps" => parsed.lamps = parse_value("--lamps", value("--lamps")?)?,
+				"--bind" => parsed.bind = parse_value("--bind", value("--bind")?)?,
+				"--port" => parsed.port = parse_value("--port", value("--port")?)?,
+				"--ssdp-port" => {
+					parsed.ssdp_port = parse_value("--ssdp-port", value("--ssdp-port")?)?
+				},
+				"--multicast" => parsed.multicast = true,
+				"--help" | "-h" => parsed.help = true,
+				other => return Err(format!("Unknown argument \"{other}\"")),
+			}
+		}
+		if parsed.port != 0
+			&& parsed
+				.port
+				.checked_add(parsed.lamps.saturating_sub(1))
+				.is_none()
+		{
+			return Err(String::from("Not enough ports after --port for all lamps"));
+		}
+		Ok(parsed)
+	}
+}
+
+/// Parse the value of an option.
+fn parse_value<T: std::str::FromStr>(name: &str, val: String) -> Result<T, String>
+where
+	T::Err: std::fmt::Display,
+{
+	val.parse()
+		.map_err(|e| format!("Invalid value \"{val}\" for {name}: {e}"))
+}
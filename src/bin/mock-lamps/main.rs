@@ -0,0 +1,64 @@
+mod args;
+
+use args::{Args, USAGE};
+use log::{error, info};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc;
+use yeerugina::mock::ssdp::{Advert, MockSsdp};
+use yeerugina::mock::{MockLamp, MockState};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+	env_logger::init();
+
+	let args = match Args::parse(std::env::args().skip(1)) {
+		Ok(args) => args,
+		Err(e) => {
+			eprintln!("{e}\n\n{USAGE}");
+			std::process::exit(2);
+		},
+	};
+	if args.help {
+		println!("{USAGE}");
+		return Ok(());
+	}
+
+	// Start the lamps on consecutive ports
+	let mut lamps = Vec::new();
+	for idx in 0..args.lamps {
+		let port = if args.port == 0 { 0 } else { args.port + idx };
+		let state = MockState {
+			name: format!("mock{idx}"),
+			..MockState::default()
+		};
+		lamps.push(MockLamp::bind(SocketAddr::new(args.bind, port), state)?);
+	}
+	let adverts = lamps
+		.iter()
+		.zip(1u64..)
+		.map(|(lamp, id)| Advert::from_mock(id, lamp))
+		.collect();
+	let ssdp = MockSsdp::bind(SocketAddr::new(args.bind, args.ssdp_port), adverts)?;
+	if args.multicast {
+		let interface = match args.bind {
+			IpAddr::V4(ip) => ip,
+			IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+		};
+		ssdp.join_multicast(interface)?;
+	}
+
+	for lamp in lamps.iter() {
+		println!("Lamp listening on {}", lamp.addr());
+	}
+	println!("Discovery responder listening on {}", ssdp.addr());
+
+	// Run until Ctrl+C
+	let (tx, rx) = mpsc::channel();
+	if let Err(e) = ctrlc::set_handler(move || {
+		let _ = tx.send(());
+	}) {
+		error!("Could not add Ctrl+C handling: {e}");
+	}
+	let _ = rx.recv();
+	info!("Received Ctrl+C signal; stopping");
+	Ok(())
+}
@@ -0,0 +1,71 @@
+use yeerugina::config::{ENV_CONFIG_PATH, Override};
+
+/// Usage text printed by --help.
+pub const USAGE: &str = "\
+Usage: mqtt-executable [OPTIONS]
+
+Options:
+  --config <path>        Read the config from <path>
+                         (default: $YEERUGINA_CONFIG, then ./config.toml)
+  --set <setting=value>  Override a setting, e.g. --set mqtt.ip=10.0.0.2:1883
+                         or --set lamp.kitchen.effect=sudden (may be repeated)
+  --check-config         Validate the config, print any problems and exit
+  --dump-config          Print the effective config and exit
+  --help                 Print this text and exit
+
+Settings are taken from the config file, then from YEERUGINA_* environment variables
+(e.g. YEERUGINA_MQTT__IP), then from --set arguments; later sources take precedence.";
+
+/// Command line arguments of the program.
+#[derive(Debug, Default)]
+pub struct Args {
+	/// Path of the config file.
+	pub config_path: Option<String>,
+	/// Overrides given with --set.
+	pub overrides: Vec<Override>,
+	/// Only validate the config.
+	pub check_config: bool,
+	/// Only print the effective config.
+	pub dump_config: bool,
+	/// Only print the usage.
+	pub help: bool,
+}
+
+impl Args {
+	/// Parse the arguments given to the program (without the program name).
+	pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+		let mut parsed = Self::default();
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--config" => {
+					let path = args.next().ok_or("--config requires a path")?;
+					parsed.config_path = Some(path);
+				},
+				"--set" => {
+					let setting = args.next().ok_or("--set requires <setting>=<value>")?;
+					parsed.overrides.push(Override::from_arg(&setting)?);
+				},
+				"--check-config" => parsed.check_config = true,
+				"--dump-config" => parsed.dump_config = true,
+				"--help" | "-h" => parsed.help = true,
+				other => return Err(format!("Unknown argument \"{other}\"")),
+			}
+		}
+		Ok(parsed)
+	}
+
+	/// Get the path of the config file: --config, then $YEERUGINA_CONFIG, then config.toml.
+	pub fn config_path(&self) -> String {
+		self.config_path
+			.clone()
+			.or_else(|| std::env::var(ENV_CONFIG_PATH).ok())
+			.unwrap_or_else(|| String::from("config.toml"))
+	}
+
+	/// Get all overrides in order of precedence: environment variables, then --set.
+	pub fn all_overrides(&self) -> Vec<Override> {
+		let mut overrides = Override::from_env();
+		overrides.extend(self.overrides.iter().cloned());
+		overrides
+	}
+}
@@ -0,0 +1,151 @@
+use crate::reply::{Replies, ReplyTo};
+use log::{debug, error, info, warn};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use yeerugina::config::{LampConfig, OfflinePolicy};
+use yeerugina::lamp::Lamp;
+use yeerugina::structs::{Command, Transition};
+
+/// A command waiting for its lamp to be reconnected.
+#[derive(Debug)]
+pub struct Buffered {
+	cmd: Command,
+	transition: Transition,
+	reply_to: Option<ReplyTo>,
+	received: Instant,
+}
+
+impl Buffered {
+	/// Wrap a command received just now.
+	pub fn new(cmd: Command, transition: Transition, reply_to: Option<ReplyTo>) -> Self {
+		Self {
+			cmd,
+			transition,
+			reply_to,
+			received: Instant::now(),
+		}
+	}
+}
+
+/// Commands received while a lamp is reconnecting.
+///
+/// The buffer is shared between the main thread, which adds the commands that could not
+/// be sent, and the monitor of the lamp, which sends them once the lamp is connected again.
+/// With OfflinePolicy::Reject, nothing is ever buffered.
+#[derive(Clone, Debug)]
+pub struct CommandBuffer {
+	policy: OfflinePolicy,
+	size: usize,
+	timeout: Duration,
+	queue: Arc<Mutex<VecDeque<Buffered>>>,
+}
+
+impl CommandBuffer {
+	/// Create an empty buffer with the settings of a lamp.
+	pub fn from_config(conf: &LampConfig) -> Self {
+		Self {
+			policy: conf.offline_policy,
+			size: conf.buffer_size,
+			timeout: conf.buffer_timeout,
+			queue: Arc::new(Mutex::new(VecDeque::new())),
+		}
+	}
+
+	/// Keep a command until the lamp is reconnected.
+	///
+	/// Returns false if the policy of the lamp is to reject the command.
+	/// If the buffer is full, the oldest command is dropped and its sender gets an error reply.
+	pub fn push(&self, lamp: &str, item: Buffered, replies: &Replies) -> bool {
+		if self.policy == OfflinePolicy::Reject {
+			return false;
+		}
+		let Ok(mut queue) = self.queue.lock() else {
+			error!("{lamp} | Command buffer mutex poisoned");
+			return false;
+		};
+		if queue.len() >= self.size
+			&& let Some(dropped) = queue.pop_front()
+		{
+			warn!("{lamp} | Command buffer full; dropping {:?}", dropped.cmd);
+			reply_dropped(lamp, &dropped, "Command buffer full", replies);
+		}
+		info!("{lamp} | Lamp is reconnecting; buffering {:?}", item.cmd);
+		queue.push_back(item);
+		true
+	}
+
+	/// Take the oldest command out of the buffer.
+	fn pop_front(&self) -> Option<Buffered> {
+		self.queue.lock().ok()?.pop_front()
+	}
+
+	/// Drop the commands older than the buffer timeout, replying with an error to their senders.
+	///
+	/// Called periodically, so that the senders learn about the failure
+	/// even if the lamp stays offline.
+	pub fn expire(&self, lamp_id: &str, replies: &Replies) {
+		let expired: Vec<Buffered> = match self.queue.lock() {
+			Ok(mut queue) => {
+				// The oldest commands are at the front
+				let count = queue
+					.iter()
+					.take_while(|item| item.received.elapsed() > self.timeout)
+					.count();
+				queue.drain(..count).collect()
+			},
+			Err(_) => return,
+		};
+		for item in expired {
+			warn!("{lamp_id} | Dropping expired command {:?}", item.cmd);
+			reply_dropped(
+				lamp_id,
+				&item,
+				"Command expired while the lamp was offline",
+				replies,
+			);
+		}
+	}
+
+	/// Send the buffered commands in the order they were received.
+	///
+	/// The lamp stays locked until the buffer is empty, so that no new command can overtake
+	/// the buffered ones; pass the lock taken when the lamp was connected.
+	/// Commands older than the buffer timeout are dropped. If the lamp disconnects again,
+	/// the remaining commands stay in the buffer.
+	/// The replies are tracked with Replies::record(), so this must be called by the thread
+	/// that reads the replies of the lamp.
+	pub fn flush(&self, lamp_id: &str, mut lamp: MutexGuard<'_, Lamp>, replies: &Replies) {
+		self.expire(lamp_id, replies);
+		let mut sent: Vec<(Buffered, io::Result<u8>)> = Vec::new();
+		while let Some(item) = self.pop_front() {
+			if !lamp.is_connected() {
+				if let Ok(mut queue) = self.queue.lock() {
+					queue.push_front(item);
+				}
+				break;
+			}
+			debug!("{lamp_id} | Sending buffered command {:?}", item.cmd);
+			let res = lamp.send_cmd_with(item.cmd.clone(), &item.transition);
+			if let Err(ref e) = res {
+				error!("{lamp_id} | Could not send buffered command: {e}");
+			}
+			sent.push((item, res));
+		}
+		// Do not keep the lamp locked while the replies are recorded and published
+		drop(lamp);
+		for (item, res) in sent {
+			if let Some(ref reply_to) = item.reply_to {
+				replies.record(reply_to, &item.cmd, vec![(lamp_id.to_string(), res)]);
+			}
+		}
+	}
+}
+
+/// Tell the sender of a buffered command that it was not sent.
+fn reply_dropped(lamp: &str, item: &Buffered, message: &str, replies: &Replies) {
+	if let Some(ref reply_to) = item.reply_to {
+		replies.send_lamp_error(reply_to, lamp, &item.cmd, message);
+	}
+}
@@ -0,0 +1,144 @@
+use log::{debug, error, info};
+use paho_mqtt as mqtt;
+use serde_json::{Value, json};
+use yeerugina::config::{Config, ConfigDiff, LampConfig, OFFLINE, ONLINE, TopicMode};
+
+/// Mireds of the warmest and coldest color temperatures the lamps accept (1700 K and 6500 K).
+const MAX_MIREDS: u32 = 588;
+const MIN_MIREDS: u32 = 154;
+
+/// Create the Home Assistant discovery message of a lamp.
+///
+/// The lamp is announced as a `light` using the JSON schema. Its command topic is the
+/// single command topic of the lamp and its state is read from the state topic.
+/// The lamp is available while both the program and the lamp itself are.
+pub fn discovery_config(conf: &Config, lamp: &LampConfig) -> Value {
+	let unique_id = format!("{}_{}", conf.mqtt.client_id, lamp.id);
+	let supports = |method: &str| lamp.capabilities.iter().any(|m| m == method);
+	let mut color_modes: Vec<&str> = [
+		("set_rgb", "rgb"),
+		("set_hsv", "hs"),
+		("set_ct_abx", "color_temp"),
+	]
+	.into_iter()
+	.filter(|(method, _)| supports(method))
+	.map(|(_, mode)| mode)
+	.collect();
+	if color_modes.is_empty() {
+		color_modes.push(if supports("set_bright") {
+			"brightness"
+		} else {
+			"onoff"
+		});
+	}
+	let mut doc = json!({
+		// null: the entity takes the name of the device
+		"name": null,
+		"unique_id": unique_id,
+		"schema": "json",
+		"command_topic": conf.mqtt.command_topic(&lamp.id),
+		"state_topic": conf.mqtt.state_topic(&lamp.id),
+		"availability": [
+			{
+				"topic": conf.mqtt.bridge_availability_topic(),
+				"payload_available": ONLINE,
+				"payload_not_available": conf.mqtt.lwt_payload,
+			},
+			{
+				"topic": conf.mqtt.availability_topic(&lamp.id),
+				"payload_available": ONLINE,
+				"payload_not_available": OFFLINE,
+			},
+		],
+		"availability_mode": "all",
+		"brightness": supports("set_bright"),
+		"brightness_scale": 100,
+		"supported_color_modes": color_modes,
+		"effect": true,
+		"effect_list": ["sudden", "smooth"],
+		"qos": conf.mqtt.qos,
+		"device": {
+			"identifiers": [unique_id],
+			"name": lamp.name,
+			"manufacturer": "Yeelight",
+		},
+	});
+	if color_modes.contains(&"color_temp") {
+		doc["min_mireds"] = json!(MIN_MIREDS);
+		doc["max_mireds"] = json!(MAX_MIREDS);
+	}
+	doc
+}
+
+/// Publish the discovery messages of every lamp.
+///
+/// Lamps using TopicMode::PerProperty are skipped, since Home Assistant's JSON schema
+/// needs a single command topic.
+pub fn publish_all(cli: &mqtt::Client, conf: &Config) {
+	info!("Publishing Home Assistant discovery messages");
+	for lamp in conf.lamps.iter() {
+		publish_lamp(cli, conf, lamp);
+	}
+}
+
+/// Publish the discovery messages for the changes between two configs.
+///
+/// Removed lamps are deleted from Home Assistant; added and changed lamps are announced again.
+/// If the MQTT settings changed, everything is deleted and announced again.
+pub fn update(cli: &mqtt::Client, old: &Config, new: &Config, diff: &ConfigDiff) {
+	if old.mqtt.ha_discovery && (diff.mqtt_changed || !new.mqtt.ha_discovery) {
+		for lamp in old.lamps.iter() {
+			clear_lamp(cli, old, &lamp.id);
+		}
+	} else if old.mqtt.ha_discovery {
+		for id in diff.removed_lamps.iter() {
+			clear_lamp(cli, old, id);
+		}
+	}
+	if !new.mqtt.ha_discovery {
+		return;
+	}
+	if diff.mqtt_changed || !old.mqtt.ha_discovery {
+		publish_all(cli, new);
+		return;
+	}
+	for id in diff.added_lamps.iter().chain(diff.changed_lamps.iter()) {
+		if let Some(lamp) = new.lamp(id) {
+			publish_lamp(cli, new, lamp);
+		}
+	}
+}
+
+/// Publish the discovery message of one lamp, or delete it if the lamp cannot be announced.
+fn publish_lamp(cli: &mqtt::Client, conf: &Config, lamp: &LampConfig) {
+	if lamp.topic_mode != TopicMode::Single {
+		info!(
+			"{} | Not announced to Home Assistant: it needs topic-mode = \"single\"",
+			lamp.id
+		);
+		clear_lamp(cli, conf, &lamp.id);
+		return;
+	}
+	let payload = discovery_config(conf, lamp).to_string();
+	debug!("{} | Discovery message: {payload}", lamp.id);
+	let msg = mqtt::Message::new_retained(
+		conf.mqtt.discovery_topic(&lamp.id),
+		payload,
+		conf.mqtt.qos as i32,
+	);
+	if let Err(e) = cli.publish(msg) {
+		error!("{} | Could not publish discovery message: {e}", lamp.id);
+	}
+}
+
+/// Delete the discovery message of a lamp with an empty retained message.
+fn clear_lamp(cli: &mqtt::Client, conf: &Config, id: &str) {
+	let msg = mqtt::Message::new_retained(
+		conf.mqtt.discovery_topic(id),
+		Vec::new(),
+		conf.mqtt.qos as i32,
+	);
+	if let Err(e) = cli.publish(msg) {
+		error!("{id} | Could not delete discovery message: {e}");
+	}
+}
@@ -0,0 +1,275 @@
+use crate::buffer::{Buffered, CommandBuffer};
+use crate::registry::Registry;
+use crate::reply::{Replies, ReplyTo};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use yeerugina::config::{Config, MqttConfig, TopicMode};
+use yeerugina::group::{LampGroup, SharedLamp, lock_lamp};
+use yeerugina::lamp::PreparedCmd;
+use yeerugina::mqtt::{MqttCommand, parse_mqtt_command, parse_property_command};
+use yeerugina::structs::{Command, Transition};
+
+/// A command message for a lamp or a group.
+#[derive(Debug)]
+pub struct Job {
+	/// Topic the message arrived on; tells how to parse the payload.
+	pub topic: String,
+	/// Payload of the message.
+	pub payload: String,
+	/// Where to send the replies, if the sender asked for them.
+	pub reply_to: Option<ReplyTo>,
+}
+
+/// Passes command messages to a worker thread per lamp and group.
+///
+/// Each worker parses the messages of its target and sends the commands in the order they
+/// arrived, so a slow lamp or a synchronized group does not hold up the others.
+/// The workers are replaced when the config is reloaded; the old workers finish
+/// the messages they already received and then stop.
+pub struct Dispatcher {
+	workers: HashMap<String, Sender<Job>>,
+	replies: Arc<Replies>,
+}
+
+impl Dispatcher {
+	/// Start a worker for every lamp and group of the registry.
+	pub fn start(conf: &Config, reg: &Registry, replies: Arc<Replies>) -> Self {
+		let mut dispatcher = Self {
+			workers: HashMap::new(),
+			replies,
+		};
+		dispatcher.restart(conf, reg);
+		dispatcher
+	}
+
+	/// Replace the workers after the lamps or groups changed.
+	pub fn restart(&mut self, conf: &Config, reg: &Registry) {
+		let replies = &self.replies;
+		let buffers: HashMap<String, (SharedLamp, CommandBuffer)> = reg
+			.lamps
+			.iter()
+			.filter_map(|(id, lamp)| {
+				let buffer = reg.buffer(id)?.clone();
+				Some((id.clone(), (Arc::clone(lamp), buffer)))
+			})
+			.collect();
+		let mut workers = HashMap::new();
+		for (id, lamp) in reg.lamps.iter() {
+			let mode = conf.lamp(id).map(|l| l.topic_mode).unwrap_or_default();
+			let sink = Sink::Lamp(Arc::clone(lamp), mode);
+			let tx = Worker::spawn(id, sink, conf, &buffers, replies);
+			workers.insert(id.clone(), tx);
+		}
+		for (name, group) in reg.groups.iter() {
+			let synchronized = conf.groups.get(name).is_some_and(|g| g.is_synchronized());
+			let sink = Sink::Group(group.clone(), synchronized);
+			let tx = Worker::spawn(name, sink, conf, &buffers, replies);
+			workers.insert(name.clone(), tx);
+		}
+		self.workers = workers;
+	}
+
+	/// Pass a message to the worker of a lamp or group.
+	/// Returns false if there is no such lamp or group.
+	pub fn dispatch(&self, target: &str, job: Job) -> bool {
+		match self.workers.get(target) {
+			Some(tx) => tx.send(job).is_ok(),
+			None => false,
+		}
+	}
+}
+
+/// Where a worker sends its commands.
+enum Sink {
+	/// A lamp, listening on the topics of the given TopicMode.
+	Lamp(SharedLamp, TopicMode),
+	/// A group; the flag tells whether it is synchronized.
+	Group(LampGroup, bool),
+}
+
+/// State of a worker thread.
+struct Worker {
+	target: String,
+	sink: Sink,
+	mqtt: MqttConfig,
+	/// The lamps the commands may reach, with their command buffers.
+	buffers: HashMap<String, (SharedLamp, CommandBuffer)>,
+	replies: Arc<Replies>,
+}
+
+impl Worker {
+	/// Start a worker thread and return the sender for its messages.
+	fn spawn(
+		target: &str, sink: Sink, conf: &Config,
+		buffers: &HashMap<String, (SharedLamp, CommandBuffer)>, replies: &Arc<Replies>,
+	) -> Sender<Job> {
+		let reached: Vec<String> = match sink {
+			Sink::Lamp(..) => vec![target.to_string()],
+			Sink::Group(ref group, _) => group.member_ids().map(String::from).collect(),
+		};
+		let worker = Self {
+			target: target.to_string(),
+			sink,
+			mqtt: conf.mqtt.clone(),
+			buffers: reached
+				.into_iter()
+				.filter_map(|id| {
+					let entry = buffers.get(&id)?;
+					Some((id, (Arc::clone(&entry.0), entry.1.clone())))
+				})
+				.collect(),
+			replies: Arc::clone(replies),
+		};
+		let (tx, rx) = mpsc::channel();
+		thread::spawn(move || worker.run(rx));
+		tx
+	}
+
+	fn run(self, rx: Receiver<Job>) {
+		debug!("{} | Worker started", self.target);
+		for job in rx {
+			self.handle(job);
+		}
+		debug!("{} | Worker stopped", self.target);
+	}
+
+	/// Parse a message and send its commands.
+	fn handle(&self, job: Job) {
+		let reply_error = |message: String| {
+			if let Some(ref reply_to) = job.reply_to {
+				self.replies.send_error(reply_to, &message);
+			}
+		};
+		let (mode, parsed) = if job.topic == self.mqtt.command_topic(&self.target) {
+			(TopicMode::Single, parse_mqtt_command(job.payload.clone()))
+		} else if let Some((_, prop)) = self.mqtt.property_from_topic(&job.topic) {
+			(
+				TopicMode::PerProperty,
+				parse_property_command(prop, &job.payload),
+			)
+		} else {
+			warn!(
+				"{} | {} is not a command topic; skipping",
+				self.target, job.topic
+			);
+			return;
+		};
+		// Lamps only listen to the topics of their own mode; groups listen to both
+		if let Sink::Lamp(_, lamp_mode) = self.sink
+			&& lamp_mode != mode
+		{
+			warn!(
+				"{} | Lamp uses topic mode {lamp_mode:?}, ignoring {}",
+				self.target, job.topic
+			);
+			reply_error(format!(
+				"Lamp {} does not accept commands on {}",
+				self.target, job.topic
+			));
+			return;
+		}
+		let MqttCommand { cmds, transition } = match parsed {
+			Ok(parsed) => parsed,
+			Err(e) => {
+				error!("{} | Could not parse MQTT command: {e}", self.target);
+				reply_error(format!("Could not parse command: {e}"));
+				return;
+			},
+		};
+		for cmd in cmds {
+			let prepared = self.prepare(&cmd, &transition);
+			let ids: Vec<(String, u8)> = prepared
+				.iter()
+				.filter_map(|(id, prep)| Some((id.clone(), prep.as_ref().ok()?.id)))
+				.collect();
+			let write = || {
+				let results = self.write(prepared);
+				// Lamps that are reconnecting may keep the command for later
+				results
+					.into_iter()
+					.filter(|(id, res)| {
+						res.is_ok() || !self.buffer(id, &cmd, &transition, job.reply_to.as_ref())
+					})
+					.collect()
+			};
+			match job.reply_to {
+				Some(ref reply_to) => self.replies.track(reply_to, &cmd, &ids, write),
+				None => {
+					write();
+				},
+			}
+		}
+	}
+
+	/// Reserve a command ID on the lamp or on every member of the group.
+	/// Returns the prepared request (or the error) for every lamp; nothing is sent yet.
+	fn prepare(
+		&self, cmd: &Command, transition: &Transition,
+	) -> Vec<(String, io::Result<PreparedCmd>)> {
+		match self.sink {
+			Sink::Lamp(ref lamp, _) => {
+				let prep =
+					lock_lamp(lamp).map(|mut lamp| lamp.prepare_cmd(cmd.clone(), transition));
+				vec![(self.target.clone(), prep)]
+			},
+			Sink::Group(ref group, _) => group.prepare_cmd(cmd, transition),
+		}
+	}
+
+	/// Write the requests returned by Worker::prepare() to the lamp or group.
+	/// Returns the command ID (or the error) for every lamp the command was sent to.
+	fn write(
+		&self, prepared: Vec<(String, io::Result<PreparedCmd>)>,
+	) -> Vec<(String, io::Result<u8>)> {
+		let target = &self.target;
+		match self.sink {
+			Sink::Lamp(ref lamp, _) => prepared
+				.into_iter()
+				.map(|(id, prep)| {
+					let res = prep.and_then(|prep| {
+						lock_lamp(lamp)?.write_prepared(&prep)?;
+						Ok(prep.id)
+					});
+					if let Err(ref e) = res {
+						error!("{target} | Could not send command to lamp: {e}");
+					}
+					(id, res)
+				})
+				.collect(),
+			Sink::Group(ref group, synchronized) => {
+				let res = if synchronized {
+					let sync_res = group.write_prepared_synced(prepared);
+					info!("{target} | Synchronized write: {sync_res}");
+					sync_res.result
+				} else {
+					group.write_prepared(prepared)
+				};
+				if !res.is_ok() {
+					error!("{target} | Command failed on some lamps: {res}");
+				}
+				res.results
+			},
+		}
+	}
+
+	/// Keep a command that could not be sent to a lamp until the lamp is reconnected.
+	///
+	/// The command is only kept if the lamp is disconnected and its offline policy allows it.
+	/// Returns true if the command was kept.
+	fn buffer(
+		&self, id: &str, cmd: &Command, transition: &Transition, reply_to: Option<&ReplyTo>,
+	) -> bool {
+		let Some((lamp, buffer)) = self.buffers.get(id) else {
+			return false;
+		};
+		if lock_lamp(lamp).is_ok_and(|lamp| lamp.is_connected()) {
+			return false;
+		}
+		let item = Buffered::new(cmd.clone(), *transition, reply_to.cloned());
+		buffer.push(id, item, &self.replies)
+	}
+}
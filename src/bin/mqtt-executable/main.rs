@@ -0,0 +1,535 @@
+//use ctrlc;
+#[cfg(feature = "mqtt")]
+mod args;
+#[cfg(feature = "mqtt")]
+mod buffer;
+#[cfg(feature = "mqtt")]
+mod discovery;
+#[cfg(feature = "mqtt")]
+mod dispatch;
+#[cfg(feature = "mqtt")]
+mod monitor;
+#[cfg(feature = "mqtt")]
+mod registry;
+#[cfg(feature = "mqtt")]
+mod reply;
+#[cfg(feature = "mqtt")]
+mod router;
+#[cfg(feature = "mqtt")]
+mod state;
+#[cfg(feature = "mqtt")]
+mod watch;
+
+#[cfg(feature = "mqtt")]
+use args::{Args, USAGE};
+#[cfg(feature = "mqtt")]
+use dispatch::{Dispatcher, Job};
+#[cfg(feature = "mqtt")]
+use log::{debug, error, info, warn};
+#[cfg(feature = "mqtt")]
+use paho_mqtt as mqtt;
+#[cfg(feature = "mqtt")]
+use registry::Registry;
+#[cfg(feature = "mqtt")]
+use reply::{Replies, ReplyTo};
+#[cfg(feature = "mqtt")]
+use router::RoutingTable;
+#[cfg(feature = "mqtt")]
+use state::{StateMsg, StatePublisher, StateSettings};
+#[cfg(feature = "mqtt")]
+use std::collections::VecDeque;
+#[cfg(feature = "mqtt")]
+use std::sync::Arc;
+#[cfg(feature = "mqtt")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "mqtt")]
+use watch::ConfigWatcher;
+#[cfg(feature = "mqtt")]
+use yeerugina::config::{Config, MqttConfig, MqttVersion, ONLINE};
+#[cfg(feature = "mqtt")]
+use yeerugina::mqtt::connect_props;
+
+#[cfg(feature = "mqtt")]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+	env_logger::init();
+
+	let args = match Args::parse(std::env::args().skip(1)) {
+		Ok(args) => args,
+		Err(e) => {
+			eprintln!("{e}\n\n{USAGE}");
+			std::process::exit(2);
+		},
+	};
+	if args.help {
+		println!("{USAGE}");
+		return Ok(());
+	}
+
+	// Settings: file < environment < command line
+	let mut conf = match Config::load(args.config_path(), &args.all_overrides()) {
+		Ok(conf) => conf,
+		Err(e) => {
+			eprintln!("{e}");
+			std::process::exit(1);
+		},
+	};
+	info!("Config loaded");
+	if args.dump_config {
+		print!("{}", conf.dump()?);
+		return Ok(());
+	}
+	// --check-config only loads and validates the config
+	if args.check_config {
+		println!(
+			"Config OK: {} lamps, {} groups",
+			conf.lamps.len(),
+			conf.groups.len()
+		);
+		return Ok(());
+	}
+	println!("Hello, world!");
+
+	// Creating options here
+	let mqtt_version = match conf.mqtt.protocol {
+		MqttVersion::V311 => mqtt::MQTT_VERSION_3_1_1,
+		MqttVersion::V5 => mqtt::MQTT_VERSION_5,
+	};
+	let create_opts = mqtt::CreateOptionsBuilder::new()
+		.server_uri(conf.mqtt.server_uri())
+		.client_id(conf.mqtt.client_id.clone())
+		.mqtt_version(mqtt_version)
+		.finalize();
+	debug!("MQTT settings created");
+
+	// Create the client and other stuff
+	let cli = mqtt::Client::new(create_opts)?;
+	info!("MQTT client created");
+	let rx: mqtt::Receiver<_> = cli.start_consuming();
+	debug!("MQTT receiver initialized");
+
+	// last will and testament
+	let lwt = mqtt::Message::new_retained(
+		conf.mqtt.bridge_availability_topic(),
+		conf.mqtt.lwt_payload.clone(),
+		conf.mqtt.qos as i32,
+	);
+	debug!("LWT message created");
+
+	// connection settings
+	let mut conn_builder = match conf.mqtt.protocol {
+		MqttVersion::V311 => {
+			let mut builder = mqtt::ConnectOptionsBuilder::new_v3();
+			builder.clean_session(false);
+			builder
+		},
+		MqttVersion::V5 => {
+			let mut builder = mqtt::ConnectOptionsBuilder::new_v5();
+			builder
+				.clean_start(false)
+				.properties(connect_props(conf.mqtt.session_expiry)?);
+			builder
+		},
+	};
+	conn_builder
+		.keep_alive_interval(conf.mqtt.keep_alive)
+		.will_message(lwt);
+	if let Some(ref username) = conf.mqtt.username {
+		conn_builder.user_name(username.clone());
+	}
+	if let Some(password) = conf.mqtt.password()? {
+		conn_builder.password(password);
+	}
+	if conf.mqtt.transport.is_tls() {
+		conn_builder.ssl_options(ssl_options(&conf.mqtt)?);
+	}
+	let conn_opts = conn_builder.finalize();
+	debug!("Connection options created");
+
+	// Connect to the broker
+	debug!("Connecting to the broker");
+	let rsp: mqtt::ServerResponse = cli.connect(conn_opts)?;
+	let mut table = RoutingTable::new(&conf, uses_sub_ids(&conf, &rsp));
+	on_connect(&cli, &conf, &rsp, &table, None)?;
+
+	// Create and connect to the lamps, then create the groups.
+	// Their states are published from now on.
+	let publisher = StatePublisher::start(cli.clone(), StateSettings::from_config(&conf.mqtt));
+	let replies = Arc::new(Replies::new(cli.clone(), conf.mqtt.message_expiry));
+	let mut reg = Registry::from_config(&conf, publisher.sender(), Arc::clone(&replies))?;
+	let mut dispatcher = Dispatcher::start(&conf, &reg, Arc::clone(&replies));
+	if conf.mqtt.ha_discovery {
+		discovery::publish_all(&cli, &conf);
+	}
+
+	// Implement Ctrl-C
+	let ctrlc_cli = cli.clone();
+	let ctrlc_res = ctrlc::set_handler(move || {
+		info!("Received Ctrl+C signal");
+		ctrlc_cli.stop_consuming();
+	});
+	// Failure to add Ctrl+C is not fatal, but an error.
+	if let Err(e) = ctrlc_res {
+		error!("Could not add Ctrl+C handling: {e}");
+	};
+	//println!("{ctrlc_res:?}");
+
+	// Reload the config whenever the file changes or on SIGHUP
+	let mut watcher = ConfigWatcher::new(args.config_path());
+	info!("Watching {} for changes", watcher.path());
+
+	info!("Message reception loop ON");
+	// Messages received while reconnecting, handled before any new ones
+	let mut queued: VecDeque<mqtt::Message> = VecDeque::new();
+	loop {
+		let received = match queued.pop_front() {
+			Some(msg) => Ok(Some(msg)),
+			None => rx.recv_timeout(WATCH_INTERVAL),
+		};
+		match received {
+			// Home Assistant forgets non-retained state when it restarts
+			Ok(Some(msg))
+				if conf.mqtt.ha_discovery && msg.topic() == conf.mqtt.ha_status_topic() =>
+			{
+				if msg.payload_str() == "online" {
+					discovery::publish_all(&cli, &conf);
+				}
+			},
+			Ok(Some(msg)) => handle_message(&msg, &table, &dispatcher, &replies),
+			Ok(None) if !cli.is_connected() => {
+				error!("Connection to MQTT broker lost");
+				// The lamps stay connected; their states are published again afterwards
+				match reconnect(&cli, &conf, &rx, &table, &mut queued) {
+					Some(new_table) => table = new_table,
+					None => break,
+				}
+				publisher.send(StateMsg::Resync);
+			},
+			Ok(None) => error!("Received None message"),
+			Err(e) if e.is_timeout() => {},
+			// The sender is gone after stop_consuming()
+			Err(_) => break,
+		}
+		replies.expire();
+		reg.expire_buffers();
+		if watcher.changed() {
+			reload(
+				&args,
+				&mut conf,
+				&mut reg,
+				&cli,
+				&publisher,
+				&mut table,
+				&mut dispatcher,
+			);
+		}
+	}
+
+	// A clean disconnect does not trigger the LWT
+	set_bridge_availability(&cli, &conf, &conf.mqtt.lwt_payload);
+	if let Err(e) = cli.disconnect(None) {
+		error!("Could not disconnect from the broker: {e}");
+	}
+	Ok(())
+}
+
+/// Create the TLS options of the broker connection from the `[mqtt.tls]` table.
+#[cfg(feature = "mqtt")]
+fn ssl_options(conf: &MqttConfig) -> mqtt::Result<mqtt::SslOptions> {
+	let tls = &conf.tls;
+	let mut builder = mqtt::SslOptionsBuilder::new();
+	if let Some(ref ca_file) = tls.ca_file {
+		builder.trust_store(ca_file)?;
+	}
+	if let Some(ref cert_file) = tls.cert_file {
+		builder.key_store(cert_file)?;
+	}
+	if let Some(ref key_file) = tls.key_file {
+		builder.private_key(key_file)?;
+	}
+	if !tls.verify_hostname {
+		warn!("Host name verification of the broker certificate is disabled");
+	}
+	builder.verify(tls.verify_hostname);
+	Ok(builder.finalize())
+}
+
+/// Reconnect to the broker, waiting longer after every failed attempt.
+///
+/// The wait starts at reconnect_min_wait and doubles up to reconnect_max_wait.
+/// Messages that arrive while waiting are added to `queued`, so that they can be handled
+/// once the connection is back. `table` is the routing table of the lost connection.
+/// Returns the routing table for the new connection,
+/// or None if the program was stopped while waiting.
+#[cfg(feature = "mqtt")]
+fn reconnect(
+	cli: &mqtt::Client, conf: &Config, rx: &mqtt::Receiver<Option<mqtt::Message>>,
+	table: &RoutingTable, queued: &mut VecDeque<mqtt::Message>,
+) -> Option<RoutingTable> {
+	let mut wait = conf.mqtt.reconnect_min_wait;
+	loop {
+		info!("Reconnecting to the broker in {wait:?}");
+		if !wait_queued(rx, wait, queued) {
+			return None;
+		}
+		let rsp = match cli.reconnect() {
+			Ok(rsp) => rsp,
+			Err(e) => {
+				warn!("Could not reconnect to the broker: {e}");
+				wait = (wait * 2).min(conf.mqtt.reconnect_max_wait);
+				continue;
+			},
+		};
+		let new_table = RoutingTable::new(conf, uses_sub_ids(conf, &rsp));
+		if let Err(e) = on_connect(cli, conf, &rsp, &new_table, Some(table)) {
+			error!("Could not subscribe again: {e}");
+		}
+		return Some(new_table);
+	}
+}
+
+/// Wait for `wait`, adding the messages that arrive in the meantime to `queued`.
+/// Returns false if the program was stopped while waiting.
+#[cfg(feature = "mqtt")]
+fn wait_queued(
+	rx: &mqtt::Receiver<Option<mqtt::Message>>, wait: Duration,
+	queued: &mut VecDeque<mqtt::Message>,
+) -> bool {
+	let deadline = Instant::now() + wait;
+	loop {
+		match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+			Ok(Some(msg)) => {
+				debug!("Keeping a message on {} until reconnected", msg.topic());
+				queued.push_back(msg);
+			},
+			Ok(None) => {},
+			Err(e) if e.is_timeout() => return true,
+			// The sender is gone after stop_consuming()
+			Err(_) => return false,
+		}
+	}
+}
+
+/// Check whether subscription IDs can be used on the broker connection.
+///
+/// They cannot with MQTT 3.1.1, or if the broker does not support them;
+/// commands are then recognized by their topics alone.
+#[cfg(feature = "mqtt")]
+fn uses_sub_ids(conf: &Config, rsp: &mqtt::ServerResponse) -> bool {
+	// Brokers announce missing support; absence means the feature is available
+	let sub_ids = conf.mqtt.protocol == MqttVersion::V5
+		&& rsp
+			.properties()
+			.get_int(mqtt::PropertyCode::SubscriptionIdentifiersAvailable)
+			!= Some(0);
+	if !sub_ids {
+		info!("Subscription IDs not available; matching commands by topic");
+	}
+	sub_ids
+}
+
+/// Finish connecting to the broker.
+/// Subscribes to the topics of the routing table, and announces that the program is online.
+///
+/// `subscribed` is the routing table of the previous connection of this run, if any.
+/// If the broker kept the session and the table did not change, the session still holds
+/// the subscriptions, so they are left alone. Any other session kept by the broker may hold
+/// subscriptions with other subscription IDs (for example from before the config changed),
+/// so they are replaced.
+#[cfg(feature = "mqtt")]
+fn on_connect(
+	cli: &mqtt::Client, conf: &Config, rsp: &mqtt::ServerResponse, table: &RoutingTable,
+	subscribed: Option<&RoutingTable>,
+) -> mqtt::Result<()> {
+	let mut session_present = false;
+	if let Some(conn_rsp) = rsp.connect_response() {
+		info!(
+			"Connected to {}, MQTT v. {}",
+			conn_rsp.server_uri, conn_rsp.mqtt_version
+		);
+		session_present = conn_rsp.session_present;
+	}
+	// Check if the server remembers us or no?
+	debug!("Checking session presence");
+	if session_present && subscribed == Some(table) {
+		info!("Broker kept the session; keeping the subscriptions");
+	} else {
+		if session_present {
+			unsubscribe(cli, conf, table);
+		}
+		subscribe(cli, conf, table)?;
+	}
+	set_bridge_availability(cli, conf, ONLINE);
+	Ok(())
+}
+
+/// Publish the availability of the program as a retained message.
+#[cfg(feature = "mqtt")]
+fn set_bridge_availability(cli: &mqtt::Client, conf: &Config, payload: &str) {
+	let topic = conf.mqtt.bridge_availability_topic();
+	info!("Publishing availability {payload} on {topic}");
+	let msg = mqtt::Message::new_retained(topic, payload, conf.mqtt.qos as i32);
+	if let Err(e) = cli.publish(msg) {
+		error!("Could not publish availability: {e}");
+	}
+}
+
+/// How often the config file is checked for changes.
+#[cfg(feature = "mqtt")]
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Subscribe to the command topics of the lamps and groups,
+/// and to the status topic of Home Assistant if discovery is enabled.
+#[cfg(feature = "mqtt")]
+fn subscribe(cli: &mqtt::Client, conf: &Config, table: &RoutingTable) -> mqtt::Result<()> {
+	table.subscribe(cli, conf.mqtt.qos as i32)?;
+	if conf.mqtt.ha_discovery {
+		let status_topic = conf.mqtt.ha_status_topic();
+		info!("Subscribing to {status_topic}");
+		cli.subscribe(&status_topic, conf.mqtt.qos as i32)?;
+	}
+	Ok(())
+}
+
+/// Undo subscribe(). Failures are logged.
+#[cfg(feature = "mqtt")]
+fn unsubscribe(cli: &mqtt::Client, conf: &Config, table: &RoutingTable) {
+	table.unsubscribe(cli);
+	if conf.mqtt.ha_discovery {
+		let status_topic = conf.mqtt.ha_status_topic();
+		if let Err(e) = cli.unsubscribe(&status_topic) {
+			warn!("Could not unsubscribe from {status_topic}: {e}");
+		}
+	}
+}
+
+/// Pass a command received from the broker to the worker of its lamp or group.
+///
+/// If the message has a ResponseTopic, the replies of the lamps (or the reason the command
+/// could not be sent) are published to it; see Replies.
+#[cfg(feature = "mqtt")]
+fn handle_message(
+	msg: &mqtt::Message, table: &RoutingTable, dispatcher: &Dispatcher, replies: &Replies,
+) {
+	let (msg_topic, msg_payload, msg_qos, msg_retain, msg_props) = (
+		msg.topic(),
+		msg.payload_str(), // Cow<'_,str>
+		msg.qos(),
+		msg.retained(),
+		msg.properties(),
+	);
+	info!(
+		"Received message. Topic {}, QoS {}, retain {}, props {:?}, content: {}",
+		msg_topic, msg_qos, msg_retain, msg_props, msg_payload
+	);
+	// Find the lamp or group the command is meant for
+	let Some(route) = table.find(msg) else {
+		info!("Message does not belong to any lamp or group; skipping");
+		return;
+	};
+	let reply_to = ReplyTo::from_message(msg);
+	let job = Job {
+		topic: msg_topic.to_string(),
+		payload: msg_payload.to_string(),
+		reply_to: reply_to.clone(),
+	};
+	if !dispatcher.dispatch(&route.target, job) {
+		warn!(
+			"{} | No lamp or group with this name; skipping",
+			route.target
+		);
+		if let Some(ref reply_to) = reply_to {
+			replies.send_error(
+				reply_to,
+				&format!("No lamp or group called {}", route.target),
+			);
+		}
+	}
+}
+
+/// Returns true if settings used to connect to the broker changed.
+/// They only take effect after a restart.
+#[cfg(feature = "mqtt")]
+fn broker_changed(old: &MqttConfig, new: &MqttConfig) -> bool {
+	let connection = |conf: &MqttConfig| {
+		(
+			conf.server_uri(),
+			conf.protocol,
+			conf.keep_alive,
+			conf.session_expiry,
+			conf.message_expiry,
+		)
+	};
+	let identity = |conf: &MqttConfig| {
+		(
+			conf.client_id.clone(),
+			conf.username.clone(),
+			conf.password.clone(),
+			conf.password_file.clone(),
+			conf.tls.clone(),
+			conf.lwt_payload.clone(),
+			conf.bridge_availability_topic(),
+		)
+	};
+	connection(old) != connection(new) || identity(old) != identity(new)
+}
+
+/// Load the config again and apply the changes to the running program.
+///
+/// Only the lamps that changed are reconnected. If the subscriptions changed, the program
+/// unsubscribes from the old topics and subscribes to the new ones. Changes to the broker
+/// connection itself (see broker_changed()) need a restart.
+/// If the new config is invalid, the problems are logged and the old config is kept.
+#[cfg(feature = "mqtt")]
+fn reload(
+	args: &Args, conf: &mut Config, reg: &mut Registry, cli: &mqtt::Client,
+	publisher: &StatePublisher, table: &mut RoutingTable, dispatcher: &mut Dispatcher,
+) {
+	let new_conf = match Config::load(args.config_path(), &args.all_overrides()) {
+		Ok(new_conf) => new_conf,
+		Err(e) => {
+			error!("Config not reloaded, keeping the old one:\n{e}");
+			return;
+		},
+	};
+	let diff = conf.diff(&new_conf);
+	if diff.is_empty() {
+		info!("Config file changed, but the settings did not");
+		return;
+	}
+	info!("Reloading config: {diff:?}");
+	reg.apply(&diff, &new_conf);
+	dispatcher.restart(&new_conf, reg);
+	let (old_mqtt, new_mqtt) = (&conf.mqtt, &new_conf.mqtt);
+	let new_table = RoutingTable::new(&new_conf, table.uses_sub_ids());
+	if (
+		&*table,
+		old_mqtt.qos,
+		old_mqtt.ha_discovery,
+		old_mqtt.ha_status_topic(),
+	) != (
+		&new_table,
+		new_mqtt.qos,
+		new_mqtt.ha_discovery,
+		new_mqtt.ha_status_topic(),
+	) {
+		unsubscribe(cli, conf, table);
+		if let Err(e) = subscribe(cli, &new_conf, &new_table) {
+			error!("Could not subscribe to the new command topics: {e}");
+		}
+	}
+	*table = new_table;
+	let new_settings = StateSettings::from_config(new_mqtt);
+	if StateSettings::from_config(old_mqtt) != new_settings {
+		publisher.send(StateMsg::Settings(new_settings));
+	}
+	discovery::update(cli, conf, &new_conf, &diff);
+	if broker_changed(old_mqtt, new_mqtt) {
+		warn!("Broker connection settings changed; restart the program to apply them");
+	}
+	*conf = new_conf;
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn main() {
+	eprintln!("Please enable the 'mqtt' feature flag and try again.");
+}
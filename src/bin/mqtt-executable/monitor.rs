@@ -0,0 +1,167 @@
+use crate::buffer::CommandBuffer;
+use crate::reply::Replies;
+use crate::state::StateMsg;
+use log::{debug, info, trace, warn};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use yeerugina::group::{SharedLamp, connect_shared, lock_lamp};
+use yeerugina::lamp::{Lamp, LampReader};
+use yeerugina::stateful::StatefulLamp;
+
+/// A thread that reads everything a lamp sends and keeps the lamp connected.
+///
+/// The thread tracks the state of the lamp with a StatefulLamp and sends every change
+/// to the StatePublisher. Replies to commands are passed on to Replies.
+/// When the connection is lost, the lamp is reconnected after waiting `retry_wait`,
+/// and the commands buffered in the meantime are sent.
+pub struct Monitor {
+	stop: Arc<AtomicBool>,
+	buffer: CommandBuffer,
+}
+
+impl Monitor {
+	/// Start monitoring a lamp.
+	pub fn spawn(
+		id: String, lamp: SharedLamp, retry_wait: Duration, states: Sender<StateMsg>,
+		replies: Arc<Replies>, buffer: CommandBuffer,
+	) -> Self {
+		let stop = Arc::new(AtomicBool::new(false));
+		let worker = Worker {
+			id,
+			lamp: StatefulLamp::new(lamp),
+			retry_wait,
+			states,
+			replies,
+			buffer: buffer.clone(),
+			stop: Arc::clone(&stop),
+		};
+		thread::spawn(move || worker.run());
+		Self { stop, buffer }
+	}
+
+	/// Get the buffer for the commands received while the lamp is reconnecting.
+	pub fn buffer(&self) -> &CommandBuffer {
+		&self.buffer
+	}
+
+	/// Stop the thread.
+	///
+	/// The thread notices the request after its current read or reconnection attempt;
+	/// disconnect the lamp with Lamp::disconnect() to make it notice sooner.
+	pub fn stop(&self) {
+		self.stop.store(true, Ordering::Relaxed);
+	}
+}
+
+impl Drop for Monitor {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+/// State of a monitor thread.
+struct Worker {
+	id: String,
+	lamp: StatefulLamp,
+	retry_wait: Duration,
+	states: Sender<StateMsg>,
+	replies: Arc<Replies>,
+	buffer: CommandBuffer,
+	stop: Arc<AtomicBool>,
+}
+
+impl Worker {
+	fn stopped(&self) -> bool {
+		self.stop.load(Ordering::Relaxed)
+	}
+
+	/// Send the current state to the publisher.
+	fn publish(&self) {
+		let msg = StateMsg::Update(self.id.clone(), self.lamp.state().clone());
+		if self.states.send(msg).is_err() {
+			debug!("{} | State publisher is gone", self.id);
+		}
+	}
+
+	/// Record the connection state and publish it if it changed.
+	fn set_connected(&mut self, connected: bool) {
+		if self.lamp.set_connected(connected) {
+			self.publish();
+		}
+	}
+
+	fn run(mut self) {
+		debug!("{} | Monitor started", self.id);
+		// Announce the lamp as unavailable until the first connection succeeds
+		self.publish();
+		while !self.stopped() {
+			let shared = Arc::clone(self.lamp.lamp());
+			let mut reader = match open_reader(&shared) {
+				Ok((reader, lamp)) => {
+					// Before anything else is sent on the new connection
+					self.buffer.flush(&self.id, lamp, &self.replies);
+					reader
+				},
+				Err(e) => {
+					warn!("{} | Lamp unreachable: {e}", self.id);
+					self.set_connected(false);
+					thread::sleep(self.retry_wait);
+					continue;
+				},
+			};
+			self.set_connected(true);
+			if let Err(e) = self.lamp.refresh() {
+				warn!("{} | Could not request the lamp state: {e}", self.id);
+			}
+			self.read_loop(&mut reader);
+			// Drop the connection, unless someone else has already replaced it
+			if let Ok(mut lamp) = lock_lamp(self.lamp.lamp())
+				&& lamp.connection_number() == reader.connection_number()
+			{
+				lamp.disconnect();
+			}
+			self.set_connected(false);
+		}
+		debug!("{} | Monitor stopped", self.id);
+	}
+
+	/// Handle everything the lamp sends until the connection is lost.
+	fn read_loop(&mut self, reader: &mut LampReader) {
+		while !self.stopped() {
+			match reader.read_line() {
+				Ok(Some(line)) => {
+					trace!("{} | Received {}", self.id, String::from_utf8_lossy(&line));
+					match Lamp::parse_response(&line) {
+						Ok(resp) => {
+							if self.lamp.handle_response(&resp) {
+								self.publish();
+							}
+							self.replies.handle(&self.id, &resp);
+						},
+						Err(e) => warn!("{} | Could not parse response: {e}", self.id),
+					}
+				},
+				// Read timeout; check whether we should stop
+				Ok(None) => {},
+				Err(e) => {
+					info!("{} | Connection lost: {e}", self.id);
+					return;
+				},
+			}
+		}
+	}
+}
+
+/// Connect the lamp if needed and create a reader for the connection.
+/// The lamp is returned still locked, so that nothing is sent before the buffered commands.
+///
+/// The lamp is not locked while connecting (see connect_shared()), so that commands are
+/// rejected or buffered at once instead of waiting for every connection attempt.
+fn open_reader(shared: &SharedLamp) -> io::Result<(LampReader, MutexGuard<'_, Lamp>)> {
+	let lamp = connect_shared(shared)?;
+	Ok((lamp.reader()?, lamp))
+}
@@ -0,0 +1,153 @@
+use crate::buffer::CommandBuffer;
+use crate::monitor::Monitor;
+use crate::reply::Replies;
+use crate::state::StateMsg;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use yeerugina::config::{Config, ConfigDiff, LampConfig};
+use yeerugina::group::{LampGroup, SharedLamp, lock_lamp};
+use yeerugina::lamp::Lamp;
+use yeerugina::record::Recorder;
+
+/// The lamps and groups the program currently controls.
+pub struct Registry {
+	/// Lamps keyed by their IDs.
+	pub lamps: HashMap<String, SharedLamp>,
+	/// Groups keyed by their names. The members share the lamps in `lamps`.
+	pub groups: HashMap<String, LampGroup>,
+	/// A monitor for every lamp, keyed by lamp ID.
+	monitors: HashMap<String, Monitor>,
+	/// Where the monitors send the lamp states.
+	states: Sender<StateMsg>,
+	/// Where the monitors send the replies of the lamps.
+	replies: Arc<Replies>,
+}
+
+impl Registry {
+	/// Create every lamp of the config, then create the groups.
+	/// The lamps are connected in the background by their monitors,
+	/// so an unreachable lamp does not stop the others.
+	pub fn from_config(
+		conf: &Config, states: Sender<StateMsg>, replies: Arc<Replies>,
+	) -> Result<Self, Box<dyn std::error::Error>> {
+		let mut reg = Self {
+			lamps: HashMap::new(),
+			groups: HashMap::new(),
+			monitors: HashMap::new(),
+			states,
+			replies,
+		};
+		for lamp_conf in conf.lamps.iter() {
+			reg.add_lamp(lamp_conf);
+		}
+		info!("{} lamps configured", reg.lamps.len());
+		reg.rebuild_groups(conf)?;
+		info!("{} groups configured", reg.groups.len());
+		Ok(reg)
+	}
+
+	/// Create a lamp and start its monitor, which connects it in the background.
+	///
+	/// The lamp starts out disconnected; until the monitor connects it, commands sent to it
+	/// are kept or rejected by its offline policy, as during a reconnect.
+	pub fn add_lamp(&mut self, lamp_conf: &LampConfig) {
+		let mut lamp = Lamp::from_config(lamp_conf);
+		if let Some(ref path) = lamp_conf.record_file {
+			match Recorder::open(path) {
+				Ok(recorder) => {
+					info!("{} | Recording traffic to {}", lamp_conf.id, path.display());
+					lamp.set_recorder(Some(recorder));
+				},
+				Err(e) => warn!(
+					"{} | Could not open {}; not recording: {e}",
+					lamp_conf.id,
+					path.display()
+				),
+			}
+		}
+		let lamp: SharedLamp = Arc::new(Mutex::new(lamp));
+		self.lamps.insert(lamp_conf.id.clone(), Arc::clone(&lamp));
+		let monitor = Monitor::spawn(
+			lamp_conf.id.clone(),
+			lamp,
+			lamp_conf.connection_tries_wait,
+			self.states.clone(),
+			Arc::clone(&self.replies),
+			CommandBuffer::from_config(lamp_conf),
+		);
+		self.monitors.insert(lamp_conf.id.clone(), monitor);
+	}
+
+	/// Stop monitoring a lamp and disconnect it.
+	pub fn remove_lamp(&mut self, id: &str) {
+		if let Some(monitor) = self.monitors.remove(id) {
+			monitor.stop();
+		}
+		if let Some(lamp) = self.lamps.remove(id)
+			&& let Ok(mut lamp) = lock_lamp(&lamp)
+		{
+			lamp.disconnect();
+		}
+	}
+
+	/// Get the buffer for the commands received while a lamp is reconnecting.
+	pub fn buffer(&self, id: &str) -> Option<&CommandBuffer> {
+		self.monitors.get(id).map(|monitor| monitor.buffer())
+	}
+
+	/// Drop the buffered commands that waited too long for their lamps.
+	/// See CommandBuffer::expire().
+	pub fn expire_buffers(&self) {
+		for (id, monitor) in self.monitors.iter() {
+			monitor.buffer().expire(id, &self.replies);
+		}
+	}
+
+	/// Recreate the groups from the config.
+	/// Only the groups are replaced; the lamps and their connections are kept.
+	pub fn rebuild_groups(&mut self, conf: &Config) -> Result<(), String> {
+		let mut groups: HashMap<String, LampGroup> = HashMap::new();
+		for name in conf.groups.keys() {
+			let members = conf
+				.group_members(name)?
+				.into_iter()
+				.filter_map(|id| {
+					let lamp = Arc::clone(self.lamps.get(&id)?);
+					Some((id, lamp))
+				})
+				.collect();
+			groups.insert(name.clone(), LampGroup::new(name.clone(), members));
+		}
+		self.groups = groups;
+		Ok(())
+	}
+
+	/// Bring the lamps and groups in line with a new config.
+	///
+	/// Removed lamps are disconnected and their states cleared,
+	/// added and changed lamps are (re)created, and the groups are rebuilt.
+	/// Lamps that did not change keep their connections;
+	/// the new ones are connected in the background by their monitors.
+	pub fn apply(&mut self, diff: &ConfigDiff, new_conf: &Config) {
+		for id in diff.removed_lamps.iter() {
+			info!("{id} | Lamp removed from config; disconnecting");
+			self.remove_lamp(id);
+			if self.states.send(StateMsg::Remove(id.clone())).is_err() {
+				debug!("{id} | State publisher is gone");
+			}
+		}
+		for id in diff.added_lamps.iter().chain(diff.changed_lamps.iter()) {
+			let Some(lamp_conf) = new_conf.lamp(id) else {
+				continue;
+			};
+			info!("{id} | Lamp added or changed");
+			self.remove_lamp(id);
+			self.add_lamp(lamp_conf);
+		}
+		if let Err(e) = self.rebuild_groups(new_conf) {
+			warn!("Could not rebuild groups: {e}");
+		}
+	}
+}
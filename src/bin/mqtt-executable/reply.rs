@@ -0,0 +1,269 @@
+use log::{debug, error, trace};
+use paho_mqtt as mqtt;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use yeerugina::lamp::Response;
+use yeerugina::mqtt::message_props;
+use yeerugina::structs::Command;
+
+/// How long to wait for a lamp to answer a command before replying with an error.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where to send the replies to an MQTT message, taken from its MQTT 5 properties.
+#[derive(Clone, Debug)]
+pub struct ReplyTo {
+	/// The ResponseTopic of the message.
+	topic: String,
+	/// The CorrelationData of the message, echoed in every reply.
+	correlation: Option<Vec<u8>>,
+	/// QoS of the replies; the same as the QoS of the message.
+	qos: i32,
+}
+
+impl ReplyTo {
+	/// Read the ResponseTopic and CorrelationData of a message.
+	/// Returns None if the sender did not ask for a reply.
+	pub fn from_message(msg: &mqtt::Message) -> Option<Self> {
+		let props = msg.properties();
+		let topic = props.get_string(mqtt::PropertyCode::ResponseTopic)?;
+		Some(Self {
+			topic,
+			correlation: props.get_binary(mqtt::PropertyCode::CorrelationData),
+			qos: msg.qos(),
+		})
+	}
+}
+
+/// A command sent to a lamp whose reply has not arrived yet.
+#[derive(Debug)]
+struct Pending {
+	reply_to: ReplyTo,
+	cmd: Command,
+	sent: Instant,
+}
+
+/// Publishes the replies of the lamps to the senders of MQTT commands.
+///
+/// When a command message has a ResponseTopic, the ID of the command sent to each lamp
+/// is recorded with Replies::track(). The monitor of the lamp passes every reply it reads
+/// to Replies::handle(), which publishes it to the ResponseTopic together with the
+/// CorrelationData of the command message. Each lamp gets its own reply, so a command
+/// sent to a group results in one reply per member.
+///
+/// A reply is a JSON object:
+/// ```json
+/// {"lamp":"kitchen","method":"get_prop","id":3,"status":"ok",
+///  "result":["on","40"],"props":{"power":"on","bright":"40"}}
+/// {"lamp":"kitchen","method":"set_rgb","id":4,"status":"error",
+///  "code":-1,"message":"unsupported method"}
+/// ```
+/// `props` is only present for get_prop, and maps the requested properties to their values.
+/// Errors that happen before a command reaches a lamp (such as an invalid payload)
+/// only have `status` and `message`, plus `lamp` and `method` when they are known.
+pub struct Replies {
+	cli: mqtt::Client,
+	message_expiry: Option<Duration>,
+	pending: Mutex<HashMap<(String, u8), Pending>>,
+}
+
+impl Replies {
+	/// Create a tracker that publishes the replies with the given client.
+	/// See MqttConfig::message_expiry.
+	pub fn new(cli: mqtt::Client, message_expiry: Option<Duration>) -> Self {
+		Self {
+			cli,
+			message_expiry,
+			pending: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Record the command IDs reserved on the lamps, send the command with `write`
+	/// and wait for the replies of the lamps it reached.
+	///
+	/// `ids` holds the lamp ID and the command ID prepared for every lamp
+	/// (see Lamp::prepare_cmd()). They are recorded before `write` runs, so a lamp cannot
+	/// answer before its command is recorded, but the replies of other commands are still
+	/// handled while `write` talks to the lamps.
+	/// `write` returns the command ID (or the error) for every lamp it did not leave to a
+	/// CommandBuffer. Lamps that could not be reached get an error reply at once,
+	/// and the IDs that were not written are forgotten.
+	pub fn track(
+		&self, reply_to: &ReplyTo, cmd: &Command, ids: &[(String, u8)],
+		write: impl FnOnce() -> Vec<(String, io::Result<u8>)>,
+	) {
+		self.insert(reply_to, cmd, ids.iter().cloned());
+		let results = write();
+		let written: Vec<(&str, u8)> = results
+			.iter()
+			.filter_map(|(lamp, res)| Some((lamp.as_str(), *res.as_ref().ok()?)))
+			.collect();
+		if let Ok(mut pending) = self.pending.lock() {
+			for (lamp, id) in ids {
+				if !written.contains(&(lamp.as_str(), *id)) {
+					pending.remove(&(lamp.clone(), *id));
+				}
+			}
+		}
+		self.reply_failures(reply_to, cmd, results);
+	}
+
+	/// Wait for the replies to a command that has already been sent.
+	///
+	/// Unlike Replies::track(), the IDs are recorded after the command was written.
+	/// Only call it from the thread that reads the replies of the lamps,
+	/// before it reads them again.
+	pub fn record(
+		&self, reply_to: &ReplyTo, cmd: &Command, results: Vec<(String, io::Result<u8>)>,
+	) {
+		let ids = results
+			.iter()
+			.filter_map(|(lamp, res)| Some((lamp.clone(), *res.as_ref().ok()?)));
+		self.insert(reply_to, cmd, ids);
+		self.reply_failures(reply_to, cmd, results);
+	}
+
+	/// Remember the commands whose replies are awaited.
+	fn insert(&self, reply_to: &ReplyTo, cmd: &Command, ids: impl Iterator<Item = (String, u8)>) {
+		let Ok(mut pending) = self.pending.lock() else {
+			error!("Reply tracker mutex poisoned; not waiting for the replies");
+			return;
+		};
+		for (lamp, id) in ids {
+			trace!("{lamp} | Waiting for the reply to command {id}");
+			let entry = Pending {
+				reply_to: reply_to.clone(),
+				cmd: cmd.clone(),
+				sent: Instant::now(),
+			};
+			pending.insert((lamp, id), entry);
+		}
+	}
+
+	/// Reply with an error for the lamps a command did not reach.
+	fn reply_failures(
+		&self, reply_to: &ReplyTo, cmd: &Command, results: Vec<(String, io::Result<u8>)>,
+	) {
+		for (lamp, res) in results {
+			if let Err(e) = res {
+				self.send_lamp_error(
+					reply_to,
+					&lamp,
+					cmd,
+					&format!("Could not send command: {e}"),
+				);
+			}
+		}
+	}
+
+	/// Publish the reply of a lamp if someone is waiting for it.
+	/// Notifications and replies to untracked commands are ignored.
+	pub fn handle(&self, lamp: &str, resp: &Response) {
+		let id = match resp {
+			Response::Result { id, .. } | Response::Error { id, .. } => *id,
+			Response::Notification(_) => return,
+		};
+		let Some(entry) = self
+			.pending
+			.lock()
+			.ok()
+			.and_then(|mut pending| pending.remove(&(lamp.to_string(), id)))
+		else {
+			return;
+		};
+		let mut doc = Map::new();
+		doc.insert("lamp".into(), json!(lamp));
+		doc.insert("method".into(), json!(entry.cmd.to_string()));
+		doc.insert("id".into(), json!(id));
+		match resp {
+			Response::Result { values, .. } => {
+				doc.insert("status".into(), json!("ok"));
+				doc.insert("result".into(), json!(values));
+				if let Command::GetProp(names) = &entry.cmd {
+					let props: Map<String, Value> =
+						names.iter().cloned().zip(values.iter().cloned()).collect();
+					doc.insert("props".into(), Value::Object(props));
+				}
+			},
+			Response::Error { code, message, .. } => {
+				doc.insert("status".into(), json!("error"));
+				doc.insert("code".into(), json!(code));
+				doc.insert("message".into(), json!(message));
+			},
+			Response::Notification(_) => unreachable!(),
+		}
+		self.publish(&entry.reply_to, doc);
+	}
+
+	/// Reply with an error to the commands that got no reply within REPLY_TIMEOUT.
+	pub fn expire(&self) {
+		let expired: Vec<((String, u8), Pending)> = match self.pending.lock() {
+			Ok(mut pending) => {
+				let keys: Vec<(String, u8)> = pending
+					.iter()
+					.filter(|(_, entry)| entry.sent.elapsed() >= REPLY_TIMEOUT)
+					.map(|(key, _)| key.clone())
+					.collect();
+				keys.into_iter()
+					.filter_map(|key| pending.remove_entry(&key))
+					.collect()
+			},
+			Err(_) => return,
+		};
+		for ((lamp, id), entry) in expired {
+			debug!("{lamp} | No reply to command {id}");
+			let mut doc = error_doc("No reply from lamp");
+			doc.insert("lamp".into(), json!(lamp));
+			doc.insert("method".into(), json!(entry.cmd.to_string()));
+			doc.insert("id".into(), json!(id));
+			self.publish(&entry.reply_to, doc);
+		}
+	}
+
+	/// Reply with an error about a command that was not sent to a lamp.
+	pub fn send_lamp_error(&self, reply_to: &ReplyTo, lamp: &str, cmd: &Command, message: &str) {
+		let mut doc = error_doc(message);
+		doc.insert("lamp".into(), json!(lamp));
+		doc.insert("method".into(), json!(cmd.to_string()));
+		self.publish(reply_to, doc);
+	}
+
+	/// Reply with an error that is not tied to a lamp, e.g. an invalid payload.
+	pub fn send_error(&self, reply_to: &ReplyTo, message: &str) {
+		self.publish(reply_to, error_doc(message));
+	}
+
+	/// Publish a reply document to the ResponseTopic, echoing the CorrelationData.
+	fn publish(&self, reply_to: &ReplyTo, doc: Map<String, Value>) {
+		let mut props = message_props(self.message_expiry).unwrap_or_else(|e| {
+			error!("Could not set the properties of the reply: {e}");
+			mqtt::Properties::new()
+		});
+		if let Some(ref data) = reply_to.correlation
+			&& let Err(e) = props.push_binary(mqtt::PropertyCode::CorrelationData, data.clone())
+		{
+			error!("Could not set the correlation data of the reply: {e}");
+		}
+		let payload = Value::Object(doc).to_string();
+		debug!("Replying on {}: {payload}", reply_to.topic);
+		let msg = mqtt::MessageBuilder::new()
+			.topic(reply_to.topic.clone())
+			.payload(payload)
+			.qos(reply_to.qos)
+			.properties(props)
+			.finalize();
+		if let Err(e) = self.cli.publish(msg) {
+			error!("Could not publish reply on {}: {e}", reply_to.topic);
+		}
+	}
+}
+
+/// Create the reply document of an error.
+fn error_doc(message: &str) -> Map<String, Value> {
+	let mut doc = Map::new();
+	doc.insert("status".into(), json!("error"));
+	doc.insert("message".into(), json!(message));
+	doc
+}
@@ -0,0 +1,83 @@
+use log::{info, warn};
+use paho_mqtt as mqtt;
+use std::collections::HashMap;
+use yeerugina::config::{Config, Route};
+use yeerugina::mqtt::{sub_id, topic_matches};
+
+/// The subscriptions of the lamps and groups, used to find the target of a message.
+///
+/// Every lamp and group has its own subscription (see Config::routes()). With subscription
+/// IDs, a message is routed by the ID the broker attaches to it. Without them (MQTT 3.1.1,
+/// or a broker not supporting them), the topic of the message is matched against the
+/// topic filters of each subscription.
+#[derive(Debug, PartialEq)]
+pub struct RoutingTable {
+	routes: Vec<Route>,
+	by_sub_id: HashMap<i32, usize>,
+	sub_ids: bool,
+}
+
+impl RoutingTable {
+	/// Create the table from the config.
+	/// `sub_ids` tells whether subscription IDs are used on the broker connection.
+	pub fn new(conf: &Config, sub_ids: bool) -> Self {
+		let routes = conf.routes();
+		let by_sub_id = routes
+			.iter()
+			.enumerate()
+			.map(|(idx, route)| (route.sub_id, idx))
+			.collect();
+		Self {
+			routes,
+			by_sub_id,
+			sub_ids,
+		}
+	}
+
+	/// Returns true if the messages are routed by subscription ID.
+	pub fn uses_sub_ids(&self) -> bool {
+		self.sub_ids
+	}
+
+	/// Subscribe to the command topics of every lamp and group.
+	pub fn subscribe(&self, cli: &mqtt::Client, qos: i32) -> mqtt::Result<()> {
+		for route in self.routes.iter() {
+			for filter in route.filters.iter() {
+				info!("{} | Subscribing to {filter}", route.target);
+				if self.sub_ids {
+					cli.subscribe_with_options(filter.as_str(), qos, None, sub_id(route.sub_id))?;
+				} else {
+					cli.subscribe(filter, qos)?;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Unsubscribe from the command topics of every lamp and group.
+	/// Failures are logged.
+	pub fn unsubscribe(&self, cli: &mqtt::Client) {
+		for filter in self.routes.iter().flat_map(|route| route.filters.iter()) {
+			if let Err(e) = cli.unsubscribe(filter) {
+				warn!("Could not unsubscribe from {filter}: {e}");
+			}
+		}
+	}
+
+	/// Find the lamp or group a message is meant for.
+	pub fn find(&self, msg: &mqtt::Message) -> Option<&Route> {
+		if self.sub_ids {
+			let id = msg
+				.properties()
+				.get_int(mqtt::PropertyCode::SubscriptionIdentifier)?;
+			self.by_sub_id.get(&id).map(|&idx| &self.routes[idx])
+		} else {
+			self.routes.iter().find(|route| {
+				route
+					.filters
+					.iter()
+					.any(|filter| topic_matches(filter, msg.topic()))
+			})
+		}
+	}
+}
@@ -0,0 +1,207 @@
+use log::{debug, error, info};
+use paho_mqtt as mqtt;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use yeerugina::config::{MqttConfig, OFFLINE, ONLINE};
+use yeerugina::stateful::LampState;
+
+/// Settings of the StatePublisher, taken from the MqttConfig.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateSettings {
+	/// Base topic; states go to `<topic>/<lamp id>/state`.
+	pub topic: String,
+	/// Availabilities go to `<availability_prefix>/<lamp id>/availability`.
+	pub availability_prefix: String,
+	/// QoS of the state messages.
+	pub qos: i32,
+	/// See MqttConfig::state_debounce.
+	pub debounce: Duration,
+}
+
+impl StateSettings {
+	/// Take the settings from the MQTT config.
+	pub fn from_config(conf: &MqttConfig) -> Self {
+		Self {
+			topic: conf.topic.clone(),
+			availability_prefix: conf.availability_prefix().to_string(),
+			qos: conf.qos as i32,
+			debounce: conf.state_debounce,
+		}
+	}
+
+	fn state_topic(&self, lamp: &str) -> String {
+		format!("{}/{}/state", self.topic, lamp)
+	}
+
+	fn availability_topic(&self, lamp: &str) -> String {
+		format!("{}/{}/availability", self.availability_prefix, lamp)
+	}
+}
+
+/// Messages handled by the publisher thread.
+#[derive(Debug)]
+pub enum StateMsg {
+	/// The state of a lamp changed.
+	Update(String, LampState),
+	/// A lamp was removed; its retained state and availability are cleared.
+	Remove(String),
+	/// The MQTT settings changed.
+	Settings(StateSettings),
+	/// The connection to the broker was restored; everything is published again.
+	Resync,
+}
+
+/// Publishes the state of every lamp as a retained message on `<topic>/<lamp id>/state`.
+///
+/// The lamp monitors send their updates through a channel. After the first update,
+/// the publisher waits for the debounce time and then publishes the latest state of every
+/// lamp that changed in the meantime. States equal to the last published one are skipped.
+///
+/// Whether the program is connected to the lamp is also published on the availability topic
+/// of the lamp, as a retained `online` or `offline`, whenever it changes.
+pub struct StatePublisher {
+	tx: Sender<StateMsg>,
+}
+
+impl StatePublisher {
+	/// Start the publisher thread.
+	pub fn start(cli: mqtt::Client, settings: StateSettings) -> Self {
+		let (tx, rx) = mpsc::channel();
+		thread::spawn(move || run(cli, settings, rx));
+		Self { tx }
+	}
+
+	/// Get a sender for state updates.
+	pub fn sender(&self) -> Sender<StateMsg> {
+		self.tx.clone()
+	}
+
+	/// Pass a message to the publisher thread.
+	pub fn send(&self, msg: StateMsg) {
+		if self.tx.send(msg).is_err() {
+			error!("State publisher is not running");
+		}
+	}
+}
+
+/// Body of the publisher thread. Ends once every sender is gone.
+fn run(cli: mqtt::Client, mut settings: StateSettings, rx: Receiver<StateMsg>) {
+	let mut pending: BTreeMap<String, LampState> = BTreeMap::new();
+	let mut published: HashMap<String, LampState> = HashMap::new();
+	let mut available: HashMap<String, bool> = HashMap::new();
+	let mut deadline = Instant::now();
+	loop {
+		let msg = if pending.is_empty() {
+			match rx.recv() {
+				Ok(msg) => msg,
+				Err(_) => break,
+			}
+		} else {
+			match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+				Ok(msg) => msg,
+				Err(RecvTimeoutError::Timeout) => {
+					publish(
+						&cli,
+						&settings,
+						&mut pending,
+						&mut published,
+						&mut available,
+					);
+					continue;
+				},
+				Err(RecvTimeoutError::Disconnected) => break,
+			}
+		};
+		match msg {
+			StateMsg::Update(id, state) => {
+				if pending.is_empty() {
+					deadline = Instant::now() + settings.debounce;
+				}
+				pending.insert(id, state);
+			},
+			StateMsg::Remove(id) => {
+				pending.remove(&id);
+				if published.remove(&id).is_some() {
+					clear(&cli, &settings, settings.state_topic(&id));
+				}
+				if available.remove(&id).is_some() {
+					clear(&cli, &settings, settings.availability_topic(&id));
+				}
+			},
+			StateMsg::Settings(new_settings) => {
+				if new_settings.topic != settings.topic {
+					info!("State topic changed; publishing every state again");
+					pending.extend(published.drain());
+					deadline = Instant::now();
+				}
+				if new_settings.availability_prefix != settings.availability_prefix {
+					info!("Availability topics changed; publishing every availability again");
+					for id in available.keys() {
+						clear(&cli, &settings, settings.availability_topic(id));
+					}
+					available.clear();
+					for (id, state) in published.iter() {
+						pending.entry(id.clone()).or_insert_with(|| state.clone());
+					}
+					deadline = Instant::now();
+				}
+				settings = new_settings;
+			},
+			StateMsg::Resync => {
+				for (id, state) in published.drain() {
+					pending.entry(id).or_insert(state);
+				}
+				available.clear();
+				deadline = Instant::now();
+			},
+		}
+	}
+	debug!("State publisher stopped");
+}
+
+/// Publish the pending states that differ from the published ones,
+/// and the availabilities that changed.
+fn publish(
+	cli: &mqtt::Client, settings: &StateSettings, pending: &mut BTreeMap<String, LampState>,
+	published: &mut HashMap<String, LampState>, available: &mut HashMap<String, bool>,
+) {
+	for (id, state) in std::mem::take(pending) {
+		if available.get(&id) != Some(&state.connected) {
+			let payload = if state.connected { ONLINE } else { OFFLINE };
+			debug!("{id} | Publishing availability {payload}");
+			let msg = mqtt::Message::new_retained(
+				settings.availability_topic(&id),
+				payload,
+				settings.qos,
+			);
+			match cli.publish(msg) {
+				Ok(()) => {
+					available.insert(id.clone(), state.connected);
+				},
+				Err(e) => error!("{id} | Could not publish availability: {e}"),
+			}
+		}
+		if published.get(&id) == Some(&state) {
+			continue;
+		}
+		let payload = state.to_json().to_string();
+		debug!("{id} | Publishing state {payload}");
+		let msg = mqtt::Message::new_retained(settings.state_topic(&id), payload, settings.qos);
+		match cli.publish(msg) {
+			Ok(()) => {
+				published.insert(id, state);
+			},
+			Err(e) => error!("{id} | Could not publish state: {e}"),
+		}
+	}
+}
+
+/// Delete a retained message with an empty retained message.
+fn clear(cli: &mqtt::Client, settings: &StateSettings, topic: String) {
+	let msg = mqtt::Message::new_retained(topic.clone(), Vec::new(), settings.qos);
+	if let Err(e) = cli.publish(msg) {
+		error!("Could not clear {topic}: {e}");
+	}
+}
@@ -0,0 +1,65 @@
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// Detects changes to the config file by comparing its modification time.
+/// On Unix, receiving SIGHUP also counts as a change.
+pub struct ConfigWatcher {
+	path: String,
+	modified: Option<SystemTime>,
+	hangup: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+	/// Start watching a file. The current state of the file is not reported as a change.
+	pub fn new(path: String) -> Self {
+		let modified = modified_time(&path);
+		let hangup = Arc::new(AtomicBool::new(false));
+		#[cfg(unix)]
+		if let Err(e) =
+			signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hangup))
+		{
+			warn!("Could not add SIGHUP handling: {e}");
+		}
+		Self {
+			path,
+			modified,
+			hangup,
+		}
+	}
+
+	/// Get the path of the watched file.
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	/// Returns true if the file was modified (or SIGHUP was received)
+	/// since the last call or since ConfigWatcher::new().
+	pub fn changed(&mut self) -> bool {
+		let modified = modified_time(&self.path);
+		if self.hangup.swap(false, Ordering::Relaxed) {
+			info!("Received SIGHUP");
+			self.modified = modified;
+			return true;
+		}
+		if modified.is_none() || modified == self.modified {
+			return false;
+		}
+		debug!("Config file {} was modified", self.path);
+		self.modified = modified;
+		true
+	}
+}
+
+/// Get the modification time of a file.
+/// Errors are logged and reported as None, so that a file being replaced is not a change.
+fn modified_time(path: &str) -> Option<SystemTime> {
+	match std::fs::metadata(path).and_then(|m| m.modified()) {
+		Ok(time) => Some(time),
+		Err(e) => {
+			debug!("Could not read modification time of {path}: {e}");
+			None
+		},
+	}
+}
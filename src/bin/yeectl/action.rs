@@ -0,0 +1,227 @@
+use serde_json::{Value, json};
+use yeerugina::mqtt::{MqttCommand, parse_mqtt_command, parse_raw_param};
+use yeerugina::stateful::STATE_PROPS;
+use yeerugina::structs::{Command, Transition};
+
+/// Names of the subcommands that act on lamps.
+pub const ACTIONS: [&str; 12] = [
+	"on", "off", "toggle", "bright", "rgb", "ct", "hsv", "flow", "scene", "get", "timer", "raw",
+];
+
+/// Usage of the scene subcommand, without the target.
+const SCENE_USAGE: &str = "expected color <rrggbb> <bright>, hsv <hue> <sat> <bright>, \
+	ct <kelvin> <bright> or auto_delay_off <bright> <minutes>";
+
+/// Build the commands of a subcommand from its name and the arguments after the target.
+/// scene and timer are sent as raw commands, as the library has no Command for them,
+/// and raw takes one parameter per argument, so quoted arguments may contain spaces;
+/// the other subcommands are translated to the text syntax of parse_mqtt_command().
+pub fn parse_action(name: &str, args: &[String]) -> Result<MqttCommand, String> {
+	let cmd = match name {
+		"scene" => scene(args),
+		"timer" => timer(args),
+		"raw" => raw(args),
+		_ => return parse_text(name, args),
+	}
+	.map_err(|e| format!("{name}: {e}"))?;
+	Ok(MqttCommand {
+		cmds: vec![cmd],
+		transition: Transition::default(),
+	})
+}
+
+/// Translate a subcommand to the text syntax of parse_mqtt_command().
+fn parse_text(name: &str, args: &[String]) -> Result<MqttCommand, String> {
+	let args = args.join(" ");
+	let text = match (name, args.as_str()) {
+		("on", _) => format!("power on {args}"),
+		("off", _) => format!("power off {args}"),
+		("toggle", "") => String::from("toggle"),
+		("toggle", _) => return Err(String::from("toggle: takes no arguments")),
+		("flow", "stop") => String::from("stop_cf"),
+		("get", "") => format!("get {}", STATE_PROPS.join(" ")),
+		("bright" | "rgb" | "ct" | "hsv" | "flow" | "get", _) => format!("{name} {args}"),
+		_ => return Err(format!("Unknown command \"{name}\"")),
+	};
+	parse_mqtt_command(text)
+}
+
+/// Build a set_scene command, checking its values against the matching commands.
+fn scene(args: &[String]) -> Result<Command, String> {
+	let Some((class, values)) = args.split_first() else {
+		return Err(String::from(SCENE_USAGE));
+	};
+	let values: Vec<usize> = match (class.as_str(), values) {
+		("color", [rgb, bright]) => {
+			let rgb = hex_rgb(rgb)?;
+			Command::new_rgb(rgb)?;
+			vec![rgb, brightness(bright)?]
+		},
+		("hsv", [hue, sat, bright]) => {
+			let (hue, sat) = (number(hue, "hue")?, number(sat, "saturation")?);
+			Command::new_hsv(hue, sat)?;
+			vec![hue, sat, brightness(bright)?]
+		},
+		("ct", [ct, bright]) => {
+			let ct = number(ct, "color temperature")?;
+			Command::new_ct_abx(ct)?;
+			vec![ct, brightness(bright)?]
+		},
+		("auto_delay_off", [bright, minutes]) => vec![brightness(bright)?, timer_minutes(minutes)?],
+		_ => return Err(String::from(SCENE_USAGE)),
+	};
+	let params = std::iter::once(json!(class))
+		.chain(values.into_iter().map(Value::from))
+		.collect();
+	Command::new_raw(String::from("set_scene"), params)
+}
+
+/// Build the command that prints (no arguments), cancels (off) or sets the power-off timer.
+fn timer(args: &[String]) -> Result<Command, String> {
+	// Type 0 is the only timer the lamps support: power off
+	let (method, params) = match args {
+		[] => ("cron_get", vec![json!(0)]),
+		[off] if off == "off" => ("cron_del", vec![json!(0)]),
+		[minutes] => ("cron_add", vec![json!(0), json!(timer_minutes(minutes)?)]),
+		_ => return Err(String::from("expected <minutes> or off")),
+	};
+	Command::new_raw(String::from(method), params)
+}
+
+/// Build a raw command from a method and its parameters, each read by parse_raw_param().
+fn raw(args: &[String]) -> Result<Command, String> {
+	let Some((method, params)) = args.split_first() else {
+		return Err(String::from("missing method"));
+	};
+	let params = params.iter().map(|word| parse_raw_param(word)).collect();
+	Command::new_raw(method.clone(), params)
+}
+
+/// Parse a number of minutes for a timer, which must last at least a minute.
+fn timer_minutes(word: &str) -> Result<usize, String> {
+	match number(word, "minutes")? {
+		0 => Err(String::from("Timer must last at least 1 minute")),
+		minutes => Ok(minutes),
+	}
+}
+
+/// Parse a brightness, checking it like Command::SetBright.
+fn brightness(word: &str) -> Result<usize, String> {
+	let bright = number(word, "brightness")?;
+	Command::new_bright(bright)?;
+	Ok(bright)
+}
+
+/// Parse a color like "ff8800" or "#ff8800".
+fn hex_rgb(word: &str) -> Result<usize, String> {
+	let hex = word.strip_prefix('#').unwrap_or(word);
+	if hex.len() != 6 {
+		return Err(format!("expected a color like \"ff8800\", got \"{word}\""));
+	}
+	usize::from_str_radix(hex, 16).map_err(|e| format!("invalid color \"{word}\": {e}"))
+}
+
+/// Parse a non-negative number, naming `what` in the error.
+fn number(word: &str, what: &str) -> Result<usize, String> {
+	word.parse()
+		.map_err(|e| format!("invalid {what} \"{word}\": {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+	use yeerugina::structs::Effect;
+
+	fn words(line: &str) -> Vec<String> {
+		line.split_whitespace().map(String::from).collect()
+	}
+
+	/// Get the method and the parameters of the single command of an action.
+	fn sent(name: &str, line: &str) -> (String, Vec<Value>) {
+		let action = parse_action(name, &words(line)).unwrap();
+		let [cmd] = action.cmds.as_slice() else {
+			panic!("expected one command, got {:?}", action.cmds);
+		};
+		(
+			cmd.to_string(),
+			cmd.params(&Effect::Sudden, &Duration::ZERO),
+		)
+	}
+
+	#[test]
+	fn scene_is_sent_as_set_scene() {
+		assert_eq!(
+			sent("scene", "color ff8800 40"),
+			(
+				String::from("set_scene"),
+				vec![json!("color"), json!(0xff8800), json!(40)]
+			)
+		);
+		assert_eq!(
+			sent("scene", "hsv 120 80 40"),
+			(
+				String::from("set_scene"),
+				vec![json!("hsv"), json!(120), json!(80), json!(40)]
+			)
+		);
+		assert_eq!(
+			sent("scene", "auto_delay_off 40 30"),
+			(
+				String::from("set_scene"),
+				vec![json!("auto_delay_off"), json!(40), json!(30)]
+			)
+		);
+	}
+
+	#[test]
+	fn scene_checks_its_values() {
+		for line in [
+			"",
+			"sunset 40",
+			"color ff8800",
+			"color ff8800 0",
+			"hsv 360 80 40",
+			"ct 1000 40",
+			"auto_delay_off 40 0",
+		] {
+			assert!(parse_action("scene", &words(line)).is_err(), "{line}");
+		}
+	}
+
+	#[test]
+	fn timer_gets_sets_and_cancels() {
+		assert_eq!(
+			sent("timer", ""),
+			(String::from("cron_get"), vec![json!(0)])
+		);
+		assert_eq!(
+			sent("timer", "off"),
+			(String::from("cron_del"), vec![json!(0)])
+		);
+		assert_eq!(
+			sent("timer", "30"),
+			(String::from("cron_add"), vec![json!(0), json!(30)])
+		);
+		assert!(parse_action("timer", &words("0")).is_err());
+		assert!(parse_action("timer", &words("30 40")).is_err());
+	}
+
+	#[test]
+	fn raw_keeps_one_parameter_per_argument() {
+		let args = [String::from("set_name"), String::from("Living room")];
+		let action = parse_action("raw", &args).unwrap();
+		assert_eq!(
+			action.cmds[0].params(&Effect::Sudden, &Duration::ZERO),
+			vec![json!("Living room")]
+		);
+		assert_eq!(
+			sent("raw", "set_scene color 65280 70"),
+			(
+				String::from("set_scene"),
+				vec![json!("color"), json!(65280), json!(70)]
+			)
+		);
+		assert!(parse_action("raw", &[]).is_err());
+	}
+}
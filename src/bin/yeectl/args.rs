@@ -0,0 +1,129 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use yeerugina::config::ENV_CONFIG_PATH;
+
+/// Usage text printed by --help.
+pub const USAGE: &str = "\
+Usage: yeectl [OPTIONS] <command> [arguments]
+
+Commands:
+  discover                          Look for lamps on the network
+  on <target> [transition]          Turn the lamp on
+  off <target> [transition]         Turn the lamp off
+  toggle <target>                   Toggle the lamp
+  bright <target> <1-100> [transition]
+  rgb <target> <rrggbb> [transition]
+  ct <target> <1700-6500> [transition]
+  hsv <target> <hue> <sat> [transition]
+  flow <target> <count> <recover|stay|off> <expression>
+  flow <target> stop
+  scene <target> color <rrggbb> <bright>
+  scene <target> hsv <hue> <sat> <bright>
+  scene <target> ct <kelvin> <bright>
+  scene <target> auto_delay_off <bright> <minutes>
+  get <target> [property...]        Print properties (default: the whole state)
+  timer <target> [<minutes>|off]    Print, set or cancel the power-off timer
+  raw <target> <method> [param...]  Send any method; params are JSON values or strings
+  shell <lamp>                      Keep a connection open and type the commands above
+                                    interactively, without the target
+
+A target is an IP address (port 55443 by default), the ID or name of a lamp
+in the config, or the name of a group. [transition] is an effect (smooth, sudden)
+and/or a duration (500ms, 2s).
+
+Options:
+  --config <path>   Read lamp names and groups from <path>
+                    (default: $YEERUGINA_CONFIG, then ./config.toml)
+  --json            Print JSON instead of a table
+  --timeout <dur>   How long to wait for lamps (default: 3s)
+  --ssdp <addr>     Send discovery requests to <addr> instead of the lamps' multicast group
+  --help            Print this text and exit
+
+Exit codes:
+  0  Every lamp succeeded
+  1  A lamp could not be reached, answered with an error or did not answer,
+     or discover found no lamps
+  2  Invalid arguments
+  3  The target is unknown or the config could not be read";
+
+/// Default value of --timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Command line arguments of the program.
+#[derive(Debug)]
+pub struct Args {
+	/// Path of the config file.
+	pub config_path: Option<String>,
+	/// Print JSON instead of a table.
+	pub json: bool,
+	/// How long to wait for lamps.
+	pub timeout: Duration,
+	/// Where to send discovery requests.
+	pub ssdp: Option<SocketAddr>,
+	/// Only print the usage.
+	pub help: bool,
+	/// The command and its arguments.
+	pub words: Vec<String>,
+}
+
+impl Default for Args {
+	fn default() -> Self {
+		Self {
+			config_path: None,
+			json: false,
+			timeout: DEFAULT_TIMEOUT,
+			ssdp: None,
+			help: false,
+			words: Vec::new(),
+		}
+	}
+}
+
+impl Args {
+	/// Parse the arguments given to the program (without the program name).
+	/// Options may be given before or after the command.
+	pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+		let mut parsed = Self::default();
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--config" => {
+					let path = args.next().ok_or("--config requires a path")?;
+					parsed.config_path = Some(path);
+				},
+				"--json" => parsed.json = true,
+				"--timeout" => {
+					let dur = args.next().ok_or("--timeout requires a duration")?;
+					parsed.timeout = humantime::parse_duration(&dur)
+						.map_err(|e| format!("Invalid value \"{dur}\" for --timeout: {e}"))?;
+					if parsed.timeout.is_zero() {
+						return Err(String::from("--timeout cannot be zero"));
+					}
+				},
+				"--ssdp" => {
+					let addr = args.next().ok_or("--ssdp requires an address")?;
+					let addr = addr
+						.parse()
+						.map_err(|e| format!("Invalid value \"{addr}\" for --ssdp: {e}"))?;
+					parsed.ssdp = Some(addr);
+				},
+				"--help" | "-h" => parsed.help = true,
+				other if other.starts_with("--") => {
+					return Err(format!("Unknown argument \"{other}\""));
+				},
+				_ => parsed.words.push(arg),
+			}
+		}
+		if !parsed.help && parsed.words.is_empty() {
+			return Err(String::from("No command given"));
+		}
+		Ok(parsed)
+	}
+
+	/// Get the path of the config file: --config, then $YEERUGINA_CONFIG, then config.toml.
+	pub fn config_path(&self) -> String {
+		self.config_path
+			.clone()
+			.or_else(|| std::env::var(ENV_CONFIG_PATH).ok())
+			.unwrap_or_else(|| String::from("config.toml"))
+	}
+}
@@ -0,0 +1,141 @@
+mod action;
+mod args;
+mod output;
+mod run;
+mod shell;
+mod target;
+
+use action::parse_action;
+use args::{Args, USAGE};
+use log::debug;
+use output::{print_discovered, print_outcomes};
+use run::run_all;
+use std::process::ExitCode;
+use target::{ResolveError, Target, resolve};
+use yeerugina::config::{Config, Override};
+use yeerugina::discovery::{discover, multicast_target};
+
+/// Every lamp succeeded.
+const EXIT_OK: u8 = 0;
+/// A lamp failed, or no lamp was found.
+const EXIT_LAMP: u8 = 1;
+/// The arguments are invalid.
+const EXIT_USAGE: u8 = 2;
+/// The target is unknown or the config could not be read.
+const EXIT_TARGET: u8 = 3;
+
+fn main() -> ExitCode {
+	env_logger::init();
+
+	let args = match Args::parse(std::env::args().skip(1)) {
+		Ok(args) => args,
+		Err(e) => {
+			eprintln!("{e}\n\n{USAGE}");
+			return ExitCode::from(EXIT_USAGE);
+		},
+	};
+	if args.help {
+		println!("{USAGE}");
+		return ExitCode::from(EXIT_OK);
+	}
+
+	let (name, rest) = args
+		.words
+		.split_first()
+		.expect("Args::parse() requires a command");
+	if name == "discover" {
+		return run_discover(&args, rest);
+	}
+	let Some((target, cmd_args)) = rest.split_first() else {
+		eprintln!("{name}: missing target\n\n{USAGE}");
+		return ExitCode::from(EXIT_USAGE);
+	};
+	if name == "shell" {
+		return run_shell(&args, target, cmd_args);
+	}
+	let action = match parse_action(name, cmd_args) {
+		Ok(action) => action,
+		Err(e) => {
+			eprintln!("{e}");
+			return ExitCode::from(EXIT_USAGE);
+		},
+	};
+	debug!("Commands: {action:?}");
+
+	let targets = match resolve_targets(&args, target) {
+		Ok(targets) => targets,
+		Err(code) => return code,
+	};
+	let outcomes = run_all(&targets, &action, args.timeout);
+	print_outcomes(&outcomes, args.json);
+	if outcomes.iter().all(|outcome| outcome.is_ok()) {
+		ExitCode::from(EXIT_OK)
+	} else {
+		ExitCode::from(EXIT_LAMP)
+	}
+}
+
+/// Find the lamps of a target, printing an error if there are none.
+fn resolve_targets(args: &Args, target: &str) -> Result<Vec<Target>, ExitCode> {
+	// The config is only read for names and groups, so that addresses work without one
+	let load_config =
+		|| Config::load(args.config_path(), &Override::from_env()).map_err(|e| e.to_string());
+	match resolve(target, load_config) {
+		Ok(targets) if targets.is_empty() => {
+			eprintln!("Group {target} has no lamps");
+			Err(ExitCode::from(EXIT_TARGET))
+		},
+		Ok(targets) => Ok(targets),
+		Err(ResolveError::Config(e) | ResolveError::Unknown(e)) => {
+			eprintln!("{e}");
+			Err(ExitCode::from(EXIT_TARGET))
+		},
+	}
+}
+
+/// Open an interactive shell on a single lamp.
+fn run_shell(args: &Args, target: &str, rest: &[String]) -> ExitCode {
+	if !rest.is_empty() {
+		eprintln!("shell: takes no arguments besides the lamp\n\n{USAGE}");
+		return ExitCode::from(EXIT_USAGE);
+	}
+	let targets = match resolve_targets(args, target) {
+		Ok(targets) => targets,
+		Err(code) => return code,
+	};
+	let [target] = targets.as_slice() else {
+		eprintln!("shell: {target} is a group; give a single lamp");
+		return ExitCode::from(EXIT_USAGE);
+	};
+	match shell::run(target, args.timeout) {
+		Ok(()) => ExitCode::from(EXIT_OK),
+		Err(e) => {
+			eprintln!("{e}");
+			ExitCode::from(EXIT_LAMP)
+		},
+	}
+}
+
+/// Look for lamps and print them.
+fn run_discover(args: &Args, rest: &[String]) -> ExitCode {
+	if !rest.is_empty() {
+		eprintln!("discover: takes no arguments\n\n{USAGE}");
+		return ExitCode::from(EXIT_USAGE);
+	}
+	let target = args.ssdp.unwrap_or_else(multicast_target);
+	match discover(target, args.timeout) {
+		Ok(lamps) => {
+			print_discovered(&lamps, args.json);
+			if lamps.is_empty() {
+				eprintln!("No lamps found");
+				ExitCode::from(EXIT_LAMP)
+			} else {
+				ExitCode::from(EXIT_OK)
+			}
+		},
+		Err(e) => {
+			eprintln!("Could not send discovery request: {e}");
+			ExitCode::from(EXIT_LAMP)
+		},
+	}
+}
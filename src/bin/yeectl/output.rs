@@ -0,0 +1,145 @@
+use crate::run::{Outcome, Status};
+use serde_json::{Map, Value, json};
+use yeerugina::discovery::DiscoveredLamp;
+
+/// Print the outcome of an action, one lamp per row or JSON object.
+///
+/// The JSON objects have the same fields as the MQTT replies:
+/// ```json
+/// [{"lamp":"kitchen","address":"192.168.1.3:55443","method":"get_prop","status":"ok",
+///   "result":["on","40"],"props":{"power":"on","bright":"40"}}]
+/// ```
+pub fn print_outcomes(outcomes: &[Outcome], json: bool) {
+	if json {
+		let docs: Vec<Value> = outcomes.iter().map(outcome_json).collect();
+		println!("{}", Value::Array(docs));
+		return;
+	}
+	let rows = outcomes
+		.iter()
+		.map(|outcome| {
+			let (status, detail) = match outcome.status {
+				Status::Ok(ref values) => ("ok", values_text(outcome.props.as_deref(), values)),
+				Status::LampError { code, ref message } => {
+					("error", format!("{message} (code {code})"))
+				},
+				Status::Failed(ref message) => ("failed", message.clone()),
+			};
+			vec![
+				outcome.lamp.clone(),
+				outcome.addr.to_string(),
+				status.to_string(),
+				detail,
+			]
+		})
+		.collect();
+	print_table(&["LAMP", "ADDRESS", "STATUS", "RESULT"], rows);
+}
+
+/// Print the lamps found by discover.
+pub fn print_discovered(lamps: &[DiscoveredLamp], json: bool) {
+	if json {
+		println!("{}", json!(lamps));
+		return;
+	}
+	let prop =
+		|lamp: &DiscoveredLamp, name: &str| lamp.props.get(name).cloned().unwrap_or_default();
+	let rows = lamps
+		.iter()
+		.map(|lamp| {
+			vec![
+				lamp.id.clone(),
+				lamp.location.to_string(),
+				lamp.model.clone(),
+				lamp.name.clone(),
+				prop(lamp, "power"),
+				prop(lamp, "bright"),
+			]
+		})
+		.collect();
+	print_table(&["ID", "ADDRESS", "MODEL", "NAME", "POWER", "BRIGHT"], rows);
+}
+
+/// Create the JSON object of an outcome.
+fn outcome_json(outcome: &Outcome) -> Value {
+	let mut doc = Map::new();
+	doc.insert("lamp".into(), json!(outcome.lamp));
+	doc.insert("address".into(), json!(outcome.addr.to_string()));
+	if !outcome.method.is_empty() {
+		doc.insert("method".into(), json!(outcome.method));
+	}
+	match outcome.status {
+		Status::Ok(ref values) => {
+			doc.insert("status".into(), json!("ok"));
+			doc.insert("result".into(), json!(values));
+			if let Some(ref names) = outcome.props {
+				let props: Map<String, Value> =
+					names.iter().cloned().zip(values.iter().cloned()).collect();
+				doc.insert("props".into(), Value::Object(props));
+			}
+		},
+		Status::LampError { code, ref message } => {
+			doc.insert("status".into(), json!("error"));
+			doc.insert("code".into(), json!(code));
+			doc.insert("message".into(), json!(message));
+		},
+		Status::Failed(ref message) => {
+			doc.insert("status".into(), json!("error"));
+			doc.insert("message".into(), json!(message));
+		},
+	}
+	Value::Object(doc)
+}
+
+/// Format the values returned by a lamp on a single line.
+/// Given the names of the requested properties, values are shown as `name=value`.
+pub fn values_text(props: Option<&[String]>, values: &[Value]) -> String {
+	match props {
+		Some(names) => names
+			.iter()
+			.zip(values)
+			.map(|(name, val)| format!("{name}={}", value_text(val)))
+			.collect::<Vec<_>>()
+			.join(" "),
+		None => values.iter().map(value_text).collect::<Vec<_>>().join(" "),
+	}
+}
+
+/// Format a notification of changed properties as `name=value` pairs.
+pub fn props_text(props: &Map<String, Value>) -> String {
+	props
+		.iter()
+		.map(|(name, val)| format!("{name}={}", value_text(val)))
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+/// Format a single value; strings are shown without quotes.
+fn value_text(val: &Value) -> String {
+	match val {
+		Value::String(s) => s.clone(),
+		other => other.to_string(),
+	}
+}
+
+/// Print rows as columns aligned to their widest cell.
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+	let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+	for row in rows.iter() {
+		for (width, cell) in widths.iter_mut().zip(row) {
+			*width = (*width).max(cell.chars().count());
+		}
+	}
+	let format_row = |cells: Vec<&str>| {
+		let line: Vec<String> = cells
+			.iter()
+			.zip(widths.iter())
+			.map(|(cell, width)| format!("{cell:width$}"))
+			.collect();
+		line.join("  ").trim_end().to_string()
+	};
+	println!("{}", format_row(headers.to_vec()));
+	for row in rows.iter() {
+		println!("{}", format_row(row.iter().map(String::as_str).collect()));
+	}
+}
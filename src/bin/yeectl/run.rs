@@ -0,0 +1,145 @@
+use crate::target::Target;
+use log::{debug, trace};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use yeerugina::lamp::{Lamp, LampReader, Response};
+use yeerugina::mqtt::MqttCommand;
+use yeerugina::structs::Command;
+
+/// How a lamp answered.
+#[derive(Debug)]
+pub enum Status {
+	/// The lamp returned these values.
+	Ok(Vec<Value>),
+	/// The lamp answered with an error.
+	LampError {
+		/// Error code returned by the lamp.
+		code: i64,
+		/// Error message returned by the lamp.
+		message: String,
+	},
+	/// The command did not reach the lamp, or the lamp did not answer.
+	Failed(String),
+}
+
+/// The outcome of an action on one lamp.
+#[derive(Debug)]
+pub struct Outcome {
+	/// ID of the target.
+	pub lamp: String,
+	/// Address of the lamp.
+	pub addr: SocketAddr,
+	/// Method of the last command sent.
+	pub method: String,
+	/// Names of the requested properties, for get_prop.
+	pub props: Option<Vec<String>>,
+	/// The answer of the lamp.
+	pub status: Status,
+}
+
+impl Outcome {
+	/// Returns true if the lamp did what it was asked.
+	pub fn is_ok(&self) -> bool {
+		matches!(self.status, Status::Ok(_))
+	}
+}
+
+/// Run an action on every target at the same time and wait for all of them.
+pub fn run_all(targets: &[Target], action: &MqttCommand, timeout: Duration) -> Vec<Outcome> {
+	thread::scope(|scope| {
+		let handles: Vec<_> = targets
+			.iter()
+			.map(|target| scope.spawn(move || run(target, action, timeout)))
+			.collect();
+		handles
+			.into_iter()
+			.zip(targets)
+			.map(|(handle, target)| {
+				handle.join().unwrap_or_else(|_| Outcome {
+					lamp: target.id.clone(),
+					addr: target.addr,
+					method: String::new(),
+					props: None,
+					status: Status::Failed(String::from("Thread panicked")),
+				})
+			})
+			.collect()
+	})
+}
+
+/// Connect to a lamp, send the action and wait for the answer.
+/// Several commands are sent one after the other; the first failure ends the action.
+fn run(target: &Target, action: &MqttCommand, timeout: Duration) -> Outcome {
+	let mut outcome = Outcome {
+		lamp: target.id.clone(),
+		addr: target.addr,
+		method: String::new(),
+		props: None,
+		status: Status::Failed(String::from("Nothing to send")),
+	};
+	let mut lamp = target.lamp();
+	if let Err(e) = lamp.connect(Target::connection_settings(timeout)) {
+		outcome.status = Status::Failed(format!("Could not connect: {e}"));
+		return outcome;
+	}
+	let mut reader = match lamp.reader() {
+		Ok(reader) => reader,
+		Err(e) => {
+			outcome.status = Status::Failed(format!("Could not read from lamp: {e}"));
+			return outcome;
+		},
+	};
+	for cmd in action.cmds.iter() {
+		outcome.method = cmd.to_string();
+		if let Command::GetProp(names) = cmd {
+			outcome.props = Some(names.clone());
+		}
+		let id = match lamp.send_cmd_with(cmd.clone(), &action.transition) {
+			Ok(id) => id,
+			Err(e) => {
+				outcome.status = Status::Failed(format!("Could not send command: {e}"));
+				break;
+			},
+		};
+		outcome.status = wait_reply(&mut reader, id, timeout);
+		if !outcome.is_ok() {
+			break;
+		}
+	}
+	lamp.disconnect();
+	outcome
+}
+
+/// Read lines from the lamp until the answer to the request with the given ID arrives.
+/// Notifications and answers to other requests are skipped.
+fn wait_reply(reader: &mut LampReader, id: u8, timeout: Duration) -> Status {
+	let deadline = Instant::now() + timeout;
+	while Instant::now() < deadline {
+		let line = match reader.read_line() {
+			Ok(Some(line)) => line,
+			Ok(None) => continue,
+			Err(e) => return Status::Failed(format!("Could not read answer: {e}")),
+		};
+		trace!("Received {}", String::from_utf8_lossy(&line).trim_end());
+		match Lamp::parse_response(&line) {
+			Ok(Response::Result {
+				id: resp_id,
+				values,
+			}) if resp_id == id => {
+				return Status::Ok(values);
+			},
+			Ok(Response::Error {
+				id: resp_id,
+				code,
+				message,
+			}) if resp_id == id => {
+				return Status::LampError { code, message };
+			},
+			Ok(_) => {},
+			Err(e) => debug!("Ignoring line from lamp: {e}"),
+		}
+	}
+	Status::Failed(String::from("No answer from lamp"))
+}
@@ -0,0 +1,349 @@
+use crate::action::{ACTIONS, parse_action};
+use crate::output::{props_text, values_text};
+use crate::target::Target;
+use log::debug;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use strum::{EnumMessage, IntoEnumIterator};
+use yeerugina::lamp::{Lamp, LampReader, Response};
+use yeerugina::mqtt::parse_mqtt_command;
+use yeerugina::stateful::STATE_PROPS;
+use yeerugina::structs::Command;
+
+/// Name of the history file, in the home directory.
+const HISTORY_FILE: &str = ".yeectl_history";
+
+/// Words handled by the shell itself instead of the lamp.
+const BUILTINS: [&str; 3] = ["help", "quit", "exit"];
+
+/// Text printed by the help builtin.
+const SHELL_HELP: &str = "\
+Commands are those of yeectl without the target, for example:
+  on
+  bright 40 smooth 2s
+  rgb ff8800 sudden
+  get power bright ct
+  scene ct 2700 40
+  timer 30
+  raw set_name \"Living room\"
+Quotes (\"...\" or '...') keep spaces in an argument.
+The text syntax of the MQTT interface (power on, set_bright 40...) is accepted as well.
+
+Tab completes command names, and property names after get.
+Properties changed on the lamp are printed as they arrive.
+help prints this text; quit, exit or Ctrl+D leave the shell.";
+
+/// What the reader thread passes on to the shell.
+enum Event {
+	/// The lamp answered a request.
+	Reply(Response),
+	/// The connection is gone.
+	Closed(String),
+}
+
+/// Open an interactive shell on a lamp, keeping its connection open until the user leaves.
+///
+/// Returns an error if the lamp cannot be reached or the connection is lost.
+pub fn run(target: &Target, timeout: Duration) -> Result<(), String> {
+	let mut lamp = target.lamp();
+	lamp.connect(Target::connection_settings(timeout))
+		.map_err(|e| format!("Could not connect to {}: {e}", target.addr))?;
+	let reader = lamp
+		.reader()
+		.map_err(|e| format!("Could not read from lamp: {e}"))?;
+
+	let mut editor = Editor::<ShellHelper, DefaultHistory>::new()
+		.map_err(|e| format!("Could not start the shell: {e}"))?;
+	editor.set_helper(Some(ShellHelper::new()));
+	let history = history_path();
+	if let Some(ref path) = history
+		&& let Err(e) = editor.load_history(path)
+	{
+		debug!("Could not load history from {}: {e}", path.display());
+	}
+	// Fails if the input is not a terminal; notifications are printed directly then
+	let printer = editor.create_external_printer().ok();
+
+	let stop = Arc::new(AtomicBool::new(false));
+	let (tx, rx) = mpsc::channel();
+	let reader_stop = Arc::clone(&stop);
+	let handle = thread::spawn(move || read_loop(reader, printer, tx, &reader_stop));
+
+	println!(
+		"Connected to {} ({}). Type help for help.",
+		target.id, target.addr
+	);
+	let res = repl(&mut editor, &mut lamp, &rx, &target.id, timeout);
+
+	// Shutting down the connection wakes up the reader thread
+	stop.store(true, Ordering::Relaxed);
+	lamp.disconnect();
+	if handle.join().is_err() {
+		debug!("Reader thread panicked");
+	}
+	if let Some(ref path) = history
+		&& let Err(e) = editor.save_history(path)
+	{
+		debug!("Could not save history to {}: {e}", path.display());
+	}
+	res
+}
+
+/// Read lines from the user and send them to the lamp until the user leaves.
+fn repl(
+	editor: &mut Editor<ShellHelper, DefaultHistory>, lamp: &mut Lamp, rx: &Receiver<Event>,
+	name: &str, timeout: Duration,
+) -> Result<(), String> {
+	let prompt = format!("{name}> ");
+	loop {
+		let line = match editor.readline(&prompt) {
+			Ok(line) => line,
+			// Ctrl+C only clears the line
+			Err(ReadlineError::Interrupted) => continue,
+			Err(ReadlineError::Eof) => return Ok(()),
+			Err(e) => return Err(format!("Could not read input: {e}")),
+		};
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		if let Err(e) = editor.add_history_entry(line) {
+			debug!("Could not add line to history: {e}");
+		}
+		match line {
+			"quit" | "exit" => return Ok(()),
+			"help" => {
+				println!("{SHELL_HELP}");
+				continue;
+			},
+			_ => {},
+		}
+		let parsed = split_words(line).and_then(|words| match words.split_first() {
+			Some((name, args)) if ACTIONS.contains(&name.as_str()) => parse_action(name, args),
+			_ => parse_mqtt_command(line.to_string()),
+		});
+		let mqtt_cmd = match parsed {
+			Ok(mqtt_cmd) => mqtt_cmd,
+			Err(e) => {
+				println!("{e}");
+				continue;
+			},
+		};
+		for cmd in mqtt_cmd.cmds.iter() {
+			// Answers to commands that timed out are of no use anymore
+			while let Ok(event) = rx.try_recv() {
+				if let Event::Closed(e) = event {
+					return Err(format!("Connection lost: {e}"));
+				}
+			}
+			let id = lamp
+				.send_cmd_with(cmd.clone(), &mqtt_cmd.transition)
+				.map_err(|e| format!("Could not send command: {e}"))?;
+			let props = match cmd {
+				Command::GetProp(names) => Some(names.as_slice()),
+				_ => None,
+			};
+			match wait_reply(rx, id, timeout)? {
+				// cron_get (timer) returns nothing if there is no timer
+				Some(Response::Result { values, .. }) if values.is_empty() => println!("(none)"),
+				Some(Response::Result { values, .. }) => {
+					println!("{}", values_text(props, &values))
+				},
+				Some(Response::Error { code, message, .. }) => {
+					println!("error: {message} (code {code})");
+					break;
+				},
+				_ => {
+					println!("No answer from lamp");
+					break;
+				},
+			}
+		}
+	}
+}
+
+/// Split a line into words like a shell would:
+/// text in double or single quotes is part of a word, spaces included.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+	let mut words = Vec::new();
+	let mut word: Option<String> = None;
+	let mut quote = None;
+	for c in line.chars() {
+		match (quote, c) {
+			(None, '"' | '\'') => {
+				quote = Some(c);
+				word.get_or_insert_default();
+			},
+			(Some(open), _) if c == open => quote = None,
+			(None, _) if c.is_whitespace() => words.extend(word.take()),
+			_ => word.get_or_insert_default().push(c),
+		}
+	}
+	if let Some(open) = quote {
+		return Err(format!("Missing closing {open}"));
+	}
+	words.extend(word);
+	Ok(words)
+}
+
+/// Wait for the answer to the request with the given ID.
+/// Returns None if the lamp did not answer in time.
+fn wait_reply(rx: &Receiver<Event>, id: u8, timeout: Duration) -> Result<Option<Response>, String> {
+	let deadline = Instant::now() + timeout;
+	loop {
+		match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+			Ok(Event::Reply(
+				resp @ (Response::Result { id: resp_id, .. } | Response::Error { id: resp_id, .. }),
+			)) if resp_id == id => return Ok(Some(resp)),
+			Ok(Event::Reply(_)) => {},
+			Ok(Event::Closed(e)) => return Err(format!("Connection lost: {e}")),
+			Err(RecvTimeoutError::Timeout) => return Ok(None),
+			Err(RecvTimeoutError::Disconnected) => return Err(String::from("Connection lost")),
+		}
+	}
+}
+
+/// Read the lines sent by the lamp. Notifications are printed at once,
+/// answers are passed on to the shell.
+fn read_loop(
+	mut reader: LampReader, mut printer: Option<impl ExternalPrinter>, tx: Sender<Event>,
+	stop: &AtomicBool,
+) {
+	loop {
+		let line = match reader.read_line() {
+			Ok(Some(line)) => line,
+			Ok(None) if stop.load(Ordering::Relaxed) => return,
+			Ok(None) => continue,
+			Err(e) => {
+				if !stop.load(Ordering::Relaxed) {
+					show(&mut printer, format!("Connection lost: {e}"));
+					// The shell is gone if this fails, so there is no one left to tell
+					let _ = tx.send(Event::Closed(e.to_string()));
+				}
+				return;
+			},
+		};
+		match Lamp::parse_response(&line) {
+			Ok(Response::Notification(props)) => {
+				show(&mut printer, format!("props: {}", props_text(&props)));
+			},
+			Ok(resp) => {
+				if tx.send(Event::Reply(resp)).is_err() {
+					return;
+				}
+			},
+			Err(e) => debug!("Ignoring line from lamp: {e}"),
+		}
+	}
+}
+
+/// Print a message without breaking the line the user is typing.
+fn show(printer: &mut Option<impl ExternalPrinter>, msg: String) {
+	match printer {
+		Some(printer) => {
+			if let Err(e) = printer.print(format!("{msg}\n")) {
+				debug!("Could not print message: {e}");
+			}
+		},
+		None => println!("{msg}"),
+	}
+}
+
+/// Get the path of the history file, if there is a home directory.
+fn history_path() -> Option<PathBuf> {
+	std::env::home_dir().map(|home| home.join(HISTORY_FILE))
+}
+
+/// Completes the names of commands and properties.
+struct ShellHelper {
+	/// Every name a command can be given by, sorted.
+	commands: Vec<&'static str>,
+}
+
+impl ShellHelper {
+	/// Collect the names of the commands from the yeectl actions, Command and the builtins.
+	fn new() -> Self {
+		let mut commands: Vec<&'static str> = Command::iter()
+			// Not part of the text syntax
+			.filter(|cmd| !matches!(cmd, Command::SetOpaqueColor(_)))
+			.flat_map(|cmd| cmd.get_serializations())
+			.copied()
+			// Raw is displayed as its method, so only its short name can be typed
+			.filter(|name| !name.starts_with('{'))
+			.chain(ACTIONS)
+			.chain(BUILTINS)
+			.collect();
+		commands.sort_unstable();
+		commands.dedup();
+		Self { commands }
+	}
+}
+
+impl Completer for ShellHelper {
+	type Candidate = String;
+
+	fn complete(
+		&self, line: &str, pos: usize, _ctx: &Context<'_>,
+	) -> rustyline::Result<(usize, Vec<String>)> {
+		let line = &line[..pos];
+		// Start of the word under the cursor
+		let start = line.trim_end_matches(|c: char| !c.is_whitespace()).len();
+		let words: &[&str] = if start == 0 {
+			&self.commands
+		} else if matches!(line.split_whitespace().next(), Some("get" | "get_prop")) {
+			&STATE_PROPS
+		} else {
+			&[]
+		};
+		let word = &line[start..];
+		let mut candidates: Vec<String> = words
+			.iter()
+			.filter(|name| name.starts_with(word))
+			.map(|name| name.to_string())
+			.collect();
+		// A complete word is followed by its arguments
+		if let [name] = candidates.as_mut_slice() {
+			name.push(' ');
+		}
+		Ok((start, candidates))
+	}
+}
+
+impl Hinter for ShellHelper {
+	type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn quotes_keep_spaces() {
+		assert_eq!(
+			split_words(r#"raw  set_name "Living room""#).unwrap(),
+			["raw", "set_name", "Living room"]
+		);
+		assert_eq!(
+			split_words(r#"raw set_scene '["a", 1]' """#).unwrap(),
+			["raw", "set_scene", r#"["a", 1]"#, ""]
+		);
+		assert!(split_words(r#"raw set_name "Living"#).is_err());
+	}
+}
@@ -0,0 +1,118 @@
+use log::{debug, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use yeerugina::config::{Config, LampConfig};
+use yeerugina::lamp::Lamp;
+use yeerugina::record::Recorder;
+use yeerugina::structs::{ConnectionSettings, Effect};
+
+/// Port the lamps accept commands on.
+pub const LAMP_PORT: u16 = 55443;
+
+/// Default transition duration of lamps given by address.
+const DEFAULT_DURATION: Duration = Duration::from_millis(500);
+
+/// A lamp a command is sent to.
+#[derive(Debug)]
+pub struct Target {
+	/// The ID of the lamp in the config, or its address.
+	pub id: String,
+	/// Address of the lamp.
+	pub addr: SocketAddr,
+	/// Settings of the lamp in the config, if it has any.
+	conf: Option<LampConfig>,
+}
+
+/// Why a target could not be resolved.
+#[derive(Debug)]
+pub enum ResolveError {
+	/// The config is needed but could not be loaded.
+	Config(String),
+	/// No lamp or group has this name.
+	Unknown(String),
+}
+
+impl Target {
+	/// Create the Lamp for this target.
+	/// Lamps from the config use their effect, duration and recorder.
+	pub fn lamp(&self) -> Lamp {
+		let Some(ref conf) = self.conf else {
+			return Lamp::new(
+				self.id.clone(),
+				self.addr.to_string(),
+				Effect::default(),
+				DEFAULT_DURATION,
+			)
+			.expect("address of a target is valid");
+		};
+		let mut lamp = Lamp::from_config(conf);
+		if let Some(ref path) = conf.record_file {
+			match Recorder::open(path) {
+				Ok(recorder) => lamp.set_recorder(Some(recorder)),
+				Err(e) => warn!("{} | Could not open {}: {e}", conf.id, path.display()),
+			}
+		}
+		lamp
+	}
+
+	/// Connection settings that give up after `timeout`, instead of the settings of the config.
+	/// A command-line tool should not retry for long.
+	pub fn connection_settings(timeout: Duration) -> ConnectionSettings {
+		ConnectionSettings {
+			read_timeout: Some(timeout),
+			write_timeout: Some(timeout),
+			conn_timeout: timeout,
+			conn_tries: 1,
+			conn_wait: timeout,
+		}
+	}
+}
+
+/// Find the lamps a target name refers to.
+///
+/// An IP address (with or without a port) is used as is. Anything else is looked up in the
+/// config, which is only loaded when needed: first as a lamp ID, then as a lamp name
+/// (ignoring case), then as a group.
+pub fn resolve(
+	spec: &str, load_config: impl FnOnce() -> Result<Config, String>,
+) -> Result<Vec<Target>, ResolveError> {
+	let addr = spec
+		.parse::<SocketAddr>()
+		.ok()
+		.or_else(|| Some(SocketAddr::new(spec.parse::<IpAddr>().ok()?, LAMP_PORT)));
+	if let Some(addr) = addr {
+		return Ok(vec![Target {
+			id: spec.to_string(),
+			addr,
+			conf: None,
+		}]);
+	}
+	let conf = load_config().map_err(ResolveError::Config)?;
+	let from_conf = |lamp: &LampConfig| Target {
+		id: lamp.id.clone(),
+		addr: lamp.ip,
+		conf: Some(lamp.clone()),
+	};
+	let lamp = conf.lamp(spec).or_else(|| {
+		conf.lamps
+			.iter()
+			.find(|l| l.name.eq_ignore_ascii_case(spec))
+	});
+	if let Some(lamp) = lamp {
+		return Ok(vec![from_conf(lamp)]);
+	}
+	if conf.groups.contains_key(spec) {
+		let members = conf
+			.group_members(spec)
+			.map_err(|e| ResolveError::Config(format!("Group {spec}: {e}")))?;
+		debug!("Group {spec} has members {members:?}");
+		return Ok(members
+			.iter()
+			.filter_map(|id| conf.lamp(id))
+			.map(from_conf)
+			.collect());
+	}
+	Err(ResolveError::Unknown(format!(
+		"\"{spec}\" is not an IP address, nor a lamp or group in the config"
+	)))
+}
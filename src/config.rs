@@ -0,0 +1,1936 @@
+use crate::structs::{ConnectionSettings, Effect, MIN_DURATION};
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+use toml::Spanned;
+use toml::de::{DeTable, DeValue};
+
+/// Struct that stores settings of the program.
+///
+/// The struct is divided into two parts:
+/// One for the lamps, another for the MQTT connection.
+///
+/// Lamps are given as an array of tables (`[[lamp]]`).
+/// An optional `[defaults]` table may contain any lamp setting (except `id`, `name` and `ip`);
+/// its values are used for every lamp that does not set them itself.
+///
+/// Example:
+/// ```toml
+/// [defaults]
+/// default-duration = "500ms"
+/// connection-tries = 5
+///
+/// [[lamp]]
+/// id = "kitchen"
+/// name = "Kitchen ceiling"
+/// ip = "192.168.1.3:55443"
+///
+/// [[lamp]]
+/// id = "desk"
+/// name = "Desk lamp"
+/// ip = "192.168.1.4:55443"
+/// effect = "sudden"
+/// topic-mode = "per-property"
+/// offline-policy = "buffer"
+///
+/// [groups]
+/// work = ["desk"]
+/// downstairs = { members = ["kitchen", "work"], synchronized = true }
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Config {
+	/// Settings for each lamp, with the defaults already applied.
+	#[serde(rename = "lamp")]
+	pub lamps: Vec<LampConfig>,
+	/// Named groups of lamps. A member is either a lamp ID or the name of another group.
+	#[serde(default)]
+	pub groups: BTreeMap<String, GroupConfig>,
+	/// Sub-struct containing settings for the MQTT connection.
+	pub mqtt: MqttConfig,
+}
+
+/// Struct containing the IP address and several timeout values.
+///
+/// The default_duration pertains to the length of the smooth color transition of the lamp.
+/// The read/write timeouts are related to the TcpStream. None means the corresponding functions
+/// can block indefinitely.
+/// connection_tries indicates how many times the program should attempt to connect before giving
+/// up. The _wait variable is the time between attempts, while connection_timeout is related to the
+/// TcpStream::connect_timeout() function.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename = "lamp", rename_all = "kebab-case")]
+pub struct LampConfig {
+	/// A unique identifier for the lamp. Used in MQTT topics.
+	pub id: String,
+	/// A name for identifying the lamp.
+	pub name: String,
+	/// IP address and port of the lamp.
+	pub ip: SocketAddr,
+	/// How long a smooth color transition takes
+	#[serde(with = "humantime_serde")]
+	pub default_duration: Duration,
+	/// Transition effect used when a command does not specify one.
+	#[serde(default)]
+	pub effect: Effect,
+	/// Which MQTT topics the lamp reads commands from.
+	#[serde(default)]
+	pub topic_mode: TopicMode,
+	/// Methods supported by the lamp, as listed in the `support` field of its
+	/// discovery response. Used to tell Home Assistant what the lamp can do;
+	/// by default, a color lamp is assumed.
+	#[serde(default = "default_capabilities")]
+	pub capabilities: Vec<String>,
+	/// How long TcpStream waits for incoming data.
+	#[serde(
+		deserialize_with = "humantime_serde_opt",
+		serialize_with = "humantime_serialize_opt",
+		default = "default_timeout_opt"
+	)]
+	pub read_timeout: Option<Duration>,
+	/// How long TcpStream takes to send data (at maximum).
+	#[serde(
+		deserialize_with = "humantime_serde_opt",
+		serialize_with = "humantime_serialize_opt",
+		default = "default_timeout_opt"
+	)]
+	pub write_timeout: Option<Duration>,
+	/// How many tries to attempt to connect before giving up.
+	pub connection_tries: u8,
+	/// For how long to wait between connection attempts.
+	#[serde(with = "humantime_serde", default = "default_wait")]
+	pub connection_tries_wait: Duration,
+	/// How long each connection attempt takes (at maximum).
+	#[serde(with = "humantime_serde", default = "default_wait")]
+	pub connection_timeout: Duration,
+	/// What to do with commands received while the lamp is reconnecting.
+	#[serde(default)]
+	pub offline_policy: OfflinePolicy,
+	/// How many commands are kept with OfflinePolicy::Buffer.
+	/// When the buffer is full, the oldest command is dropped.
+	#[serde(default = "default_buffer_size")]
+	pub buffer_size: usize,
+	/// How long a buffered command stays valid. Older commands are dropped instead of sent.
+	#[serde(with = "humantime_serde", default = "default_buffer_timeout")]
+	pub buffer_timeout: Duration,
+	/// Record the traffic of the lamp to this JSONL file. See record::Recorder.
+	/// Several lamps may share a file.
+	#[serde(default)]
+	pub record_file: Option<PathBuf>,
+}
+
+/// Default value for buffer_size.
+fn default_buffer_size() -> usize {
+	16
+}
+
+/// Default value for buffer_timeout.
+fn default_buffer_timeout() -> Duration {
+	Duration::from_secs(30)
+}
+
+/// The default value for capabilities: the methods of a color lamp used by the program.
+fn default_capabilities() -> Vec<String> {
+	[
+		"get_prop",
+		"set_power",
+		"toggle",
+		"set_bright",
+		"set_ct_abx",
+		"set_rgb",
+		"set_hsv",
+		"start_cf",
+		"stop_cf",
+	]
+	.map(String::from)
+	.to_vec()
+}
+
+/// The default value for connection_tries_{wait,timeout}.
+fn default_wait() -> Duration {
+	Duration::from_secs(5)
+}
+
+/// The default value for {read,write}_timeout
+fn default_timeout_opt() -> Option<Duration> {
+	Some(Duration::from_secs(5))
+}
+
+/// Custom deserializer function for Option<Duration>
+fn humantime_serde_opt<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	let opt = Option::<String>::deserialize(deserializer)?;
+	debug!("Deserialized option {opt:?}");
+	match opt {
+		None => Ok(None), // don't care, will be replaced by default_timeout()
+		Some(s) if s.is_empty() => Ok(None), // this one will actually be None
+		Some(s) => humantime::parse_duration(&s)
+			.map(Some)
+			.map_err(serde::de::Error::custom),
+	}
+}
+
+/// Custom serializer function for Option<Duration>.
+/// None is written as an empty string, which humantime_serde_opt() reads back as None.
+fn humantime_serialize_opt<S>(opt: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+	S: serde::Serializer,
+{
+	match opt {
+		None => serializer.serialize_str(""),
+		Some(dur) => serializer.serialize_str(&humantime::format_duration(*dur).to_string()),
+	}
+}
+
+impl LampConfig {
+	/// Get a tuple containing the read and write timeouts of the lamp.
+	pub fn get_read_write_timeouts(&self) -> (Option<Duration>, Option<Duration>) {
+		(self.read_timeout, self.write_timeout)
+	}
+
+	/// Return a ConnectionSettings struct.
+	pub fn get_connection_settings(&self) -> ConnectionSettings {
+		ConnectionSettings {
+			read_timeout: self.read_timeout,
+			write_timeout: self.write_timeout,
+			conn_timeout: self.connection_timeout,
+			conn_tries: self.connection_tries,
+			conn_wait: self.connection_tries_wait,
+		}
+	}
+}
+
+/// The MQTT topic layout used to send commands to a lamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TopicMode {
+	/// A single topic, `<topic>/<lamp id>/set`, carrying JSON or text commands.
+	/// See mqtt::parse_mqtt_command().
+	#[default]
+	Single,
+	/// One topic per property, such as `<topic>/<lamp id>/brightness/set`,
+	/// carrying only the value. See mqtt::parse_property_command().
+	PerProperty,
+}
+
+/// How commands are handled while the connection to a lamp is down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OfflinePolicy {
+	/// The command fails at once.
+	#[default]
+	Reject,
+	/// The command is kept and sent once the lamp is connected again.
+	Buffer,
+}
+
+/// Settings of a lamp group.
+///
+/// A group is either a plain list of members, or a table with the members
+/// and additional options.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum GroupConfig {
+	/// Only the members of the group.
+	Members(Vec<String>),
+	/// Members of the group together with other settings.
+	#[serde(rename_all = "kebab-case")]
+	Detailed {
+		/// Lamp IDs or names of other groups.
+		members: Vec<String>,
+		/// Whether commands should be written to all lamps simultaneously.
+		/// See LampGroup::send_cmd_synced().
+		#[serde(default)]
+		synchronized: bool,
+	},
+}
+
+impl GroupConfig {
+	/// Get the members (lamp IDs or group names) of the group.
+	pub fn members(&self) -> &[String] {
+		match self {
+			Self::Members(members) | Self::Detailed { members, .. } => members,
+		}
+	}
+
+	/// Returns true if commands to this group should be sent in sync.
+	pub fn is_synchronized(&self) -> bool {
+		matches!(
+			self,
+			Self::Detailed {
+				synchronized: true,
+				..
+			}
+		)
+	}
+}
+
+/// Struct containing settings that are used to define the MQTT connection.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename = "mqtt", rename_all = "kebab-case")]
+pub struct MqttConfig {
+	/// IP address and port of the MQTT broker.
+	pub ip: SocketAddr,
+	/// How to connect to the broker.
+	#[serde(default)]
+	pub transport: Transport,
+	/// Version of the MQTT protocol spoken with the broker.
+	#[serde(default)]
+	pub protocol: MqttVersion,
+	/// Longest time without traffic before the client and the broker check the connection.
+	#[serde(with = "humantime_serde", default = "default_keep_alive")]
+	pub keep_alive: Duration,
+	/// How long the broker keeps the session (and the subscriptions) after the program
+	/// disconnects. Only used with MQTT 5; MQTT 3.1.1 sessions never expire.
+	#[serde(with = "humantime_serde", default = "default_session_expiry")]
+	pub session_expiry: Duration,
+	/// How long the broker keeps a reply it could not deliver yet.
+	/// An empty string means forever; otherwise it must be at least 1s.
+	/// Only used with MQTT 5; retained messages never expire.
+	#[serde(
+		deserialize_with = "humantime_serde_opt",
+		serialize_with = "humantime_serialize_opt",
+		default = "default_message_expiry"
+	)]
+	pub message_expiry: Option<Duration>,
+	/// Path of the websocket endpoint of the broker, used with Transport::Ws and Transport::Wss.
+	#[serde(default = "default_ws_path")]
+	pub ws_path: String,
+	/// User name to log in to the broker with.
+	#[serde(default)]
+	pub username: Option<String>,
+	/// Password to log in to the broker with.
+	/// Left out of Config::dump(); consider password_file instead.
+	#[serde(default, skip_serializing)]
+	pub password: Option<String>,
+	/// File containing the password. A trailing newline is ignored.
+	#[serde(default)]
+	pub password_file: Option<PathBuf>,
+	/// Certificates and keys used with Transport::Mqtts and Transport::Wss.
+	#[serde(default)]
+	pub tls: TlsConfig,
+	/// Client identifier used as the name of this program:
+	#[serde(default = "default_id")]
+	pub client_id: String,
+	/// Base topic of the program.
+	/// Commands for a lamp (or a group) are read from `<topic>/<lamp id>/set`,
+	/// or from `<topic>/<lamp id>/<property>/set` for lamps using TopicMode::PerProperty.
+	pub topic: String,
+	/// First subscription ID. Every lamp and group subscribes with its own ID,
+	/// counting up from this one; see Config::routes().
+	pub sub_id: i32,
+	/// Define the QoS value for the subscription.
+	#[serde(default = "default_qos")]
+	pub qos: u32,
+	/// Last will and testament (LWT) payload.
+	/// The broker publishes it on the bridge availability topic when the program
+	/// disconnects unexpectedly; the program publishes it itself when it shuts down.
+	#[serde(default = "default_lwt")]
+	pub lwt_payload: String,
+	/// Prefix of the availability topics; the base topic is used if it is not set.
+	/// The availability of the program is published on `<prefix>/bridge/availability`,
+	/// the availability of each lamp on `<prefix>/<lamp id>/availability`.
+	#[serde(default)]
+	pub availability_prefix: Option<String>,
+	/// How long to wait for further changes before publishing the state of a lamp.
+	/// Lamps often report a change in several notifications; they are published together.
+	#[serde(with = "humantime_serde", default = "default_debounce")]
+	pub state_debounce: Duration,
+	/// How long to wait before the first attempt to reconnect to the broker.
+	/// The wait doubles after every failed attempt, up to reconnect_max_wait.
+	#[serde(with = "humantime_serde", default = "default_reconnect_min_wait")]
+	pub reconnect_min_wait: Duration,
+	/// The longest wait between two attempts to reconnect to the broker.
+	#[serde(with = "humantime_serde", default = "default_reconnect_max_wait")]
+	pub reconnect_max_wait: Duration,
+	/// Whether to publish Home Assistant MQTT discovery messages for the lamps.
+	#[serde(default)]
+	pub ha_discovery: bool,
+	/// Topic prefix Home Assistant reads discovery messages from.
+	#[serde(default = "default_discovery_prefix")]
+	pub ha_discovery_prefix: String,
+}
+
+/// Default value for ws_path.
+fn default_ws_path() -> String {
+	String::from("/mqtt")
+}
+
+/// Default value for keep_alive.
+fn default_keep_alive() -> Duration {
+	Duration::from_secs(60)
+}
+
+/// Default value for session_expiry.
+fn default_session_expiry() -> Duration {
+	Duration::from_secs(3600)
+}
+
+/// Default value for message_expiry.
+fn default_message_expiry() -> Option<Duration> {
+	Some(Duration::from_secs(3600))
+}
+
+/// Version of the MQTT protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MqttVersion {
+	/// MQTT 3.1.1. Replies to commands and subscription IDs are not available.
+	#[serde(rename = "3.1.1")]
+	V311,
+	/// MQTT 5.
+	#[default]
+	#[serde(rename = "5")]
+	V5,
+}
+
+/// The protocol used to connect to the broker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+	/// Plain TCP (`mqtt://`).
+	#[default]
+	Mqtt,
+	/// TCP with TLS (`mqtts://`).
+	Mqtts,
+	/// Websockets (`ws://`).
+	Ws,
+	/// Websockets with TLS (`wss://`).
+	Wss,
+}
+
+impl Transport {
+	/// Get the URI scheme of the transport.
+	pub fn scheme(&self) -> &'static str {
+		match self {
+			Self::Mqtt => "mqtt",
+			Self::Mqtts => "mqtts",
+			Self::Ws => "ws",
+			Self::Wss => "wss",
+		}
+	}
+
+	/// Returns true if the transport uses TLS.
+	pub fn is_tls(&self) -> bool {
+		matches!(self, Self::Mqtts | Self::Wss)
+	}
+
+	/// Returns true if the transport uses websockets.
+	pub fn is_websocket(&self) -> bool {
+		matches!(self, Self::Ws | Self::Wss)
+	}
+}
+
+/// TLS settings of the broker connection, in the `[mqtt.tls]` table.
+///
+/// Without a CA file, the certificate of the broker is checked against the
+/// certificates trusted by the system.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+	/// PEM file with the certificates of the authorities the broker certificate is checked against.
+	#[serde(default)]
+	pub ca_file: Option<PathBuf>,
+	/// PEM file with the certificate of the client, for brokers that authenticate clients.
+	#[serde(default)]
+	pub cert_file: Option<PathBuf>,
+	/// PEM file with the private key of the client certificate.
+	/// If left out, the key is read from cert_file.
+	#[serde(default)]
+	pub key_file: Option<PathBuf>,
+	/// Whether to check that the broker certificate matches the host name of the broker.
+	/// Only turn this off in lab setups.
+	#[serde(default = "default_true")]
+	pub verify_hostname: bool,
+}
+
+impl Default for TlsConfig {
+	fn default() -> Self {
+		Self {
+			ca_file: None,
+			cert_file: None,
+			key_file: None,
+			verify_hostname: true,
+		}
+	}
+}
+
+/// Default value for flags that are on unless turned off.
+fn default_true() -> bool {
+	true
+}
+
+/// Default client ID.
+fn default_id() -> String {
+	String::from("yeerugina")
+}
+
+/// Default QoS value
+fn default_qos() -> u32 {
+	1u32
+}
+
+/// Default value for lwt_payload.
+fn default_lwt() -> String {
+	String::from(OFFLINE)
+}
+
+/// Default value for ha_discovery_prefix.
+fn default_discovery_prefix() -> String {
+	String::from("homeassistant")
+}
+
+/// Default value for reconnect_min_wait.
+fn default_reconnect_min_wait() -> Duration {
+	Duration::from_secs(1)
+}
+
+/// Default value for reconnect_max_wait.
+fn default_reconnect_max_wait() -> Duration {
+	Duration::from_secs(60)
+}
+
+/// Default value for state_debounce.
+fn default_debounce() -> Duration {
+	Duration::from_millis(100)
+}
+
+/// Payload published on an availability topic when the program or a lamp is reachable.
+pub const ONLINE: &str = "online";
+/// Payload published on the availability topic of a lamp when it is unreachable.
+pub const OFFLINE: &str = "offline";
+
+impl MqttConfig {
+	/// Get the URI of the broker, e.g. `mqtts://192.168.1.2:8883`
+	/// or `ws://192.168.1.2:8080/mqtt`.
+	pub fn server_uri(&self) -> String {
+		if self.transport.is_websocket() {
+			format!("{}://{}{}", self.transport.scheme(), self.ip, self.ws_path)
+		} else {
+			format!("{}://{}", self.transport.scheme(), self.ip)
+		}
+	}
+
+	/// Get the password to log in with, reading password_file if needed.
+	pub fn password(&self) -> io::Result<Option<String>> {
+		if let Some(ref path) = self.password_file {
+			let password = fs::read_to_string(path).map_err(|e| {
+				io::Error::new(
+					e.kind(),
+					format!("Could not read password file {}: {e}", path.display()),
+				)
+			})?;
+			let password = password.strip_suffix('\n').unwrap_or(&password);
+			return Ok(Some(
+				password.strip_suffix('\r').unwrap_or(password).to_string(),
+			));
+		}
+		Ok(self.password.clone())
+	}
+
+	/// Get the topic from which commands for the given lamp or group are read.
+	pub fn command_topic(&self, target: &str) -> String {
+		format!("{}/{}/set", self.topic, target)
+	}
+
+	/// Get the topic on which the state of the given lamp is published.
+	pub fn state_topic(&self, lamp: &str) -> String {
+		format!("{}/{}/state", self.topic, lamp)
+	}
+
+	/// Get the prefix of the availability topics.
+	pub fn availability_prefix(&self) -> &str {
+		self.availability_prefix.as_deref().unwrap_or(&self.topic)
+	}
+
+	/// Get the topic on which the program announces whether it is running.
+	/// The LWT is sent to this topic.
+	pub fn bridge_availability_topic(&self) -> String {
+		format!("{}/bridge/availability", self.availability_prefix())
+	}
+
+	/// Get the topic on which the program announces whether the given lamp is reachable.
+	pub fn availability_topic(&self, lamp: &str) -> String {
+		format!("{}/{}/availability", self.availability_prefix(), lamp)
+	}
+
+	/// Get the topic of the Home Assistant discovery message of the given lamp.
+	/// The client ID is used as the node ID.
+	pub fn discovery_topic(&self, lamp: &str) -> String {
+		format!(
+			"{}/light/{}/{}/config",
+			self.ha_discovery_prefix, self.client_id, lamp
+		)
+	}
+
+	/// Get the topic on which Home Assistant announces that it (re)started.
+	pub fn ha_status_topic(&self) -> String {
+		format!("{}/status", self.ha_discovery_prefix)
+	}
+
+	/// Get the topic filter matching the command topics of every lamp and group.
+	pub fn command_filter(&self) -> String {
+		self.command_topic("+")
+	}
+
+	/// Get the topic from which a single property of the given lamp or group is set.
+	pub fn property_topic(&self, target: &str, property: &str) -> String {
+		format!("{}/{}/{}/set", self.topic, target, property)
+	}
+
+	/// Get the topic filter matching the property topics of every lamp and group.
+	pub fn property_filter(&self) -> String {
+		self.property_topic("+", "+")
+	}
+
+	/// Extract the target and the property from a property topic.
+	/// Returns None if the topic is not a property topic.
+	pub fn property_from_topic<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+		let rest = topic.strip_prefix(&self.topic)?.strip_prefix('/')?;
+		let (target, property) = rest.strip_suffix("/set")?.split_once('/')?;
+		if target.is_empty() || property.is_empty() || property.contains('/') {
+			None
+		} else {
+			Some((target, property))
+		}
+	}
+
+	/// Extract the target (a lamp ID or a group name) from a command topic.
+	/// Returns None if the topic is not a command topic.
+	pub fn target_from_topic<'a>(&self, topic: &'a str) -> Option<&'a str> {
+		let rest = topic.strip_prefix(&self.topic)?.strip_prefix('/')?;
+		let id = rest.strip_suffix("/set")?;
+		if id.is_empty() || id.contains('/') {
+			None
+		} else {
+			Some(id)
+		}
+	}
+}
+
+impl Config {
+	/// Deserialize a .toml file containing the settings and produce a Config struct.
+	pub fn read_file(path: String) -> Result<Self, ConfigError> {
+		Self::load(path, &[])
+	}
+
+	/// Read a .toml file, apply overrides to it and produce a Config struct.
+	///
+	/// Overrides replace the values of the file, and later overrides replace earlier ones.
+	/// To get the documented precedence (file < environment < command line),
+	/// pass the overrides from Override::from_env() before the ones from the command line.
+	pub fn load(path: String, overrides: &[Override]) -> Result<Self, ConfigError> {
+		debug!("Reading config from {path}");
+		let cont = std::fs::read_to_string(&path).map_err(|e| {
+			ConfigError::new(vec![Diagnostic::new(
+				"",
+				format!("Could not read {path}: {e}"),
+			)])
+		})?;
+		debug!("File read successfully");
+		Self::parse_with_overrides(&cont, overrides).map_err(|e| e.with_file(path))
+	}
+
+	/// Parse the contents of a .toml file and produce a Config struct.
+	///
+	/// The values of the `[defaults]` table are copied into every `[[lamp]]` table
+	/// that does not set them. The result is then checked with Config::validate().
+	/// Every problem is reported in the ConfigError, together with its position in the file.
+	pub fn parse(cont: &str) -> Result<Self, ConfigError> {
+		Self::parse_with_overrides(cont, &[])
+	}
+
+	/// Like Config::parse(), but the overrides are applied before the defaults are copied.
+	pub fn parse_with_overrides(cont: &str, overrides: &[Override]) -> Result<Self, ConfigError> {
+		let src = Source::new(cont, overrides);
+		let mut root = DeTable::parse(cont).map_err(|e| ConfigError::from_toml(&e, &src))?;
+		for (ov, (range, _)) in overrides.iter().zip(src.overrides.iter()) {
+			debug!("Applying override {} from {}", ov.path, ov.origin);
+			ov.apply(root.get_mut(), range)
+				.map_err(|d| ConfigError::new(vec![d.locate(root.get_ref(), &src)]))?;
+		}
+		apply_lamp_defaults(root.get_mut())
+			.map_err(|d| ConfigError::new(vec![d.locate(root.get_ref(), &src)]))?;
+		// The [defaults] table is left in place (and ignored by serde),
+		// so that diagnostics can still point into it.
+		let conf = Self::deserialize(toml::de::Deserializer::from(root.clone()))
+			.map_err(|e| ConfigError::from_toml(&e, &src))?;
+		let diagnostics: Vec<Diagnostic> = conf
+			.validate()
+			.into_iter()
+			.map(|d| d.locate(root.get_ref(), &src))
+			.collect();
+		if diagnostics.is_empty() {
+			Ok(conf)
+		} else {
+			Err(ConfigError::new(diagnostics))
+		}
+	}
+
+	/// Check the semantic rules that cannot be expressed through the types of the fields.
+	///
+	/// Returns every problem found; an empty Vec means the config is valid.
+	/// The diagnostics returned here do not have a span, since the Config no longer knows
+	/// where its values came from. Config::parse() adds the spans.
+	pub fn validate(&self) -> Vec<Diagnostic> {
+		let mut diags: Vec<Diagnostic> = Vec::new();
+		for (idx, lamp) in self.lamps.iter().enumerate() {
+			let path = |key: &str| format!("lamp[{idx}].{key}");
+			if let Err(e) = check_topic_level(&lamp.id) {
+				diags.push(Diagnostic::new(path("id"), format!("Invalid lamp ID: {e}")));
+			}
+			if lamp.id == "bridge" {
+				diags.push(Diagnostic::new(
+					path("id"),
+					"The lamp ID \"bridge\" is reserved for the availability of the program",
+				));
+			}
+			if lamp.connection_tries == 0 {
+				diags.push(Diagnostic::new(
+					path("connection-tries"),
+					"connection-tries must be greater than zero",
+				));
+			}
+			if lamp.connection_timeout.is_zero() {
+				diags.push(Diagnostic::new(
+					path("connection-timeout"),
+					"connection-timeout cannot be zero",
+				));
+			}
+			if lamp.offline_policy == OfflinePolicy::Buffer && lamp.buffer_size == 0 {
+				diags.push(Diagnostic::new(
+					path("buffer-size"),
+					"buffer-size must be greater than zero to buffer commands",
+				));
+			}
+			if lamp.effect == Effect::Smooth && lamp.default_duration < MIN_DURATION {
+				diags.push(Diagnostic::new(
+					path("default-duration"),
+					format!("Smooth transitions must take at least {MIN_DURATION:?}"),
+				));
+			}
+		}
+		find_duplicates("id", self.lamps.iter().map(|l| l.id.clone()), &mut diags);
+		find_duplicates(
+			"name",
+			self.lamps.iter().map(|l| l.name.clone()),
+			&mut diags,
+		);
+		find_duplicates(
+			"ip",
+			self.lamps.iter().map(|l| l.ip.to_string()),
+			&mut diags,
+		);
+		for name in self.groups.keys() {
+			let path = format!("groups.{name}");
+			if let Err(e) = check_topic_level(name) {
+				diags.push(Diagnostic::new(&path, format!("Invalid group name: {e}")));
+			}
+			if self.lamp(name).is_some() {
+				diags.push(Diagnostic::new(
+					&path,
+					format!("Group \"{name}\" has the same name as a lamp ID"),
+				));
+			}
+			if let Err(e) = self.group_members(name) {
+				diags.push(Diagnostic::new(&path, e));
+			}
+		}
+		if self.mqtt.qos > 2 {
+			diags.push(Diagnostic::new(
+				"mqtt.qos",
+				format!("QoS must be 0, 1 or 2, not {}", self.mqtt.qos),
+			));
+		}
+		// Every lamp and group uses its own subscription ID, counting up from sub-id
+		let last_sub_id =
+			i64::from(self.mqtt.sub_id) + (self.lamps.len() + self.groups.len()) as i64 - 1;
+		if self.mqtt.sub_id < 1 || last_sub_id > i64::from(MAX_SUB_ID) {
+			diags.push(Diagnostic::new(
+				"mqtt.sub-id",
+				format!(
+					"Subscription IDs must be between 1 and {MAX_SUB_ID}; \
+					 every lamp and group uses one, counting up from sub-id"
+				),
+			));
+		}
+		if let Err(e) = check_base_topic(&self.mqtt.topic) {
+			diags.push(Diagnostic::new("mqtt.topic", format!("Invalid topic: {e}")));
+		}
+		if self.mqtt.password.is_some() && self.mqtt.password_file.is_some() {
+			diags.push(Diagnostic::new(
+				"mqtt.password-file",
+				"Set either password or password-file, not both",
+			));
+		}
+		if (self.mqtt.password.is_some() || self.mqtt.password_file.is_some())
+			&& self.mqtt.username.is_none()
+		{
+			diags.push(Diagnostic::new(
+				"mqtt.username",
+				"A password needs a username",
+			));
+		}
+		if self.mqtt.transport.is_websocket() && !self.mqtt.ws_path.starts_with('/') {
+			diags.push(Diagnostic::new(
+				"mqtt.ws-path",
+				"The websocket path must start with '/'",
+			));
+		}
+		if !self.mqtt.transport.is_tls() && self.mqtt.tls != TlsConfig::default() {
+			diags.push(Diagnostic::new(
+				"mqtt.tls",
+				format!(
+					"TLS settings need transport \"mqtts\" or \"wss\", not \"{}\"",
+					self.mqtt.transport.scheme()
+				),
+			));
+		}
+		if self.mqtt.tls.key_file.is_some() && self.mqtt.tls.cert_file.is_none() {
+			diags.push(Diagnostic::new(
+				"mqtt.tls.key-file",
+				"A client key needs a client certificate (cert-file)",
+			));
+		}
+		if self.mqtt.keep_alive.as_secs() > u64::from(u16::MAX) {
+			diags.push(Diagnostic::new(
+				"mqtt.keep-alive",
+				format!("keep-alive cannot be longer than {} seconds", u16::MAX),
+			));
+		}
+		if self.mqtt.protocol == MqttVersion::V5 {
+			// MQTT 5 sends both expiries as whole seconds in four bytes
+			if self.mqtt.session_expiry.as_secs() > u64::from(u32::MAX) {
+				diags.push(Diagnostic::new(
+					"mqtt.session-expiry",
+					format!("session-expiry cannot be longer than {} seconds", u32::MAX),
+				));
+			}
+			match self.mqtt.message_expiry {
+				Some(expiry) if expiry.as_secs() > u64::from(u32::MAX) => {
+					diags.push(Diagnostic::new(
+						"mqtt.message-expiry",
+						format!("message-expiry cannot be longer than {} seconds", u32::MAX),
+					));
+				},
+				Some(expiry) if expiry < Duration::from_secs(1) => {
+					diags.push(Diagnostic::new(
+						"mqtt.message-expiry",
+						"message-expiry must be at least 1s; leave it empty to keep replies forever",
+					));
+				},
+				_ => {},
+			}
+		}
+		if self.mqtt.reconnect_min_wait.is_zero() {
+			diags.push(Diagnostic::new(
+				"mqtt.reconnect-min-wait",
+				"reconnect-min-wait cannot be zero",
+			));
+		}
+		if self.mqtt.reconnect_max_wait < self.mqtt.reconnect_min_wait {
+			diags.push(Diagnostic::new(
+				"mqtt.reconnect-max-wait",
+				"reconnect-max-wait cannot be shorter than reconnect-min-wait",
+			));
+		}
+		if let Some(ref prefix) = self.mqtt.availability_prefix
+			&& let Err(e) = check_base_topic(prefix)
+		{
+			diags.push(Diagnostic::new(
+				"mqtt.availability-prefix",
+				format!("Invalid topic: {e}"),
+			));
+		}
+		if self.mqtt.ha_discovery {
+			if let Err(e) = check_base_topic(&self.mqtt.ha_discovery_prefix) {
+				diags.push(Diagnostic::new(
+					"mqtt.ha-discovery-prefix",
+					format!("Invalid topic: {e}"),
+				));
+			}
+			if let Err(e) = check_topic_level(&self.mqtt.client_id) {
+				diags.push(Diagnostic::new(
+					"mqtt.client-id",
+					format!("Invalid client ID (used as the Home Assistant node ID): {e}"),
+				));
+			}
+		}
+		diags
+	}
+
+	/// Get the subscription of every lamp and group.
+	///
+	/// Lamps listen on the topics of their TopicMode, groups on both kinds of topics.
+	/// The lamps come first, in the order of the config, followed by the groups in
+	/// alphabetical order. The subscription IDs are numbered in the same order,
+	/// starting from MqttConfig::sub_id.
+	pub fn routes(&self) -> Vec<Route> {
+		let lamps = self.lamps.iter().map(|lamp| {
+			let filter = match lamp.topic_mode {
+				TopicMode::Single => self.mqtt.command_topic(&lamp.id),
+				TopicMode::PerProperty => self.mqtt.property_topic(&lamp.id, "+"),
+			};
+			(lamp.id.clone(), vec![filter])
+		});
+		let groups = self.groups.keys().map(|name| {
+			let filters = vec![
+				self.mqtt.command_topic(name),
+				self.mqtt.property_topic(name, "+"),
+			];
+			(name.clone(), filters)
+		});
+		lamps
+			.chain(groups)
+			.zip(self.mqtt.sub_id..)
+			.map(|((target, filters), sub_id)| Route {
+				target,
+				filters,
+				sub_id,
+			})
+			.collect()
+	}
+
+	/// Write the config as TOML, with the defaults and overrides already applied.
+	pub fn dump(&self) -> Result<String, String> {
+		toml::to_string(self).map_err(|e| e.to_string())
+	}
+
+	/// Compare this config to a newer one.
+	///
+	/// Lamps are matched by their IDs, so renaming a lamp's ID counts as removing it
+	/// and adding a new one.
+	pub fn diff(&self, new: &Config) -> ConfigDiff {
+		let mut diff = ConfigDiff::default();
+		for lamp in self.lamps.iter() {
+			match new.lamp(&lamp.id) {
+				None => diff.removed_lamps.push(lamp.id.clone()),
+				Some(new_lamp) if new_lamp != lamp => diff.changed_lamps.push(lamp.id.clone()),
+				Some(_) => {},
+			}
+		}
+		for lamp in new.lamps.iter() {
+			if self.lamp(&lamp.id).is_none() {
+				diff.added_lamps.push(lamp.id.clone());
+			}
+		}
+		diff.groups_changed = self.groups != new.groups;
+		diff.mqtt_changed = self.mqtt != new.mqtt;
+		diff
+	}
+
+	/// Get the settings of the lamp with the given ID.
+	pub fn lamp(&self, id: &str) -> Option<&LampConfig> {
+		self.lamps.iter().find(|l| l.id == id)
+	}
+
+	/// Get the IDs of all lamps belonging to a group, resolving nested groups.
+	///
+	/// Each lamp is listed once, even if it can be reached through several nested groups.
+	/// Returns an error if the group does not exist, refers to an unknown lamp or group,
+	/// or contains itself.
+	pub fn group_members(&self, group: &str) -> Result<Vec<String>, String> {
+		let mut members: Vec<String> = Vec::new();
+		let mut path: Vec<&str> = Vec::new();
+		self.collect_members(group, &mut path, &mut members)?;
+		Ok(members)
+	}
+
+	/// Recursive helper for group_members().
+	/// `path` holds the groups currently being resolved and is used to detect cycles.
+	fn collect_members<'a>(
+		&'a self, group: &'a str, path: &mut Vec<&'a str>, members: &mut Vec<String>,
+	) -> Result<(), String> {
+		let Some(group_members) = self.groups.get(group) else {
+			return Err(format!("Unknown group \"{group}\""));
+		};
+		if path.contains(&group) {
+			path.push(group);
+			return Err(format!("Group cycle: {}", path.join(" -> ")));
+		}
+		path.push(group);
+		for member in group_members.members().iter() {
+			if self.lamp(member).is_some() {
+				if !members.contains(member) {
+					members.push(member.clone());
+				}
+			} else if self.groups.contains_key(member) {
+				self.collect_members(member, path, members)?;
+			} else {
+				return Err(format!("Group \"{group}\" has unknown member \"{member}\""));
+			}
+		}
+		path.pop();
+		Ok(())
+	}
+}
+
+/// The subscription through which a lamp or a group receives its commands.
+/// See Config::routes().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+	/// The lamp ID or group name.
+	pub target: String,
+	/// Topic filters of the subscription.
+	pub filters: Vec<String>,
+	/// Subscription ID of the subscription (MQTT 5 only).
+	pub sub_id: i32,
+}
+
+/// Differences between two configs, as returned by Config::diff().
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+	/// IDs of lamps only present in the new config.
+	pub added_lamps: Vec<String>,
+	/// IDs of lamps only present in the old config.
+	pub removed_lamps: Vec<String>,
+	/// IDs of lamps present in both configs, but with different settings.
+	pub changed_lamps: Vec<String>,
+	/// Whether any group was added, removed or changed.
+	pub groups_changed: bool,
+	/// Whether the MQTT settings changed.
+	pub mqtt_changed: bool,
+}
+
+impl ConfigDiff {
+	/// Returns true if the configs are equal.
+	pub fn is_empty(&self) -> bool {
+		self == &Self::default()
+	}
+}
+
+/// The largest subscription identifier allowed by MQTT v5.
+const MAX_SUB_ID: i32 = 268_435_455;
+
+/// Check that a string can be used as a single level of an MQTT topic.
+fn check_topic_level(level: &str) -> Result<(), String> {
+	if level.is_empty() {
+		Err(String::from("cannot be empty"))
+	} else if let Some(c) = level.chars().find(|c| matches!(c, '/' | '+' | '#' | '\0')) {
+		Err(format!("cannot contain {c:?}"))
+	} else {
+		Ok(())
+	}
+}
+
+/// Check that a string can be used as the base of the topics of the program.
+fn check_base_topic(topic: &str) -> Result<(), String> {
+	if topic.starts_with('/') || topic.ends_with('/') {
+		return Err(String::from("cannot start or end with '/'"));
+	}
+	topic.split('/').try_for_each(check_topic_level)
+}
+
+/// Push a diagnostic to `diags` for every lamp that repeats a value of an earlier lamp.
+fn find_duplicates(key: &str, vals: impl Iterator<Item = String>, diags: &mut Vec<Diagnostic>) {
+	let mut seen: HashMap<String, usize> = HashMap::new();
+	for (idx, val) in vals.enumerate() {
+		if let Some(first) = seen.insert(val.clone(), idx) {
+			diags.push(Diagnostic::new(
+				format!("lamp[{idx}].{key}"),
+				format!("Lamp has the same {key} \"{val}\" as lamp #{}", first + 1),
+			));
+		}
+	}
+}
+
+/// Copy the values of the `[defaults]` table into each `[[lamp]]` table not overriding them.
+fn apply_lamp_defaults(root: &mut DeTable<'_>) -> Result<(), Box<Diagnostic>> {
+	let defaults = match root.get("defaults").map(|v| v.get_ref()) {
+		None => DeTable::new(),
+		Some(DeValue::Table(t)) => t.clone(),
+		Some(_) => {
+			return Err(Box::new(Diagnostic::new(
+				"defaults",
+				"`defaults` must be a table",
+			)));
+		},
+	};
+	for key in ["id", "name", "ip"] {
+		if defaults.contains_key(key) {
+			return Err(Box::new(Diagnostic::new(
+				format!("defaults.{key}"),
+				format!("`{key}` cannot be set in `defaults`"),
+			)));
+		}
+	}
+	let Some(lamps) = root.get_mut("lamp") else {
+		return Err(Box::new(Diagnostic::new(
+			"",
+			"No lamps configured; add at least one [[lamp]] table",
+		)));
+	};
+	let DeValue::Array(lamps) = lamps.get_mut() else {
+		return Err(Box::new(Diagnostic::new(
+			"lamp",
+			"`lamp` must be an array of tables ([[lamp]])",
+		)));
+	};
+	for (idx, lamp) in lamps.iter_mut().enumerate() {
+		let DeValue::Table(lamp) = lamp.get_mut() else {
+			return Err(Box::new(Diagnostic::new(
+				format!("lamp[{idx}]"),
+				"`lamp` must be an array of tables ([[lamp]])",
+			)));
+		};
+		for (key, val) in defaults.iter() {
+			if !lamp.contains_key(key.get_ref().as_ref()) {
+				trace!("Using default value for {key}");
+				lamp.insert(key.clone(), val.clone());
+			}
+		}
+	}
+	Ok(())
+}
+
+/// A problem found while loading or validating the config.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+	/// Path to the offending value, such as `lamp[1].connection-tries`.
+	/// Empty if the problem does not concern a single value.
+	pub path: String,
+	/// Description of the problem.
+	pub message: String,
+	/// Position of the offending value in the config file, if known.
+	pub span: Option<SourceSpan>,
+	/// Description of where the value came from,
+	/// if it was set by an override instead of the config file.
+	pub origin: Option<String>,
+}
+
+/// A position in the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceSpan {
+	/// Line number, starting from 1.
+	pub line: usize,
+	/// Column number (in characters), starting from 1.
+	pub column: usize,
+	/// Byte range of the value in the file.
+	pub range: Range<usize>,
+	/// The contents of the line, used when printing the diagnostic.
+	pub line_text: String,
+}
+
+impl SourceSpan {
+	/// Find the line and column of a byte range in `src`.
+	pub fn new(src: &str, range: Range<usize>) -> Self {
+		let start = range.start.min(src.len());
+		let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+		let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+		Self {
+			line: src[..start].matches('\n').count() + 1,
+			column: src[line_start..start].chars().count() + 1,
+			range,
+			line_text: src[line_start..line_end].trim_end_matches('\r').to_string(),
+		}
+	}
+}
+
+impl Diagnostic {
+	/// Create a diagnostic without a span.
+	pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+		Self {
+			path: path.into(),
+			message: message.into(),
+			span: None,
+			origin: None,
+		}
+	}
+
+	/// Look up the path of the diagnostic in a parsed document and add the span of the value.
+	/// If the path cannot be found, the diagnostic is returned unchanged.
+	fn locate(mut self, root: &DeTable<'_>, src: &Source<'_>) -> Self {
+		if self.span.is_none()
+			&& self.origin.is_none()
+			&& let Some(range) = find_span(root, &self.path)
+		{
+			(self.span, self.origin) = src.position(range);
+		}
+		self
+	}
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.path.is_empty() {
+			write!(f, "{}", self.message)?;
+		} else {
+			write!(f, "{}: {}", self.path, self.message)?;
+		}
+		if let Some(span) = &self.span {
+			let num = span.line.to_string();
+			let pad = " ".repeat(num.len());
+			write!(
+				f,
+				"\n{pad} --> line {}, column {}\n{pad} |\n{num} | {}\n{pad} | {}^",
+				span.line,
+				span.column,
+				span.line_text,
+				" ".repeat(span.column - 1)
+			)?;
+		}
+		if let Some(origin) = &self.origin {
+			write!(f, "\n --> set by {origin}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Find the byte range of the value at `path` (for example `lamp[1].ip`) in a parsed document.
+fn find_span(root: &DeTable<'_>, path: &str) -> Option<Range<usize>> {
+	if path.is_empty() {
+		return None;
+	}
+	let mut table = root;
+	let mut found: Option<&Spanned<DeValue<'_>>> = None;
+	for part in path.split('.') {
+		if let Some(val) = found {
+			let DeValue::Table(t) = val.get_ref() else {
+				return None;
+			};
+			table = t;
+		}
+		// Split "lamp[1]" into the key "lamp" and the index 1
+		let (key, idx) = match part.split_once('[') {
+			Some((key, rest)) => (key, Some(rest.strip_suffix(']')?.parse::<usize>().ok()?)),
+			None => (part, None),
+		};
+		let mut val = table.get(key)?;
+		if let Some(idx) = idx {
+			let DeValue::Array(arr) = val.get_ref() else {
+				return None;
+			};
+			val = arr.get(idx)?;
+		}
+		found = Some(val);
+	}
+	found.map(|v| v.span())
+}
+
+/// Error returned when the config cannot be loaded.
+///
+/// Contains every problem that was found, so that they can all be fixed at once.
+#[derive(Clone, Debug)]
+pub struct ConfigError {
+	/// Path of the config file, if the config was read from a file.
+	pub file: Option<String>,
+	/// The problems found in the config.
+	pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ConfigError {
+	/// Create an error from a list of diagnostics.
+	pub fn new(diagnostics: Vec<Diagnostic>) -> Self {
+		Self {
+			file: None,
+			diagnostics,
+		}
+	}
+
+	/// Convert an error from the toml crate, adding the span if the error has one.
+	fn from_toml(e: &toml::de::Error, src: &Source<'_>) -> Self {
+		let mut diag = Diagnostic::new("", e.message().trim_end());
+		if let Some(range) = e.span() {
+			(diag.span, diag.origin) = src.position(range);
+		}
+		Self::new(vec![diag])
+	}
+
+	/// Set the path of the file the config was read from.
+	pub fn with_file(mut self, file: String) -> Self {
+		self.file = Some(file);
+		self
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let file = self.file.as_deref().unwrap_or("config");
+		write!(f, "{file}: {} problem(s) found", self.diagnostics.len())?;
+		for diag in self.diagnostics.iter() {
+			write!(f, "\n\nerror: {diag}")?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The text of a config file together with the overrides applied to it.
+/// Used to turn the spans of values into positions that are meaningful to the user.
+struct Source<'a> {
+	text: &'a str,
+	/// The span given to the values of each override, with the origin of the override.
+	/// The spans lie after the end of the file, so they cannot be mistaken for a position in it.
+	overrides: Vec<(Range<usize>, &'a str)>,
+}
+
+impl<'a> Source<'a> {
+	/// Give every override a span of its own, as long as its value.
+	fn new(text: &'a str, overrides: &'a [Override]) -> Self {
+		// An error at the end of the file has an empty span at text.len()
+		let mut start = text.len() + 1;
+		let overrides = overrides
+			.iter()
+			.map(|ov| {
+				let range = start..start + ov.value.len().max(1);
+				start = range.end;
+				(range, ov.origin.as_str())
+			})
+			.collect();
+		Self { text, overrides }
+	}
+
+	/// Get the position of a span, either in the file or as the origin of an override.
+	fn position(&self, range: Range<usize>) -> (Option<SourceSpan>, Option<String>) {
+		match self
+			.overrides
+			.iter()
+			.find(|(ov_range, _)| ov_range.contains(&range.start))
+		{
+			Some((_, origin)) => (None, Some(origin.to_string())),
+			None => (Some(SourceSpan::new(self.text, range)), None),
+		}
+	}
+}
+
+/// A setting given outside the config file, which replaces the value in the file.
+///
+/// The path uses the same names as the config file, with the lamps addressed by their IDs:
+/// `mqtt.ip`, `defaults.default-duration` or `lamp.kitchen.effect`.
+/// Only settings under `defaults`, `lamp` and `mqtt` can be overridden.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+	/// Dotted path of the setting.
+	pub path: String,
+	/// The new value. Used as a string, unless the setting is a number, a boolean,
+	/// an array or a table (see NON_STRING_KEYS); then it is parsed as a TOML value.
+	pub value: String,
+	/// Where the override came from, shown in diagnostics.
+	pub origin: String,
+}
+
+/// Prefix of the environment variables that override settings.
+pub const ENV_PREFIX: &str = "YEERUGINA_";
+
+/// Environment variable that holds the path of the config file.
+/// It is not an override itself, so it is skipped by Override::from_env().
+pub const ENV_CONFIG_PATH: &str = "YEERUGINA_CONFIG";
+
+/// The tables whose settings can be overridden.
+const OVERRIDE_SECTIONS: [&str; 3] = ["defaults", "lamp", "mqtt"];
+
+/// Settings (in `[defaults]`, `[[lamp]]` or `[mqtt]`) whose values are not strings.
+/// The values of their overrides are parsed as TOML; every other value is taken as it is,
+/// so that `mqtt.client-id=123` stays a string.
+const NON_STRING_KEYS: [&str; 7] = [
+	"connection-tries",
+	"buffer-size",
+	"capabilities",
+	"sub-id",
+	"qos",
+	"ha-discovery",
+	"tls",
+];
+
+impl Override {
+	/// Collect the overrides given as environment variables.
+	///
+	/// The name of the variable is the path of the setting in upper case,
+	/// with `__` between the parts and `_` instead of `-`.
+	/// For example, `YEERUGINA_MQTT__IP` sets `mqtt.ip`,
+	/// and `YEERUGINA_LAMP__KITCHEN__DEFAULT_DURATION` sets `lamp.kitchen.default-duration`.
+	/// Variables that do not start with the name of an overridable table, such as `YEERUGINA_LOG`,
+	/// are skipped with a warning.
+	pub fn from_env() -> Vec<Self> {
+		Self::from_vars(std::env::vars())
+	}
+
+	/// Collect overrides from (name, value) pairs. See Override::from_env().
+	pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Vec<Self> {
+		let mut overrides: Vec<Self> = vars
+			.filter(|(name, _)| name != ENV_CONFIG_PATH)
+			.filter_map(|(name, value)| {
+				let rest = name.strip_prefix(ENV_PREFIX)?;
+				let path: Vec<String> = rest
+					.split("__")
+					.map(|part| part.to_lowercase().replace('_', "-"))
+					.collect();
+				if !OVERRIDE_SECTIONS.contains(&path[0].as_str()) {
+					warn!("Ignoring {name}: it does not name a setting");
+					return None;
+				}
+				Some(Self {
+					path: path.join("."),
+					value,
+					origin: format!("environment variable {name}"),
+				})
+			})
+			.collect();
+		// Make the order independent of the environment
+		overrides.sort_by(|a, b| a.path.cmp(&b.path));
+		overrides
+	}
+
+	/// Parse an override given on the command line as `path=value`.
+	pub fn from_arg(arg: &str) -> Result<Self, String> {
+		let Some((path, value)) = arg.split_once('=') else {
+			return Err(format!("Expected <setting>=<value>, got \"{arg}\""));
+		};
+		Ok(Self {
+			path: path.trim().to_string(),
+			value: value.to_string(),
+			origin: format!("command line argument \"{arg}\""),
+		})
+	}
+
+	/// Insert the value into a parsed document.
+	/// `span` is given to the value, so that diagnostics can tell where the value came from;
+	/// see Source::new().
+	fn apply<'i>(
+		&'i self, root: &mut DeTable<'i>, span: &Range<usize>,
+	) -> Result<(), Box<Diagnostic>> {
+		let err = |msg: String| {
+			let mut diag = Diagnostic::new("", msg);
+			diag.origin = Some(self.origin.clone());
+			Box::new(diag)
+		};
+		let parts: Vec<&str> = self.path.split('.').collect();
+		let (table, key) = match parts.as_slice() {
+			[section @ ("defaults" | "mqtt"), key] => {
+				let table = root
+					.entry(Spanned::new(0..0, (*section).into()))
+					.or_insert_with(|| Spanned::new(0..0, DeValue::Table(DeTable::new())));
+				let DeValue::Table(table) = table.get_mut() else {
+					return Err(err(format!("`{section}` is not a table")));
+				};
+				(table, *key)
+			},
+			["lamp", id, key] => {
+				let table = find_lamp_table(root, id)
+					.ok_or_else(|| err(format!("No lamp with ID \"{id}\"")))?;
+				(table, *key)
+			},
+			_ => {
+				return Err(err(format!(
+					"Cannot override `{}`; expected defaults.<key>, lamp.<id>.<key> or mqtt.<key>",
+					self.path
+				)));
+			},
+		};
+		let parsed = NON_STRING_KEYS
+			.contains(&key)
+			.then(|| DeValue::parse(&self.value).ok())
+			.flatten();
+		// A value that is not valid TOML is reported by the deserializer as a string
+		let value = match parsed {
+			Some(val) => respan(val.into_inner(), span),
+			None => DeValue::String(self.value.as_str().into()),
+		};
+		table.insert(
+			Spanned::new(span.clone(), key.into()),
+			Spanned::new(span.clone(), value),
+		);
+		Ok(())
+	}
+}
+
+/// Give a span to every value inside an array or a table.
+/// The spans set by DeValue::parse() are positions in the override, not in the file.
+fn respan<'i>(value: DeValue<'i>, span: &Range<usize>) -> DeValue<'i> {
+	match value {
+		DeValue::Array(arr) => DeValue::Array(
+			arr.into_iter()
+				.map(|val| Spanned::new(span.clone(), respan(val.into_inner(), span)))
+				.collect(),
+		),
+		DeValue::Table(table) => DeValue::Table(
+			table
+				.into_iter()
+				.map(|(key, val)| {
+					(
+						Spanned::new(span.clone(), key.into_inner()),
+						Spanned::new(span.clone(), respan(val.into_inner(), span)),
+					)
+				})
+				.collect(),
+		),
+		other => other,
+	}
+}
+
+/// Find the table of the lamp with the given ID.
+/// The IDs are compared ignoring case and treating `_` and `-` as equal,
+/// since environment variable names cannot express every ID exactly.
+fn find_lamp_table<'a, 'i>(root: &'a mut DeTable<'i>, id: &str) -> Option<&'a mut DeTable<'i>> {
+	let normalize = |s: &str| s.to_lowercase().replace('_', "-");
+	let DeValue::Array(lamps) = root.get_mut("lamp")?.get_mut() else {
+		return None;
+	};
+	lamps.iter_mut().find_map(|lamp| match lamp.get_mut() {
+		DeValue::Table(t) => {
+			let lamp_id = t.get("id")?.get_ref().as_str()?;
+			(normalize(lamp_id) == normalize(id)).then_some(t)
+		},
+		_ => None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CONFIG: &str = r#"
+[[lamp]]
+id = "kitchen"
+name = "Kitchen ceiling"
+ip = "192.168.1.3:55443"
+default-duration = "500ms"
+connection-tries = 5
+
+[mqtt]
+ip = "192.168.1.2:1883"
+topic = "yeelight"
+sub-id = 1
+"#;
+
+	const DEFAULTS: &str = r#"
+[defaults]
+default-duration = "1s"
+connection-tries = 3
+effect = "sudden"
+
+[[lamp]]
+id = "kitchen"
+name = "Kitchen ceiling"
+ip = "192.168.1.3:55443"
+
+[[lamp]]
+id = "desk"
+name = "Desk lamp"
+ip = "192.168.1.4:55443"
+connection-tries = 7
+effect = "smooth"
+
+[mqtt]
+ip = "192.168.1.2:1883"
+topic = "yeelight"
+sub-id = 1
+"#;
+
+	fn set(arg: &str) -> Override {
+		Override::from_arg(arg).unwrap()
+	}
+
+	/// Parse a config that must be invalid and return its diagnostics.
+	fn problems(cont: &str) -> Vec<Diagnostic> {
+		match Config::parse(cont) {
+			Ok(conf) => panic!("config should be invalid: {conf:?}"),
+			Err(e) => e.diagnostics,
+		}
+	}
+
+	#[test]
+	fn defaults_fill_unset_lamp_settings() {
+		let conf = Config::parse(DEFAULTS).unwrap();
+		let kitchen = conf.lamp("kitchen").unwrap();
+		assert_eq!(kitchen.default_duration, Duration::from_secs(1));
+		assert_eq!(kitchen.connection_tries, 3);
+		assert_eq!(kitchen.effect, Effect::Sudden);
+		// Settings missing from both tables keep their own defaults
+		assert_eq!(kitchen.offline_policy, OfflinePolicy::Reject);
+	}
+
+	#[test]
+	fn lamp_settings_win_over_defaults() {
+		let conf = Config::parse(DEFAULTS).unwrap();
+		let desk = conf.lamp("desk").unwrap();
+		assert_eq!(desk.default_duration, Duration::from_secs(1));
+		assert_eq!(desk.connection_tries, 7);
+		assert_eq!(desk.effect, Effect::Smooth);
+	}
+
+	#[test]
+	fn overridden_defaults_skip_lamps_with_their_own_value() {
+		let conf =
+			Config::parse_with_overrides(DEFAULTS, &[set("defaults.connection-tries=4")]).unwrap();
+		assert_eq!(conf.lamp("kitchen").unwrap().connection_tries, 4);
+		assert_eq!(conf.lamp("desk").unwrap().connection_tries, 7);
+	}
+
+	#[test]
+	fn defaults_cannot_set_lamp_identity() {
+		for (key, line) in [
+			("id", r#"id = "lamp""#),
+			("name", r#"name = "Lamp""#),
+			("ip", r#"ip = "192.168.1.9:55443""#),
+		] {
+			let cont = DEFAULTS.replace("[defaults]\n", &format!("[defaults]\n{line}\n"));
+			let diags = problems(&cont);
+			let [diag] = diags.as_slice() else {
+				panic!("{diags:?}");
+			};
+			assert_eq!(diag.path, format!("defaults.{key}"));
+			let span = diag.span.as_ref().unwrap();
+			assert_eq!((span.line, span.line_text.as_str()), (3, line));
+		}
+	}
+
+	/// Get the path, line and column of each diagnostic, which must all have a span.
+	fn positions(diags: &[Diagnostic]) -> Vec<(&str, usize, usize)> {
+		diags
+			.iter()
+			.map(|d| {
+				let span = d.span.as_ref().unwrap_or_else(|| panic!("no span: {d}"));
+				(d.path.as_str(), span.line, span.column)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn duplicate_lamps_are_reported_on_the_later_lamp() {
+		let cont = DEFAULTS
+			.replace(r#"id = "desk""#, r#"id = "kitchen""#)
+			.replace(r#"name = "Desk lamp""#, r#"name = "Kitchen ceiling""#)
+			.replace("192.168.1.4", "192.168.1.3");
+		let diags = problems(&cont);
+		assert_eq!(
+			positions(&diags),
+			[
+				("lamp[1].id", 13, 6),
+				("lamp[1].name", 14, 8),
+				("lamp[1].ip", 15, 6)
+			]
+		);
+		assert_eq!(
+			diags[0].message,
+			"Lamp has the same id \"kitchen\" as lamp #1"
+		);
+	}
+
+	#[test]
+	fn nested_groups_list_each_lamp_once() {
+		let cont = format!(
+			r#"{DEFAULTS}
+[groups]
+all = ["downstairs", "desk"]
+downstairs = ["kitchen", "desk"]
+"#
+		);
+		let conf = Config::parse(&cont).unwrap();
+		assert_eq!(conf.group_members("all").unwrap(), ["kitchen", "desk"]);
+		assert_eq!(
+			conf.group_members("upstairs").unwrap_err(),
+			"Unknown group \"upstairs\""
+		);
+	}
+
+	#[test]
+	fn unknown_group_members_are_reported() {
+		let cont = format!(
+			r#"{DEFAULTS}
+[groups]
+downstairs = ["kitchen", "garage"]
+"#
+		);
+		let diags = problems(&cont);
+		assert_eq!(positions(&diags), [("groups.downstairs", 25, 14)]);
+		assert_eq!(
+			diags[0].message,
+			"Group \"downstairs\" has unknown member \"garage\""
+		);
+	}
+
+	#[test]
+	fn group_cycles_are_reported() {
+		let cont = format!(
+			r#"{DEFAULTS}
+[groups]
+a = ["kitchen", "b"]
+b = {{ members = ["a"] }}
+"#
+		);
+		let diags = problems(&cont);
+		assert_eq!(
+			positions(&diags),
+			[("groups.a", 25, 5), ("groups.b", 26, 5)]
+		);
+		assert_eq!(diags[0].message, "Group cycle: a -> b -> a");
+		assert_eq!(diags[1].message, "Group cycle: b -> a -> b");
+	}
+
+	/// Add settings to the [mqtt] table of CONFIG and return the paths of the diagnostics.
+	fn mqtt_problems(settings: &str) -> Vec<String> {
+		problems(&format!("{CONFIG}{settings}\n"))
+			.into_iter()
+			.map(|d| d.path)
+			.collect()
+	}
+
+	#[test]
+	fn tls_settings_need_a_tls_transport() {
+		let tls = "[mqtt.tls]\nca-file = \"ca.pem\"";
+		assert_eq!(mqtt_problems(tls), ["mqtt.tls"]);
+		assert_eq!(
+			mqtt_problems(&format!("transport = \"ws\"\n{tls}")),
+			["mqtt.tls"]
+		);
+		for transport in ["mqtts", "wss"] {
+			let cont = format!("{CONFIG}transport = \"{transport}\"\n{tls}\n");
+			assert!(Config::parse(&cont).is_ok(), "{transport}");
+		}
+	}
+
+	#[test]
+	fn client_key_needs_a_certificate() {
+		let diags = problems(&format!(
+			"{CONFIG}transport = \"mqtts\"\n[mqtt.tls]\nkey-file = \"client.key\"\n"
+		));
+		assert_eq!(positions(&diags), [("mqtt.tls.key-file", 15, 12)]);
+	}
+
+	#[test]
+	fn password_needs_a_username_and_a_single_source() {
+		assert_eq!(mqtt_problems("password = \"secret\""), ["mqtt.username"]);
+		assert_eq!(
+			mqtt_problems("username = \"me\"\npassword = \"secret\"\npassword-file = \"pw\""),
+			["mqtt.password-file"]
+		);
+	}
+
+	#[test]
+	fn websocket_path_must_be_absolute() {
+		assert_eq!(
+			mqtt_problems("transport = \"wss\"\nws-path = \"mqtt\""),
+			["mqtt.ws-path"]
+		);
+		// The path is not used without websockets
+		assert!(Config::parse(&format!("{CONFIG}ws-path = \"mqtt\"\n")).is_ok());
+	}
+
+	#[test]
+	fn protocol_must_be_known() {
+		let diags = problems(&format!("{CONFIG}protocol = \"4\"\n"));
+		let span = diags[0].span.as_ref().unwrap();
+		assert_eq!(span.line_text, "protocol = \"4\"");
+	}
+
+	#[test]
+	fn expiries_must_fit_mqtt_5() {
+		assert_eq!(
+			mqtt_problems("session-expiry = \"200years\""),
+			["mqtt.session-expiry"]
+		);
+		assert_eq!(
+			mqtt_problems("message-expiry = \"200years\""),
+			["mqtt.message-expiry"]
+		);
+		assert_eq!(
+			mqtt_problems("message-expiry = \"500ms\""),
+			["mqtt.message-expiry"]
+		);
+		// MQTT 3.1.1 has no expiries, so they are not checked
+		let cont = format!(
+			"{CONFIG}protocol = \"3.1.1\"\nsession-expiry = \"200years\"\nmessage-expiry = \"500ms\"\n"
+		);
+		assert!(Config::parse(&cont).is_ok());
+		// An empty message-expiry keeps replies forever
+		let conf = Config::parse(&format!("{CONFIG}message-expiry = \"\"\n")).unwrap();
+		assert_eq!(conf.mqtt.message_expiry, None);
+	}
+
+	#[test]
+	fn availability_topics_must_be_valid() {
+		assert_eq!(
+			mqtt_problems("availability-prefix = \"home/+/lights\""),
+			["mqtt.availability-prefix"]
+		);
+		assert_eq!(
+			mqtt_problems("availability-prefix = \"/home\""),
+			["mqtt.availability-prefix"]
+		);
+		let diags = problems(&CONFIG.replace("id = \"kitchen\"", "id = \"bridge\""));
+		assert_eq!(positions(&diags), [("lamp[0].id", 3, 6)]);
+	}
+
+	#[test]
+	fn reconnect_waits_must_be_ordered() {
+		assert_eq!(
+			mqtt_problems("reconnect-min-wait = \"0s\""),
+			["mqtt.reconnect-min-wait"]
+		);
+		assert_eq!(
+			mqtt_problems("reconnect-min-wait = \"10s\"\nreconnect-max-wait = \"5s\""),
+			["mqtt.reconnect-max-wait"]
+		);
+		assert_eq!(mqtt_problems("keep-alive = \"1day\""), ["mqtt.keep-alive"]);
+	}
+
+	#[test]
+	fn diff_of_equal_configs_is_empty() {
+		let old = Config::parse(DEFAULTS).unwrap();
+		let new = Config::parse(DEFAULTS).unwrap();
+		assert!(old.diff(&new).is_empty());
+	}
+
+	#[test]
+	fn diff_lists_added_removed_and_changed_lamps() {
+		let old = Config::parse(DEFAULTS).unwrap();
+		let cont = DEFAULTS
+			.replace("connection-tries = 7", "connection-tries = 8")
+			.replace(r#"id = "kitchen""#, r#"id = "hall""#);
+		let new = Config::parse(&format!(
+			r#"{cont}
+[[lamp]]
+id = "porch"
+name = "Porch light"
+ip = "192.168.1.5:55443"
+"#
+		))
+		.unwrap();
+		let diff = old.diff(&new);
+		assert_eq!(diff.added_lamps, ["hall", "porch"]);
+		assert_eq!(diff.removed_lamps, ["kitchen"]);
+		assert_eq!(diff.changed_lamps, ["desk"]);
+		assert!(!diff.groups_changed);
+		assert!(!diff.mqtt_changed);
+	}
+
+	#[test]
+	fn diff_notices_changed_defaults() {
+		let old = Config::parse(DEFAULTS).unwrap();
+		let new = Config::parse(&DEFAULTS.replace(r#""1s""#, r#""2s""#)).unwrap();
+		// Both lamps take default-duration from [defaults]
+		assert_eq!(old.diff(&new).changed_lamps, ["kitchen", "desk"]);
+	}
+
+	/// Add a [groups] table to DEFAULTS.
+	fn with_groups(groups: &str) -> String {
+		format!("{DEFAULTS}[groups]\n{groups}\n")
+	}
+
+	#[test]
+	fn diff_notices_groups_and_broker_settings() {
+		let old = Config::parse(&with_groups(r#"all = ["kitchen"]"#)).unwrap();
+		for groups in [
+			r#"all = ["kitchen", "desk"]"#,
+			r#"all = { members = ["kitchen"], synchronized = true }"#,
+			"all = [\"kitchen\"]\nrest = [\"desk\"]",
+			"",
+		] {
+			let new = Config::parse(&with_groups(groups)).unwrap();
+			let expected = ConfigDiff {
+				groups_changed: true,
+				..Default::default()
+			};
+			assert_eq!(old.diff(&new), expected, "{groups}");
+		}
+		let new = Config::parse(
+			&with_groups(r#"all = ["kitchen"]"#).replace("sub-id = 1", "sub-id = 1\nqos = 2"),
+		)
+		.unwrap();
+		let expected = ConfigDiff {
+			mqtt_changed: true,
+			..Default::default()
+		};
+		assert_eq!(old.diff(&new), expected);
+	}
+
+	#[test]
+	fn env_skips_unknown_variables() {
+		let vars = [
+			("YEERUGINA_LOG", "debug"),
+			("YEERUGINA_CONFIG", "config.toml"),
+			("YEERUGINA_MQTT__CLIENT_ID", "bridge"),
+			("PATH", "/usr/bin"),
+		];
+		let overrides = Override::from_vars(
+			vars.into_iter()
+				.map(|(name, value)| (name.to_string(), value.to_string())),
+		);
+		assert_eq!(overrides.len(), 1);
+		assert_eq!(overrides[0].path, "mqtt.client-id");
+		assert_eq!(overrides[0].value, "bridge");
+	}
+
+	#[test]
+	fn string_settings_stay_strings() {
+		let overrides = [
+			set("mqtt.client-id=123"),
+			set("mqtt.username=true"),
+			set("mqtt.password=0042"),
+		];
+		let conf = Config::parse_with_overrides(CONFIG, &overrides).unwrap();
+		assert_eq!(conf.mqtt.client_id, "123");
+		assert_eq!(conf.mqtt.username.as_deref(), Some("true"));
+		assert_eq!(conf.mqtt.password.as_deref(), Some("0042"));
+	}
+
+	#[test]
+	fn other_settings_are_parsed() {
+		let overrides = [
+			set("mqtt.qos=2"),
+			set("mqtt.ha-discovery=true"),
+			set("lamp.kitchen.capabilities=[\"set_power\", \"toggle\"]"),
+			set("mqtt.transport=mqtts"),
+			set("mqtt.tls={verify-hostname = false}"),
+		];
+		let conf = Config::parse_with_overrides(CONFIG, &overrides).unwrap();
+		assert_eq!(conf.mqtt.qos, 2);
+		assert!(conf.mqtt.ha_discovery);
+		assert_eq!(conf.lamps[0].capabilities, ["set_power", "toggle"]);
+		assert!(!conf.mqtt.tls.verify_hostname);
+	}
+
+	#[test]
+	fn non_string_keys_match_the_config() {
+		let conf = Config::parse(CONFIG).unwrap();
+		let doc: toml::Table = toml::from_str(&conf.dump().unwrap()).unwrap();
+		let lamp = doc["lamp"].as_array().unwrap()[0].as_table().unwrap();
+		let mqtt = doc["mqtt"].as_table().unwrap();
+		for (key, val) in lamp.iter().chain(mqtt.iter()) {
+			assert_eq!(
+				NON_STRING_KEYS.contains(&key.as_str()),
+				!val.is_str(),
+				"{key} = {val}"
+			);
+		}
+	}
+
+	#[test]
+	fn diagnostics_name_the_override() {
+		let overrides = [set("mqtt.client-id=bridge"), set("mqtt.qos=high")];
+		let err = Config::parse_with_overrides(CONFIG, &overrides).unwrap_err();
+		let [diag] = err.diagnostics.as_slice() else {
+			panic!("{err}");
+		};
+		assert!(diag.span.is_none());
+		assert_eq!(
+			diag.origin.as_deref(),
+			Some("command line argument \"mqtt.qos=high\"")
+		);
+
+		let overrides = [set("lamp.kitchen.connection-tries=0")];
+		let err = Config::parse_with_overrides(CONFIG, &overrides).unwrap_err();
+		assert_eq!(
+			err.diagnostics[0].origin.as_deref(),
+			Some("command line argument \"lamp.kitchen.connection-tries=0\"")
+		);
+	}
+
+	#[test]
+	fn diagnostics_in_the_file_keep_their_position() {
+		let file = CONFIG.replace("connection-tries = 5", "connection-tries = 0");
+		let err = Config::parse_with_overrides(&file, &[set("mqtt.qos=1")]).unwrap_err();
+		let span = err.diagnostics[0].span.as_ref().unwrap();
+		assert_eq!(span.line_text, "connection-tries = 0");
+		assert!(err.diagnostics[0].origin.is_none());
+	}
+}
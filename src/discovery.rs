@@ -0,0 +1,151 @@
+use log::{debug, trace};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Multicast group real lamps listen on for discovery requests.
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+/// Port real lamps listen on for discovery requests.
+pub const SSDP_PORT: u16 = 1982;
+
+/// Search target of Yeelight discovery requests.
+pub const SEARCH_TARGET: &str = "wifi_bulb";
+
+/// The discovery request sent by discover().
+pub const SEARCH_REQUEST: &str = concat!(
+	"M-SEARCH * HTTP/1.1\r\n",
+	"HOST: 239.255.255.250:1982\r\n",
+	"MAN: \"ssdp:discover\"\r\n",
+	"ST: wifi_bulb\r\n",
+);
+
+/// Get the address real lamps answer discovery requests on.
+pub fn multicast_target() -> SocketAddr {
+	SocketAddr::V4(SocketAddrV4::new(MULTICAST_ADDR, SSDP_PORT))
+}
+
+/// A lamp that answered a discovery request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DiscoveredLamp {
+	/// Unique ID of the lamp, as sent by the lamp (e.g. `0x000000000015243f`).
+	pub id: String,
+	/// Address the lamp accepts commands on.
+	pub location: SocketAddr,
+	/// Model of the lamp, e.g. `color`, `mono` or `stripe`.
+	pub model: String,
+	/// Firmware version.
+	pub fw_ver: String,
+	/// Methods supported by the lamp.
+	pub support: Vec<String>,
+	/// Name of the lamp, set with set_name. Often empty.
+	pub name: String,
+	/// The state reported by the lamp: power, bright, color_mode, ct, rgb, hue and sat.
+	pub props: BTreeMap<String, String>,
+}
+
+/// Headers of a discovery response that are reported in DiscoveredLamp::props.
+const STATE_HEADERS: [&str; 7] = ["power", "bright", "color_mode", "ct", "rgb", "hue", "sat"];
+
+impl DiscoveredLamp {
+	/// Parse the response of a lamp to a discovery request.
+	///
+	/// The response looks like an HTTP response, with the details of the lamp in its headers:
+	/// ```text
+	/// HTTP/1.1 200 OK
+	/// Location: yeelight://192.168.1.239:55443
+	/// id: 0x000000000015243f
+	/// model: color
+	/// fw_ver: 18
+	/// support: get_prop set_default set_power toggle set_bright ...
+	/// power: on
+	/// ...
+	/// ```
+	/// Header names are case-insensitive. The `Location` and `id` headers are required.
+	pub fn parse(response: &str) -> Result<Self, String> {
+		let mut lines = response.lines();
+		let status = lines.next().unwrap_or_default();
+		if !status.starts_with("HTTP/1.1 200") {
+			return Err(format!("Not a discovery response: \"{status}\""));
+		}
+		let headers: BTreeMap<String, String> = lines
+			.filter_map(|line| line.split_once(':'))
+			.map(|(name, val)| (name.trim().to_ascii_lowercase(), val.trim().to_string()))
+			.collect();
+		let header = |name: &str| headers.get(name).cloned().unwrap_or_default();
+		let location = headers
+			.get("location")
+			.ok_or("Response without a Location header")?;
+		let location = location
+			.strip_prefix("yeelight://")
+			.ok_or_else(|| format!("Unexpected location \"{location}\""))?
+			.parse()
+			.map_err(|e| format!("Invalid location \"{location}\": {e}"))?;
+		let id = headers.get("id").ok_or("Response without an id header")?;
+		Ok(Self {
+			id: id.clone(),
+			location,
+			model: header("model"),
+			fw_ver: header("fw_ver"),
+			support: header("support")
+				.split_whitespace()
+				.map(String::from)
+				.collect(),
+			name: header("name"),
+			props: STATE_HEADERS
+				.into_iter()
+				.filter_map(|name| Some((name.to_string(), headers.get(name)?.clone())))
+				.collect(),
+		})
+	}
+}
+
+/// Look for lamps on the network.
+///
+/// Sends a discovery request to `target` and collects the responses that arrive within `wait`.
+/// Use multicast_target() to reach real lamps; any other address (such as that of a
+/// mock::ssdp::MockSsdp) receives the request directly. Every lamp is returned once,
+/// in the order the responses arrived; responses that cannot be parsed are skipped.
+pub fn discover(target: SocketAddr, wait: Duration) -> io::Result<Vec<DiscoveredLamp>> {
+	let bind: SocketAddr = if target.ip().is_loopback() {
+		SocketAddr::from(([127, 0, 0, 1], 0))
+	} else {
+		SocketAddr::from(([0, 0, 0, 0], 0))
+	};
+	let socket = UdpSocket::bind(bind)?;
+	debug!("Sending discovery request to {target}");
+	socket.send_to(SEARCH_REQUEST.as_bytes(), target)?;
+	let deadline = Instant::now() + wait;
+	let mut lamps: Vec<DiscoveredLamp> = Vec::new();
+	let mut buf = [0u8; 2048];
+	loop {
+		let left = deadline.saturating_duration_since(Instant::now());
+		if left.is_zero() {
+			break;
+		}
+		socket.set_read_timeout(Some(left))?;
+		let (len, from) = match socket.recv_from(&mut buf) {
+			Ok(received) => received,
+			Err(e)
+				if matches!(
+					e.kind(),
+					io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+				) =>
+			{
+				break;
+			},
+			Err(e) => return Err(e),
+		};
+		let response = String::from_utf8_lossy(&buf[..len]);
+		trace!("Discovery response from {from}: {response:?}");
+		match DiscoveredLamp::parse(&response) {
+			Ok(lamp) if lamps.iter().all(|known| known.id != lamp.id) => lamps.push(lamp),
+			Ok(_) => {},
+			Err(e) => debug!("Ignoring response from {from}: {e}"),
+		}
+	}
+	debug!("Discovered {} lamps", lamps.len());
+	Ok(lamps)
+}
@@ -0,0 +1,339 @@
+use crate::lamp::{Lamp, PreparedCmd};
+use crate::structs::{Command, Transition};
+use log::{debug, info, warn};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Barrier, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A Lamp that can be shared between threads (and between several groups).
+pub type SharedLamp = Arc<Mutex<Lamp>>;
+
+/// A named set of lamps that are controlled together.
+///
+/// Commands sent to the group are passed to every member in parallel.
+/// A failure on one lamp does not prevent the command from reaching the others;
+/// instead, the outcome for each lamp is collected into a GroupResult.
+///
+/// Example, assuming you have created and connected two SharedLamps:
+/// ```no_run
+/// use yeerugina::group::LampGroup;
+/// use yeerugina::structs::{Command, Transition};
+/// # use yeerugina::lamp::Lamp;
+/// # use yeerugina::structs::Effect;
+/// # use std::sync::{Arc, Mutex};
+/// # use std::time::Duration;
+/// # fn main() -> Result<(), std::net::AddrParseError> {
+/// # let new_lamp = |name: &str, addr: &str| -> Result<_, std::net::AddrParseError> {
+/// #     let lamp = Lamp::new(name.into(), addr.into(), Effect::Smooth, Duration::from_millis(500))?;
+/// #     Ok(Arc::new(Mutex::new(lamp)))
+/// # };
+/// # let kitchen = new_lamp("kitchen", "192.168.1.3:55443")?;
+/// # let hall = new_lamp("hall", "192.168.1.4:55443")?;
+///
+/// let group = LampGroup::new(
+///     String::from("downstairs"),
+///     vec![(String::from("kitchen"), kitchen), (String::from("hall"), hall)],
+/// );
+/// let res = group.send_cmd(&Command::Toggle, &Transition::default());
+/// if !res.is_ok() {
+///     println!("{res}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct LampGroup {
+	name: String,
+	members: Vec<(String, SharedLamp)>,
+}
+
+impl LampGroup {
+	/// Creates a new group from a name and a list of (lamp ID, lamp) pairs.
+	pub fn new(name: String, members: Vec<(String, SharedLamp)>) -> Self {
+		Self { name, members }
+	}
+
+	/// Get the name of the group.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Get the IDs of the lamps in the group.
+	pub fn member_ids(&self) -> impl Iterator<Item = &str> {
+		self.members.iter().map(|(id, _)| id.as_str())
+	}
+
+	/// Send a command to every lamp in the group at the same time.
+	///
+	/// The requests are prepared with LampGroup::prepare_cmd()
+	/// and written with LampGroup::write_prepared().
+	pub fn send_cmd(&self, cmd: &Command, trans: &Transition) -> GroupResult {
+		info!(
+			"{} | Sending {cmd:?} to {} lamps",
+			self.name,
+			self.members.len()
+		);
+		self.write_prepared(self.prepare_cmd(cmd, trans))
+	}
+
+	/// Send a command to every lamp in the group, writing the requests as simultaneously as possible.
+	///
+	/// Used for effects (such as smooth transitions) that should look synchronized.
+	/// The requests are prepared with LampGroup::prepare_cmd()
+	/// and written with LampGroup::write_prepared_synced().
+	pub fn send_cmd_synced(&self, cmd: &Command, trans: &Transition) -> SyncResult {
+		info!(
+			"{} | Sending {cmd:?} to {} lamps in sync",
+			self.name,
+			self.members.len()
+		);
+		self.write_prepared_synced(self.prepare_cmd(cmd, trans))
+	}
+
+	/// Reserve a command ID on every lamp in the group, without sending anything.
+	///
+	/// Each member is only locked while its request is prepared (see Lamp::prepare_cmd()).
+	/// Returns the prepared request (or the error) for every member, in the order of the members.
+	pub fn prepare_cmd(
+		&self, cmd: &Command, trans: &Transition,
+	) -> Vec<(String, io::Result<PreparedCmd>)> {
+		self.members
+			.iter()
+			.map(|(id, lamp)| {
+				let prep = lock_lamp(lamp).map(|mut lamp| lamp.prepare_cmd(cmd.clone(), trans));
+				(id.clone(), prep)
+			})
+			.collect()
+	}
+
+	/// Write the requests returned by LampGroup::prepare_cmd() to every lamp at the same time.
+	///
+	/// Each lamp is handled in its own thread. The function waits for all of them to finish
+	/// and returns the ID of the request (or the error) for each member.
+	pub fn write_prepared(&self, prepared: Vec<(String, io::Result<PreparedCmd>)>) -> GroupResult {
+		let results = thread::scope(|scope| {
+			let handles: Vec<_> = self
+				.members
+				.iter()
+				.zip(prepared)
+				.map(|((_, lamp), (id, prep))| {
+					let write = move || {
+						let prep = prep?;
+						lock_lamp(lamp)?.write_prepared(&prep)?;
+						Ok(prep.id)
+					};
+					(id, scope.spawn(write))
+				})
+				.collect();
+			handles
+				.into_iter()
+				.map(|(id, handle)| {
+					let res = handle
+						.join()
+						.unwrap_or_else(|_| Err(io::Error::other("Lamp thread panicked")));
+					(id, res)
+				})
+				.collect()
+		});
+		let group_res = GroupResult { results };
+		if group_res.is_ok() {
+			debug!("{} | Command sent to every lamp", self.name);
+		} else {
+			warn!("{} | {group_res}", self.name);
+		}
+		group_res
+	}
+
+	/// Write the requests returned by LampGroup::prepare_cmd() as simultaneously as possible.
+	///
+	/// Members that are not connected are connected first, in parallel and without being
+	/// locked (see connect_shared()). The members are then locked one after the other in the
+	/// order of their IDs, so that groups sharing lamps cannot deadlock. Finally, every member
+	/// gets its own thread; the threads wait on a barrier, so that the actual writes start
+	/// at the same moment.
+	///
+	/// The returned SyncResult also contains the measured skew,
+	/// i.e. the time between the first and the last completed write.
+	pub fn write_prepared_synced(
+		&self, prepared: Vec<(String, io::Result<PreparedCmd>)>,
+	) -> SyncResult {
+		let mut connected: Vec<(usize, io::Result<PreparedCmd>)> = self
+			.connect_all()
+			.into_iter()
+			.zip(prepared)
+			.map(|(conn, (_, prep))| conn.and(prep))
+			.enumerate()
+			.collect();
+		connected.sort_by_key(|(idx, _)| self.members[*idx].0.as_str());
+		let mut outcomes: Vec<(usize, io::Result<(u8, Instant)>)> = Vec::new();
+		let mut guards: Vec<(usize, PreparedCmd, MutexGuard<'_, Lamp>)> = Vec::new();
+		for (idx, prep) in connected {
+			match prep.and_then(|prep| Ok((prep, lock_lamp(&self.members[idx].1)?))) {
+				Ok((prep, guard)) => guards.push((idx, prep, guard)),
+				Err(e) => outcomes.push((idx, Err(e))),
+			}
+		}
+		let barrier = Barrier::new(guards.len());
+		let writes: Vec<_> = thread::scope(|scope| {
+			let handles: Vec<_> = guards
+				.iter_mut()
+				.map(|(idx, prep, guard)| {
+					let lamp: &mut Lamp = guard;
+					let (prep, barrier) = (&*prep, &barrier);
+					(*idx, scope.spawn(move || sync_write(lamp, prep, barrier)))
+				})
+				.collect();
+			handles
+				.into_iter()
+				.map(|(idx, handle)| {
+					let res = handle
+						.join()
+						.unwrap_or_else(|_| Err(io::Error::other("Lamp thread panicked")));
+					(idx, res)
+				})
+				.collect()
+		});
+		drop(guards);
+		outcomes.extend(writes);
+		outcomes.sort_by_key(|(idx, _)| *idx);
+		// Measure the time between the first and the last write
+		let write_times: Vec<Instant> = outcomes
+			.iter()
+			.filter_map(|(_, res)| res.as_ref().ok().map(|(_, t)| *t))
+			.collect();
+		let skew = match (write_times.iter().min(), write_times.iter().max()) {
+			(Some(first), Some(last)) => Some(last.duration_since(*first)),
+			_ => None,
+		};
+		let results = outcomes
+			.into_iter()
+			.map(|(idx, res)| (self.members[idx].0.clone(), res.map(|(cmd_id, _)| cmd_id)))
+			.collect();
+		let sync_res = SyncResult {
+			result: GroupResult { results },
+			skew,
+		};
+		if sync_res.result.is_ok() {
+			debug!("{} | {sync_res}", self.name);
+		} else {
+			warn!("{} | {sync_res}", self.name);
+		}
+		sync_res
+	}
+
+	/// Connect every member that is not connected, in parallel.
+	/// Returns the outcome for each member, in the order of the members.
+	fn connect_all(&self) -> Vec<io::Result<()>> {
+		thread::scope(|scope| {
+			let handles: Vec<_> = self
+				.members
+				.iter()
+				.map(|(_, lamp)| scope.spawn(move || connect_shared(lamp).map(drop)))
+				.collect();
+			handles
+				.into_iter()
+				.map(|handle| {
+					handle
+						.join()
+						.unwrap_or_else(|_| Err(io::Error::other("Lamp thread panicked")))
+				})
+				.collect()
+		})
+	}
+}
+
+/// Thread body for LampGroup::write_prepared_synced().
+/// The lamp stays locked by the calling thread.
+fn sync_write(lamp: &mut Lamp, prep: &PreparedCmd, barrier: &Barrier) -> io::Result<(u8, Instant)> {
+	barrier.wait();
+	lamp.write_prepared(prep)?;
+	Ok((prep.id, Instant::now()))
+}
+
+/// Connect a SharedLamp if it is not connected, and return it locked.
+///
+/// The lamp is not locked while connecting, which may take several tries:
+/// the connection is opened with a Dialer and attached afterwards.
+/// Returns an error of kind NotConnected if the lamp has no connection settings yet.
+pub fn connect_shared(lamp: &SharedLamp) -> io::Result<MutexGuard<'_, Lamp>> {
+	let dialer = {
+		let guard = lock_lamp(lamp)?;
+		if guard.is_connected() {
+			return Ok(guard);
+		}
+		guard.dialer()?
+	};
+	let stream = dialer.dial()?;
+	let mut guard = lock_lamp(lamp)?;
+	// Keep a connection made by someone else in the meantime
+	if !guard.is_connected() {
+		guard.attach(stream);
+	}
+	Ok(guard)
+}
+
+/// Lock a SharedLamp, turning a poisoned mutex into an io::Error.
+pub fn lock_lamp(lamp: &SharedLamp) -> io::Result<MutexGuard<'_, Lamp>> {
+	lamp.lock()
+		.map_err(|_| io::Error::other("Lamp mutex poisoned"))
+}
+
+/// The outcome of sending a command to a LampGroup.
+///
+/// Contains the lamp ID and the ID of the request (or the error) for every member.
+#[derive(Debug)]
+pub struct GroupResult {
+	/// Per-lamp results, in the same order as the members of the group.
+	pub results: Vec<(String, io::Result<u8>)>,
+}
+
+impl GroupResult {
+	/// Returns true if the command reached every lamp.
+	pub fn is_ok(&self) -> bool {
+		self.results.iter().all(|(_, res)| res.is_ok())
+	}
+
+	/// Iterate over the lamps that failed, together with their errors.
+	pub fn failures(&self) -> impl Iterator<Item = (&str, &io::Error)> {
+		self.results
+			.iter()
+			.filter_map(|(id, res)| res.as_ref().err().map(|e| (id.as_str(), e)))
+	}
+}
+
+impl fmt::Display for GroupResult {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let failed: Vec<String> = self
+			.failures()
+			.map(|(id, e)| format!("{id} ({e})"))
+			.collect();
+		let ok_count = self.results.len() - failed.len();
+		write!(f, "{ok_count}/{} lamps OK", self.results.len())?;
+		if !failed.is_empty() {
+			write!(f, "; failed: {}", failed.join(", "))?;
+		}
+		Ok(())
+	}
+}
+
+/// The outcome of LampGroup::send_cmd_synced() and LampGroup::write_prepared_synced().
+#[derive(Debug)]
+pub struct SyncResult {
+	/// Per-lamp results.
+	pub result: GroupResult,
+	/// Time between the first and the last successful write.
+	/// None if no write succeeded.
+	pub skew: Option<Duration>,
+}
+
+impl fmt::Display for SyncResult {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.result)?;
+		if let Some(skew) = self.skew {
+			write!(f, "; skew {skew:?}")?;
+		}
+		Ok(())
+	}
+}
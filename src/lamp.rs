@@ -1,9 +1,12 @@
-use crate::structs::{Command, ConnectionSettings, Effect};
+use crate::config::LampConfig;
+use crate::record::{Direction, Recorder};
+use crate::structs::{Command, ConnectionSettings, Effect, Transition};
 use log::{debug, info, trace, warn};
 use regex::bytes::Regex;
+use serde_json::{Map, Value};
 use std::io;
-use std::io::Write;
-use std::net::{AddrParseError, SocketAddr, TcpStream};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{AddrParseError, Shutdown, SocketAddr, TcpStream};
 use std::time::Duration;
 
 /// Structure (record) describing a Yeelight lamp.
@@ -30,7 +33,166 @@ pub struct Lamp {
 	duration: Duration,
 	ip: SocketAddr,
 	stream: Option<TcpStream>,
+	conn_settings: Option<ConnectionSettings>,
+	conn_number: u64,
 	cmd_count: u8,
+	recorder: Option<Recorder>,
+}
+
+/// A line received from a lamp, as parsed by Lamp::parse_response().
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+	/// The command with the given ID succeeded.
+	/// Most commands return `["ok"]`; get_prop returns the values of the requested properties.
+	Result {
+		/// ID of the command.
+		id: u8,
+		/// The values returned by the lamp.
+		values: Vec<Value>,
+	},
+	/// The command with the given ID failed.
+	Error {
+		/// ID of the command.
+		id: u8,
+		/// Error code returned by the lamp.
+		code: i64,
+		/// Error message returned by the lamp.
+		message: String,
+	},
+	/// The lamp reports that some of its properties changed.
+	/// Sent to every connected client, whoever caused the change.
+	Notification(Map<String, Value>),
+}
+
+/// Reads the lines a lamp sends over its connection.
+///
+/// Created by Lamp::reader(). The reader uses its own handle to the TcpStream,
+/// so it can wait for data without holding on to the Lamp.
+#[derive(Debug)]
+pub struct LampReader {
+	name: String,
+	conn_number: u64,
+	reader: BufReader<TcpStream>,
+	buf: Vec<u8>,
+	recorder: Option<Recorder>,
+}
+
+impl LampReader {
+	/// Get the number of the connection this reader belongs to.
+	/// See Lamp::connection_number().
+	pub fn connection_number(&self) -> u64 {
+		self.conn_number
+	}
+
+	/// Wait for the next line sent by the lamp.
+	///
+	/// Returns Ok(None) if the read timeout of the lamp expired before a full line arrived;
+	/// the part of the line received so far is kept for the next call.
+	/// Returns an error of kind UnexpectedEof once the lamp closes the connection.
+	pub fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+		match self.reader.read_until(b'\n', &mut self.buf) {
+			Ok(0) => Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				"Connection closed by the lamp",
+			)),
+			Ok(_) if self.buf.ends_with(b"\n") => {
+				if let Some(ref recorder) = self.recorder {
+					recorder.record(&self.name, self.conn_number, Direction::Received, &self.buf);
+				}
+				Ok(Some(std::mem::take(&mut self.buf)))
+			},
+			// EOF in the middle of a line
+			Ok(_) => Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				"Connection closed by the lamp",
+			)),
+			Err(e)
+				if matches!(
+					e.kind(),
+					io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+				) =>
+			{
+				Ok(None)
+			},
+			Err(e) => Err(e),
+		}
+	}
+}
+
+/// Opens connections to a lamp without needing the Lamp itself.
+///
+/// Created by Lamp::dialer(). Connecting may take several tries, so a SharedLamp need not
+/// stay locked meanwhile: dial without the lock, then hand the stream to Lamp::attach().
+#[derive(Clone, Debug)]
+pub struct Dialer {
+	name: String,
+	ip: SocketAddr,
+	conn_settings: ConnectionSettings,
+}
+
+impl Dialer {
+	/// Try to connect to the lamp, as often as the ConnectionSettings allow.
+	///
+	/// The read and write timeouts are set on the returned stream;
+	/// failing to set them does not interrupt the function.
+	pub fn dial(&self) -> io::Result<TcpStream> {
+		let ConnectionSettings {
+			read_timeout,
+			write_timeout,
+			conn_timeout,
+			conn_tries,
+			conn_wait,
+		} = self.conn_settings;
+		if conn_timeout.is_zero() {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"conn_timeout cannot be zero",
+			));
+		};
+		info!("{} | Connecting lamp", self.name);
+		let mut try_counter = 0u8;
+		let stream = loop {
+			debug!("{} | Start connection attempt loop", self.name);
+			let maybe_stream = TcpStream::connect_timeout(&self.ip, conn_timeout);
+			try_counter += 1;
+			match maybe_stream {
+				Ok(stream) => {
+					debug!("{} | TcpStream returned from connect_timeout()", self.name);
+					break stream;
+				},
+				Err(e) if try_counter < conn_tries => {
+					info!("Connection failed (try {try_counter}/{conn_tries}): {e}");
+					std::thread::sleep(conn_wait);
+				},
+				Err(e) => {
+					warn!("Could not connect after {try_counter}/{conn_tries} tries; giving up");
+					return Err(e);
+				},
+			};
+		};
+		// Try to set the read and write timeouts
+		trace!("{} | Setting timeout values", self.name);
+		if let Err(e) = stream.set_read_timeout(read_timeout) {
+			warn!("Could not set TcpStream read timeout: {e}");
+		};
+		if let Err(e) = stream.set_write_timeout(write_timeout) {
+			warn!("Could not set TcpStream write timeout: {e}");
+		};
+		Ok(stream)
+	}
+}
+
+/// A request that has been assigned an ID and converted to bytes, but not yet sent.
+///
+/// Created by Lamp::prepare_cmd() and sent with Lamp::write_prepared().
+/// Preparing requests ahead of time keeps the actual write as short as possible,
+/// which is useful when several lamps should react at the same moment.
+#[derive(Clone, Debug)]
+pub struct PreparedCmd {
+	/// ID of the request.
+	pub id: u8,
+	/// The request as it will be written to the TcpStream.
+	pub bytes: Vec<u8>,
 }
 
 impl Lamp {
@@ -63,10 +225,33 @@ impl Lamp {
 			duration,
 			ip,
 			stream: None,
+			conn_settings: None,
+			conn_number: 0,
 			cmd_count: 0u8,
+			recorder: None,
 		})
 	}
 
+	/// Creates a new Lamp struct from the settings of a lamp in the config file.
+	///
+	/// Unlike Lamp::new(), this cannot fail, since the IP address has already been parsed.
+	/// The lamp is not connected, but it keeps the connection settings of the config,
+	/// so Lamp::dialer() and Lamp::reconnect() can use them right away.
+	pub fn from_config(conf: &LampConfig) -> Self {
+		trace!("{} | Creating a new lamp from config", conf.name);
+		Self {
+			name: conf.name.clone(),
+			effect: conf.effect,
+			duration: conf.default_duration,
+			ip: conf.ip,
+			stream: None,
+			conn_settings: Some(conf.get_connection_settings()),
+			conn_number: 0,
+			cmd_count: 0u8,
+			recorder: None,
+		}
+	}
+
 	/// Try to connect to the lamp, returning a Result.
 	///
 	/// If successful, the Result will contain the read and write timeouts of the lamp.
@@ -101,61 +286,186 @@ impl Lamp {
 	/// to the values provided in the read_write_timeouts tuple.
 	/// If errors arise during the setting stage, they will not interrupt the function.
 	/// Finally, the actual ("real") timeout values are returned as the Result.
+	/// The settings are stored so that Lamp::reconnect() can reuse them later.
 	pub fn connect(
 		&mut self, conn_settings: ConnectionSettings,
 	) -> io::Result<(Option<Duration>, Option<Duration>)> {
-		let ConnectionSettings {
-			read_timeout,
-			write_timeout,
-			conn_timeout,
-			conn_tries,
-			conn_wait,
-		} = conn_settings;
-		if conn_timeout.is_zero() {
+		self.conn_settings = Some(conn_settings.clone());
+		self.disconnect();
+		let dialer = Dialer {
+			name: self.name.clone(),
+			ip: self.ip,
+			conn_settings,
+		};
+		let stream = dialer.dial()?;
+		// Get the values for the timeouts here
+		// Note that if both operations fail
+		// only the read_timeout failure will be propagated
+		let timeouts = (stream.read_timeout()?, stream.write_timeout()?);
+		self.attach(stream);
+		Ok(timeouts)
+	}
+
+	/// Get a Dialer that connects with the settings given to the last Lamp::connect() call,
+	/// or with those of the config for a lamp created by Lamp::from_config().
+	///
+	/// Returns an error of kind NotConnected if there are no settings yet.
+	pub fn dialer(&self) -> io::Result<Dialer> {
+		let Some(conn_settings) = self.conn_settings.clone() else {
 			return Err(io::Error::new(
-				io::ErrorKind::InvalidInput,
-				"conn_timeout cannot be zero",
+				io::ErrorKind::NotConnected,
+				"Lamp has never been connected",
 			));
 		};
-		info!("{} | Connecting lamp", self.name);
-		let mut try_counter = 0u8;
-		loop {
-			debug!("{} | Start connection attempt loop", self.name);
-			let maybe_stream = TcpStream::connect_timeout(&self.ip, conn_timeout);
-			try_counter += 1;
-			match maybe_stream {
-				Ok(stream) => {
-					debug!("{} | TcpStream returned from connect_timeout()", self.name);
-					self.stream = Some(stream);
-					break;
-				},
-				Err(e) if try_counter < conn_tries => {
-					info!("Connection failed (try {try_counter}/{conn_tries}): {e}");
-					std::thread::sleep(conn_wait);
-				},
-				Err(e) => {
-					warn!("Could not connect after {try_counter}/{conn_tries} tries; giving up");
-					return Err(e);
-				},
-			};
+		Ok(Dialer {
+			name: self.name.clone(),
+			ip: self.ip,
+			conn_settings,
+		})
+	}
+
+	/// Use a connection opened by Dialer::dial(), dropping the current one (if any).
+	pub fn attach(&mut self, stream: TcpStream) {
+		self.disconnect();
+		self.stream = Some(stream);
+		self.conn_number += 1;
+	}
+
+	/// Returns true if the lamp has an open TcpStream.
+	pub fn is_connected(&self) -> bool {
+		self.stream.is_some()
+	}
+
+	/// Get the number of the current (or last) connection.
+	///
+	/// The number increases every time Lamp::connect() succeeds, so it can be used to tell
+	/// whether the lamp has reconnected in the meantime.
+	pub fn connection_number(&self) -> u64 {
+		self.conn_number
+	}
+
+	/// Close the connection to the lamp, if any.
+	///
+	/// Any LampReader of the connection will receive an error on its next read.
+	pub fn disconnect(&mut self) {
+		if let Some(stream) = self.stream.take() {
+			debug!("{} | Disconnecting lamp", self.name);
+			if let Err(e) = stream.shutdown(Shutdown::Both) {
+				debug!("{} | Could not shut down TcpStream: {e}", self.name);
+			}
 		}
-		// Using unwrap() since we just defined self.stream = Some(...)
-		let stream: &mut TcpStream = self
-			.stream
-			.as_mut()
-			.expect("Could not get mutable ref to stream");
-		// Try to set the read and write timeouts
-		trace!("{} | Setting timeout values", self.name);
-		if let Err(e) = stream.set_read_timeout(read_timeout) {
-			warn!("Could not set TcpStream read timeout: {e}");
+	}
+
+	/// Create a LampReader for the current connection.
+	///
+	/// Returns an error of kind NotConnected if the lamp is not connected.
+	pub fn reader(&self) -> io::Result<LampReader> {
+		let Some(ref stream) = self.stream else {
+			return Err(io::Error::new(
+				io::ErrorKind::NotConnected,
+				"Lamp is not connected yet",
+			));
 		};
-		if let Err(e) = stream.set_write_timeout(write_timeout) {
-			warn!("Could not set TcpStream write timeout: {e}");
+		Ok(LampReader {
+			name: self.name.clone(),
+			conn_number: self.conn_number,
+			reader: BufReader::new(stream.try_clone()?),
+			buf: Vec::new(),
+			recorder: self.recorder.clone(),
+		})
+	}
+
+	/// Get the name of the lamp.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Record the traffic of the lamp, or stop recording with None.
+	///
+	/// Every request written from now on is recorded, and so is every line received by
+	/// the LampReaders created from now on. See Recorder.
+	pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+		self.recorder = recorder;
+	}
+
+	/// Get the recorder of the lamp, if the traffic is being recorded.
+	pub fn recorder(&self) -> Option<&Recorder> {
+		self.recorder.as_ref()
+	}
+
+	/// Record a request written to the lamp.
+	fn record_sent(&self, bytes: &[u8]) {
+		if let Some(ref recorder) = self.recorder {
+			recorder.record(&self.name, self.conn_number, Direction::Sent, bytes);
+		}
+	}
+
+	/// Drop the current connection (if any) and connect again
+	/// using the settings of Lamp::dialer().
+	///
+	/// Returns an error of kind NotConnected if there are no settings yet.
+	pub fn reconnect(&mut self) -> io::Result<(Option<Duration>, Option<Duration>)> {
+		let conn_settings = self.dialer()?.conn_settings;
+		info!("{} | Reconnecting lamp", self.name);
+		self.connect(conn_settings)
+	}
+
+	/// Reconnect the lamp if it is not connected.
+	pub fn ensure_connected(&mut self) -> io::Result<()> {
+		if !self.is_connected() {
+			self.reconnect()?;
+		}
+		Ok(())
+	}
+
+	/// Assign an ID to a command and convert it to bytes without sending it.
+	///
+	/// The ID is reserved immediately, so the internal command counter is incremented
+	/// even if the prepared command is never written.
+	/// Fields of the Transition that are None are replaced by the lamp's defaults.
+	pub fn prepare_cmd(&mut self, cmd: Command, trans: &Transition) -> PreparedCmd {
+		let id = self.cmd_count;
+		debug!("{} | Preparing command {cmd:?} with ID {id}", self.name);
+		let (effect, duration) = self.resolve_transition(trans);
+		let req = cmd.to_request(id, &effect, &duration);
+		self.cmd_count = self.cmd_count.wrapping_add(1);
+		PreparedCmd {
+			id,
+			bytes: req.into_bytes(),
+		}
+	}
+
+	/// Fill in the missing fields of a Transition with the lamp's defaults.
+	fn resolve_transition(&self, trans: &Transition) -> (Effect, Duration) {
+		(
+			trans.effect.unwrap_or(self.effect),
+			trans.duration.unwrap_or(self.duration),
+		)
+	}
+
+	/// Write a command created by Lamp::prepare_cmd() to the TcpStream.
+	pub fn write_prepared(&mut self, prep: &PreparedCmd) -> io::Result<()> {
+		let Some(ref mut stream) = self.stream else {
+			warn!("{} | Lamp not connected, cannot send command", self.name);
+			return Err(io::Error::new(
+				io::ErrorKind::NotConnected,
+				"Lamp is not connected yet",
+			));
 		};
-		// Get the values for the timeouts here
-		// Note that if both operations fail
-		// only the read_timeout failure will be propagated
-		Ok((stream.read_timeout()?, stream.write_timeout()?))
+		trace!("{} | Writing prepared command {}", self.name, prep.id);
+		let res = stream.write_all(&prep.bytes);
+		self.check_write(res)?;
+		self.record_sent(&prep.bytes);
+		Ok(())
+	}
+
+	/// Drop the connection if a write failed, so that Lamp::ensure_connected() reconnects.
+	fn check_write(&mut self, res: io::Result<()>) -> io::Result<()> {
+		if let Err(ref e) = res {
+			warn!("{} | Write failed, dropping connection: {e}", self.name);
+			self.disconnect();
+		}
+		res
 	}
 
 	/// Try to send a command, returning the ID of said command.
@@ -173,7 +483,13 @@ impl Lamp {
 	/// let cmd_id: u8 = lamp.send_cmd(cmd)?;
 	/// ```
 	pub fn send_cmd(&mut self, cmd: Command) -> io::Result<u8> {
+		self.send_cmd_with(cmd, &Transition::default())
+	}
+
+	/// Like Lamp::send_cmd(), but with a custom effect and/or duration for this command only.
+	pub fn send_cmd_with(&mut self, cmd: Command, trans: &Transition) -> io::Result<u8> {
 		debug!("{} | Attempting to send command {cmd:?}", self.name);
+		let (effect, duration) = self.resolve_transition(trans);
 		// Use stream instead of self.stream later on.
 		// Return io::Error if not connected yet.
 		// ref mut because shared reference and moves...
@@ -189,11 +505,13 @@ impl Lamp {
 		let id = self.cmd_count;
 		debug!("{} Command ID {id}", self.name);
 		// Construct message bytes
-		let req = cmd.to_request(id, &self.effect, &self.duration);
+		let req = cmd.to_request(id, &effect, &duration);
 		let byte_arr: &[u8] = req.as_bytes();
 		// Output and increment counter
 		trace!("{} | Writing bytes to TcpStream", self.name);
-		stream.write_all(byte_arr)?;
+		let res = stream.write_all(byte_arr);
+		self.check_write(res)?;
+		self.record_sent(byte_arr);
 		//self.cmd_count += 1;
 		self.cmd_count = self.cmd_count.wrapping_add(1);
 		debug!("{} | New Command ID {}", self.name, self.cmd_count);
@@ -221,16 +539,39 @@ impl Lamp {
 		Ok(resp_id == self.cmd_count.wrapping_sub(1))
 	}
 
-	/// Take in a response from the lamp and parse it.
+	/// Take in a line received from the lamp and parse it.
+	///
+	/// The lamp sends three kinds of lines:
+	/// - `{"id":1,"result":["ok"]}` when a command succeeded,
+	/// - `{"id":1,"error":{"code":-1,"message":"unsupported method"}}` when it failed,
+	/// - `{"method":"props","params":{"power":"on"}}` when the state of the lamp changed.
 	///
-	/// Return Ok(None) if succeeded and nothing returned
-	/// (for example, when using set_rgb or toggle)
-	/// Ok(String) if get_prop was called and we got values back
-	/// Err(String) if something went wrong
-	pub fn parse_response(resp: &[u8]) -> Result<Option<String>, String> {
-		todo!()
-		//let Ok(re) = Regex::new(todo!()) else {
-		//	return Err(String::from("Could not create regex"));
-		//};
+	/// Returns Err(String) if the line is none of these.
+	pub fn parse_response(resp: &[u8]) -> Result<Response, String> {
+		let val: Value = serde_json::from_slice(resp).map_err(|e| format!("Invalid JSON: {e}"))?;
+		if val["method"] == "props" {
+			return match val.get("params") {
+				Some(Value::Object(params)) => Ok(Response::Notification(params.clone())),
+				_ => Err(String::from("Notification without params")),
+			};
+		}
+		let id = val["id"]
+			.as_u64()
+			.and_then(|id| u8::try_from(id).ok())
+			.ok_or("Response without a valid ID")?;
+		if let Some(Value::Array(values)) = val.get("result") {
+			Ok(Response::Result {
+				id,
+				values: values.clone(),
+			})
+		} else if let Some(err) = val.get("error") {
+			Ok(Response::Error {
+				id,
+				code: err["code"].as_i64().unwrap_or_default(),
+				message: err["message"].as_str().unwrap_or_default().to_string(),
+			})
+		} else {
+			Err(String::from("Response has neither a result nor an error"))
+		}
 	}
 }
@@ -5,11 +5,21 @@
 //! The program will process messages sent under some MQTT topic, parse them and pass them onward
 //! to the lamp by sending them through a TcpStream.
 
+/// Module containing the program settings and the logic for loading them.
+pub mod config;
+/// Module containing the discovery of lamps on the local network.
+pub mod discovery;
+/// Module containing LampGroup, used to control several lamps at once.
+pub mod group;
 /// Module containing the Lamp struct.
 pub mod lamp;
+/// Module containing MockLamp, a fake lamp for testing without hardware.
+pub mod mock;
 /// Module containing functions that pertain to MQTT.
 /// For instance, functions taking in input messages are defined here.
 pub mod mqtt;
+/// Module containing the traffic recorder of Lamp and the replay of recordings.
+pub mod record;
 /// Module containing objects needed for stateful lamp control.
 pub mod stateful;
 /// Module containing other structs used by the program.
@@ -0,0 +1,683 @@
+/// A stand-in for the discovery responders of real lamps.
+pub mod ssdp;
+
+use crate::stateful::ColorMode;
+use crate::structs::Request;
+use log::{debug, info, trace, warn};
+use serde_json::{Map, Value, json};
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How many requests a real lamp accepts from a client per minute.
+pub const QUOTA_PER_MINUTE: usize = 60;
+
+/// The window over which Faults::quota is counted.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+/// Properties reported by MockState::prop(), and compared to find out what a command changed.
+pub const MOCK_PROPS: [&str; 10] = [
+	"power",
+	"bright",
+	"color_mode",
+	"ct",
+	"rgb",
+	"hue",
+	"sat",
+	"flowing",
+	"name",
+	"delayoff",
+];
+
+/// The simulated state of a MockLamp.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockState {
+	/// Whether the lamp is on.
+	pub power: bool,
+	/// Brightness in percent, 1-100.
+	pub bright: u8,
+	/// Which of the color properties is in use.
+	pub color_mode: ColorMode,
+	/// Color temperature in Kelvin, 1700-6500.
+	pub ct: u16,
+	/// Color as 0xRRGGBB.
+	pub rgb: u32,
+	/// Hue, 0-359.
+	pub hue: u16,
+	/// Saturation, 0-100.
+	pub sat: u8,
+	/// Whether a color flow is running.
+	pub flowing: bool,
+	/// Name set with set_name.
+	pub name: String,
+	/// Minutes until the lamp turns off, set with cron_add; 0 if there is no timer.
+	/// Unlike on a real lamp, the timer does not count down.
+	pub delayoff: u16,
+}
+
+impl Default for MockState {
+	/// A lamp that is on at full brightness, with a neutral color temperature.
+	fn default() -> Self {
+		Self {
+			power: true,
+			bright: 100,
+			color_mode: ColorMode::Ct,
+			ct: 4000,
+			rgb: 0xFFFFFF,
+			hue: 0,
+			sat: 0,
+			flowing: false,
+			name: String::new(),
+			delayoff: 0,
+		}
+	}
+}
+
+impl MockState {
+	/// Get a property the way a real lamp reports it, i.e. as a string.
+	/// Returns None for properties the mock does not know.
+	pub fn prop(&self, name: &str) -> Option<String> {
+		let val = match name {
+			"power" => String::from(if self.power { "on" } else { "off" }),
+			"bright" => self.bright.to_string(),
+			"color_mode" => self.color_mode.to_prop().to_string(),
+			"ct" => self.ct.to_string(),
+			"rgb" => self.rgb.to_string(),
+			"hue" => self.hue.to_string(),
+			"sat" => self.sat.to_string(),
+			"flowing" => String::from(if self.flowing { "1" } else { "0" }),
+			"name" => self.name.clone(),
+			"delayoff" => self.delayoff.to_string(),
+			_ => return None,
+		};
+		Some(val)
+	}
+
+	/// Get the properties in MOCK_PROPS that differ between two states,
+	/// with their values in `self`.
+	pub fn changes(&self, old: &MockState) -> Map<String, Value> {
+		MOCK_PROPS
+			.into_iter()
+			.filter_map(|name| {
+				let val = self.prop(name)?;
+				(old.prop(name).as_ref() != Some(&val)).then(|| (name.to_string(), json!(val)))
+			})
+			.collect()
+	}
+
+	/// Run a method on the simulated lamp.
+	///
+	/// Returns the result values of the method, or the error message the lamp replies with.
+	/// The parameters are checked like a real lamp does: the values must be in range, and the
+	/// optional effect and duration must be `"smooth"`/`"sudden"` and at least 30 ms.
+	/// The state is left unchanged if the method fails.
+	pub fn execute(&mut self, method: &str, params: &[Value]) -> Result<Vec<Value>, String> {
+		let mut new = self.clone();
+		let res = new.apply(method, params)?;
+		*self = new;
+		Ok(res)
+	}
+
+	/// Run a method, possibly changing the state even if it fails.
+	fn apply(&mut self, method: &str, params: &[Value]) -> Result<Vec<Value>, String> {
+		match method {
+			"get_prop" => {
+				let names = params
+					.iter()
+					.map(|p| p.as_str().ok_or_else(invalid_params))
+					.collect::<Result<Vec<&str>, String>>()?;
+				// Unknown properties are returned as empty strings
+				return Ok(names
+					.into_iter()
+					.map(|name| json!(self.prop(name).unwrap_or_default()))
+					.collect());
+			},
+			"set_power" => {
+				check_transition(params, 1)?;
+				self.power = match params.first().and_then(Value::as_str) {
+					Some("on") => true,
+					Some("off") => false,
+					_ => return Err(invalid_params()),
+				};
+			},
+			"toggle" => self.power = !self.power,
+			"set_bright" => {
+				check_transition(params, 1)?;
+				self.bright = int_param(params, 0, 1, 100)? as u8;
+			},
+			"set_ct_abx" => {
+				check_transition(params, 1)?;
+				self.ct = int_param(params, 0, 1700, 6500)? as u16;
+				self.color_mode = ColorMode::Ct;
+			},
+			"set_rgb" => {
+				check_transition(params, 1)?;
+				self.rgb = int_param(params, 0, 0, 0xFFFFFF)? as u32;
+				self.color_mode = ColorMode::Rgb;
+			},
+			"set_hsv" => {
+				check_transition(params, 2)?;
+				self.hue = int_param(params, 0, 0, 359)? as u16;
+				self.sat = int_param(params, 1, 0, 100)? as u8;
+				self.color_mode = ColorMode::Hsv;
+			},
+			"start_cf" => {
+				int_param(params, 0, 0, i64::MAX)?;
+				int_param(params, 1, 0, 2)?;
+				let expr = params.get(2).and_then(Value::as_str);
+				if expr.is_none_or(|expr| expr.split(',').count() % 4 != 0) {
+					return Err(invalid_params());
+				}
+				self.flowing = true;
+			},
+			"stop_cf" => self.flowing = false,
+			"set_scene" => self.set_scene(params)?,
+			"cron_add" => {
+				int_param(params, 0, 0, 0)?;
+				self.delayoff = int_param(params, 1, 1, i64::from(u16::MAX))? as u16;
+			},
+			"cron_get" => {
+				int_param(params, 0, 0, 0)?;
+				if self.delayoff == 0 {
+					return Ok(Vec::new());
+				}
+				return Ok(vec![json!({"type": 0, "delay": self.delayoff, "mix": 0})]);
+			},
+			"cron_del" => {
+				int_param(params, 0, 0, 0)?;
+				self.delayoff = 0;
+			},
+			"set_name" => {
+				let name = params.first().and_then(Value::as_str);
+				self.name = name.ok_or_else(invalid_params)?.to_string();
+			},
+			_ => return Err(String::from("method not supported")),
+		}
+		Ok(vec![json!("ok")])
+	}
+
+	/// Run set_scene: turn the lamp on and apply the scene.
+	fn set_scene(&mut self, params: &[Value]) -> Result<(), String> {
+		let class = params.first().and_then(Value::as_str);
+		let bright = match class {
+			Some("color") => {
+				self.rgb = int_param(params, 1, 0, 0xFFFFFF)? as u32;
+				self.color_mode = ColorMode::Rgb;
+				int_param(params, 2, 1, 100)?
+			},
+			Some("hsv") => {
+				self.hue = int_param(params, 1, 0, 359)? as u16;
+				self.sat = int_param(params, 2, 0, 100)? as u8;
+				self.color_mode = ColorMode::Hsv;
+				int_param(params, 3, 1, 100)?
+			},
+			Some("ct") => {
+				self.ct = int_param(params, 1, 1700, 6500)? as u16;
+				self.color_mode = ColorMode::Ct;
+				int_param(params, 2, 1, 100)?
+			},
+			Some("auto_delay_off") => {
+				self.delayoff = int_param(params, 2, 1, i64::from(u16::MAX))? as u16;
+				int_param(params, 1, 1, 100)?
+			},
+			_ => return Err(invalid_params()),
+		};
+		self.bright = bright as u8;
+		self.power = true;
+		Ok(())
+	}
+}
+
+/// The error message for parameters the lamp does not accept.
+fn invalid_params() -> String {
+	String::from("invalid params")
+}
+
+/// Read an integer parameter and check that it is within `min..=max`.
+fn int_param(params: &[Value], idx: usize, min: i64, max: i64) -> Result<i64, String> {
+	params
+		.get(idx)
+		.and_then(Value::as_i64)
+		.filter(|val| (min..=max).contains(val))
+		.ok_or_else(invalid_params)
+}
+
+/// Check the optional effect and duration parameters starting at `idx`.
+fn check_transition(params: &[Value], idx: usize) -> Result<(), String> {
+	let Some(effect) = params.get(idx) else {
+		return Ok(());
+	};
+	let duration = params.get(idx + 1).and_then(Value::as_i64);
+	match (effect.as_str(), duration) {
+		(Some("sudden"), _) | (Some("smooth"), None) => Ok(()),
+		(Some("smooth"), Some(ms)) if ms >= 30 => Ok(()),
+		_ => Err(invalid_params()),
+	}
+}
+
+/// Ways in which a MockLamp misbehaves, set with MockLamp::set_faults().
+///
+/// The default is a well-behaved lamp. The counters (`quota_errors`, `malformed_replies`
+/// and `close_on_connect`) are used up by the mock: each affected request or connection
+/// decrements them, so a test can script a fixed number of failures followed by recovery.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+	/// Close a connection after answering this many of its requests.
+	pub drop_after: Option<usize>,
+	/// Wait this long before answering each request.
+	pub reply_delay: Duration,
+	/// Answer the next requests with invalid JSON instead of the real reply.
+	/// The requests are still executed.
+	pub malformed_replies: usize,
+	/// Answer the next requests with a "client quota exceeded" error without executing them.
+	pub quota_errors: usize,
+	/// Close the next connections as soon as they are accepted, before any request is read.
+	pub close_on_connect: usize,
+	/// Allow each connection at most this many requests per minute; the requests above
+	/// the limit get a "client quota exceeded" error. Use QUOTA_PER_MINUTE for the limit
+	/// of a real lamp.
+	pub quota: Option<usize>,
+}
+
+/// What the faults do to one request.
+#[derive(Debug)]
+struct Plan {
+	delay: Duration,
+	quota_error: bool,
+	malformed: bool,
+}
+
+/// Per-connection bookkeeping of a client thread.
+#[derive(Debug, Default)]
+struct Session {
+	answered: usize,
+	/// When the requests within the quota window arrived.
+	recent: VecDeque<Instant>,
+}
+
+impl Session {
+	/// Count an answered request. Returns false once the session reached drop_after.
+	fn count_answer(&mut self, drop_after: Option<usize>) -> bool {
+		self.answered += 1;
+		drop_after.is_none_or(|limit| self.answered < limit)
+	}
+}
+
+/// A connected client of a MockLamp.
+#[derive(Debug)]
+struct Client {
+	id: u64,
+	stream: TcpStream,
+}
+
+/// State shared between the threads of a MockLamp.
+#[derive(Debug)]
+struct Shared {
+	addr: SocketAddr,
+	state: Mutex<MockState>,
+	/// The clients, locked while writing so that lines are never interleaved.
+	clients: Mutex<Vec<Client>>,
+	requests: Mutex<Vec<Request>>,
+	faults: Mutex<Faults>,
+	next_client: AtomicU64,
+	stop: AtomicBool,
+}
+
+/// A fake Yeelight lamp listening on a local TCP port.
+///
+/// The mock speaks the same line-based JSON protocol as a real lamp, so a Lamp can be connected
+/// to MockLamp::addr() and used as usual. Every request is answered with a result or an error:
+/// ```json
+/// {"id":1,"result":["ok"]}
+/// {"id":2,"error":{"code":-1,"message":"method not supported"}}
+/// ```
+/// When a command changes the simulated state, a notification with the changed properties
+/// is sent to every connected client, after the result:
+/// ```json
+/// {"method":"props","params":{"power":"off"}}
+/// ```
+/// The server runs in background threads and is stopped when the MockLamp is dropped.
+/// To test how a client copes with a misbehaving lamp, see MockLamp::set_faults().
+///
+/// Example:
+/// ```
+/// use yeerugina::mock::{MockLamp, MockState};
+/// use yeerugina::lamp::Lamp;
+/// use yeerugina::structs::{Command, ConnectionSettings, Effect};
+/// use std::time::Duration;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let mock = MockLamp::start(MockState::default())?;
+/// let mut lamp = Lamp::new(
+///     String::from("mock"),
+///     mock.addr().to_string(),
+///     Effect::default(),
+///     Duration::from_millis(500),
+/// )
+/// .expect("the address of a MockLamp is valid");
+/// lamp.connect(ConnectionSettings {
+///     read_timeout: Some(Duration::from_secs(1)),
+///     write_timeout: None,
+///     conn_timeout: Duration::from_secs(1),
+///     conn_tries: 1,
+///     conn_wait: Duration::from_millis(100),
+/// })?;
+/// let mut reader = lamp.reader()?;
+/// lamp.send_cmd(Command::SetPower(false))?;
+/// let reply = reader.read_line()?.expect("the mock answers at once");
+/// assert_eq!(reply, b"{\"id\":0,\"result\":[\"ok\"]}\r\n");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MockLamp {
+	shared: Arc<Shared>,
+	acceptor: Option<JoinHandle<()>>,
+}
+
+impl MockLamp {
+	/// Start a mock lamp on a free port of 127.0.0.1.
+	pub fn start(state: MockState) -> io::Result<Self> {
+		Self::bind(SocketAddr::from(([127, 0, 0, 1], 0)), state)
+	}
+
+	/// Start a mock lamp on the given address.
+	/// Use port 55443 to listen where real lamps do.
+	pub fn bind(addr: SocketAddr, state: MockState) -> io::Result<Self> {
+		let listener = TcpListener::bind(addr)?;
+		let shared = Arc::new(Shared {
+			addr: listener.local_addr()?,
+			state: Mutex::new(state),
+			clients: Mutex::new(Vec::new()),
+			requests: Mutex::new(Vec::new()),
+			faults: Mutex::new(Faults::default()),
+			next_client: AtomicU64::new(0),
+			stop: AtomicBool::new(false),
+		});
+		info!("mock {} | Listening", shared.addr);
+		let acceptor = {
+			let shared = Arc::clone(&shared);
+			thread::spawn(move || shared.accept(listener))
+		};
+		Ok(Self {
+			shared,
+			acceptor: Some(acceptor),
+		})
+	}
+
+	/// Get the address the mock listens on.
+	pub fn addr(&self) -> SocketAddr {
+		self.shared.addr
+	}
+
+	/// Get the current simulated state.
+	pub fn state(&self) -> MockState {
+		lock(&self.shared.state).clone()
+	}
+
+	/// Change the simulated state, as if the lamp was controlled by someone else.
+	/// The changed properties are sent to the clients as a notification.
+	pub fn set_state(&self, state: MockState) {
+		let changes = {
+			let mut current = lock(&self.shared.state);
+			let changes = state.changes(&current);
+			*current = state;
+			changes
+		};
+		self.shared.notify(changes);
+	}
+
+	/// Get every request received so far, in the order it arrived.
+	/// Lines that were not valid requests are left out.
+	pub fn requests(&self) -> Vec<Request> {
+		lock(&self.shared.requests).clone()
+	}
+
+	/// Get the faults currently in effect, including what is left of their counters.
+	pub fn faults(&self) -> Faults {
+		lock(&self.shared.faults).clone()
+	}
+
+	/// Make the mock misbehave. Replaces the previous faults.
+	/// Takes effect from the next connection or request on.
+	pub fn set_faults(&self, faults: Faults) {
+		debug!("mock {} | Setting faults {faults:?}", self.shared.addr);
+		*lock(&self.shared.faults) = faults;
+	}
+
+	/// Get the number of connected clients.
+	pub fn client_count(&self) -> usize {
+		lock(&self.shared.clients).len()
+	}
+
+	/// Close the connections of all clients, as a lamp does when it loses power.
+	/// The mock keeps listening, so the clients can reconnect.
+	pub fn disconnect_all(&self) {
+		for client in lock(&self.shared.clients).drain(..) {
+			shutdown(&client.stream);
+		}
+	}
+
+	/// Stop listening and close the connections of all clients.
+	pub fn stop(&mut self) {
+		let Some(acceptor) = self.acceptor.take() else {
+			return;
+		};
+		info!("mock {} | Stopping", self.shared.addr);
+		self.shared.stop.store(true, Ordering::SeqCst);
+		// Wake up the acceptor thread, which then sees the stop flag
+		if let Err(e) = TcpStream::connect(self.shared.addr) {
+			warn!(
+				"mock {} | Could not wake up the listener: {e}",
+				self.shared.addr
+			);
+		}
+		self.disconnect_all();
+		if acceptor.join().is_err() {
+			warn!("mock {} | Listener thread panicked", self.shared.addr);
+		}
+	}
+}
+
+impl Drop for MockLamp {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+impl Shared {
+	/// Accept clients until the mock is stopped.
+	fn accept(self: Arc<Self>, listener: TcpListener) {
+		for conn in listener.incoming() {
+			if self.stop.load(Ordering::SeqCst) {
+				break;
+			}
+			let stream = match conn {
+				Ok(stream) => stream,
+				Err(e) => {
+					warn!("mock {} | Could not accept client: {e}", self.addr);
+					continue;
+				},
+			};
+			let id = self.next_client.fetch_add(1, Ordering::SeqCst);
+			if take(&mut lock(&self.faults).close_on_connect) {
+				debug!("mock {} | Closing client {id} on connect", self.addr);
+				shutdown(&stream);
+				continue;
+			}
+			let reader = match stream.try_clone() {
+				Ok(reader) => reader,
+				Err(e) => {
+					warn!("mock {} | Could not clone client stream: {e}", self.addr);
+					continue;
+				},
+			};
+			debug!("mock {} | Client {id} connected", self.addr);
+			lock(&self.clients).push(Client { id, stream });
+			let shared = Arc::clone(&self);
+			thread::spawn(move || shared.serve(id, reader));
+		}
+		debug!("mock {} | Listener stopped", self.addr);
+	}
+
+	/// Answer the requests of a client until it disconnects.
+	fn serve(&self, id: u64, stream: TcpStream) {
+		let mut session = Session::default();
+		for line in BufReader::new(stream).split(b'\n') {
+			let Ok(line) = line else {
+				break;
+			};
+			let line = line.strip_suffix(b"\r").unwrap_or(&line);
+			if line.is_empty() {
+				continue;
+			}
+			trace!(
+				"mock {} | Client {id} sent {}",
+				self.addr,
+				String::from_utf8_lossy(line)
+			);
+			if !self.handle(id, line, &mut session) {
+				debug!("mock {} | Dropping client {id}", self.addr);
+				break;
+			}
+		}
+		debug!("mock {} | Client {id} disconnected", self.addr);
+		let mut clients = lock(&self.clients);
+		if let Some(idx) = clients.iter().position(|client| client.id == id) {
+			shutdown(&clients.swap_remove(idx).stream);
+		}
+	}
+
+	/// Run a request and send the reply, followed by a notification if the state changed.
+	/// Returns false if the connection should be closed.
+	fn handle(&self, client: u64, line: &[u8], session: &mut Session) -> bool {
+		let req: Request = match serde_json::from_slice(line) {
+			Ok(req) => req,
+			Err(e) => {
+				warn!("mock {} | Ignoring invalid request: {e}", self.addr);
+				return true;
+			},
+		};
+		lock(&self.requests).push(req.clone());
+		let req_id = req.id;
+		let (plan, drop_after) = self.plan(session);
+		if !plan.delay.is_zero() {
+			thread::sleep(plan.delay);
+		}
+		if plan.quota_error {
+			debug!("mock {} | Client {client} is over its quota", self.addr);
+			let reply = error_reply(req_id, "client quota exceeded");
+			self.send_to(client, &reply);
+			return session.count_answer(drop_after);
+		}
+		let (res, changes) = {
+			let mut state = lock(&self.state);
+			let old = state.clone();
+			let res = state.execute(&req.method, &req.params);
+			(res, state.changes(&old))
+		};
+		let reply = match res {
+			Ok(values) => json!({"id": req_id, "result": values}).to_string(),
+			Err(message) => error_reply(req_id, &message),
+		};
+		if plan.malformed {
+			// Cut the reply in half, as if the lamp stopped writing mid-line
+			debug!("mock {} | Sending a malformed reply to {req_id}", self.addr);
+			let half = reply.floor_char_boundary(reply.len() / 2);
+			self.send_to(client, &reply[..half]);
+		} else {
+			self.send_to(client, &reply);
+		}
+		self.notify(changes);
+		session.count_answer(drop_after)
+	}
+
+	/// Decide what the faults do to the next request of a session.
+	/// Returns the plan and the drop_after limit in effect.
+	fn plan(&self, session: &mut Session) -> (Plan, Option<usize>) {
+		let mut faults = lock(&self.faults);
+		let now = Instant::now();
+		while session
+			.recent
+			.front()
+			.is_some_and(|at| now.duration_since(*at) >= QUOTA_WINDOW)
+		{
+			session.recent.pop_front();
+		}
+		let over_quota = faults
+			.quota
+			.is_some_and(|quota| session.recent.len() >= quota);
+		let quota_error = take(&mut faults.quota_errors) || over_quota;
+		if !quota_error {
+			session.recent.push_back(now);
+		}
+		let plan = Plan {
+			delay: faults.reply_delay,
+			quota_error,
+			malformed: !quota_error && take(&mut faults.malformed_replies),
+		};
+		(plan, faults.drop_after)
+	}
+
+	/// Send a line to one client.
+	fn send_to(&self, client: u64, line: &str) {
+		let mut clients = lock(&self.clients);
+		if let Some(client) = clients.iter_mut().find(|c| c.id == client) {
+			write_line(self.addr, client, line);
+		}
+	}
+
+	/// Send a notification with the changed properties to every client.
+	/// Nothing is sent if nothing changed.
+	fn notify(&self, changes: Map<String, Value>) {
+		if changes.is_empty() {
+			return;
+		}
+		let line = json!({"method": "props", "params": changes}).to_string();
+		for client in lock(&self.clients).iter_mut() {
+			write_line(self.addr, client, &line);
+		}
+	}
+}
+
+/// Create the reply to a failed request.
+/// Written by hand to keep the ID first, like a real lamp does.
+fn error_reply(id: u64, message: &str) -> String {
+	format!(
+		r#"{{"id":{id},"error":{{"code":-1,"message":{}}}}}"#,
+		json!(message)
+	)
+}
+
+/// Decrement a fault counter. Returns true if it was not zero yet.
+fn take(counter: &mut usize) -> bool {
+	let nonzero = *counter > 0;
+	*counter = counter.saturating_sub(1);
+	nonzero
+}
+
+/// Write a line the way a lamp does, i.e. terminated by CRLF.
+/// A failed write closes the connection.
+fn write_line(addr: SocketAddr, client: &mut Client, line: &str) {
+	trace!("mock {addr} | Sending {line} to client {}", client.id);
+	let bytes = format!("{line}\r\n");
+	if let Err(e) = client.stream.write_all(bytes.as_bytes()) {
+		debug!("mock {addr} | Could not write to client {}: {e}", client.id);
+		shutdown(&client.stream);
+	}
+}
+
+/// Close a client connection, ignoring errors from connections that are already closed.
+fn shutdown(stream: &TcpStream) {
+	let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Lock a mutex of the mock. A panic in another thread of the mock does not stop the others.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
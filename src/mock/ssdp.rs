@@ -0,0 +1,286 @@
+use super::{MockLamp, MockState};
+use crate::discovery::{MULTICAST_ADDR, SEARCH_TARGET};
+use log::{debug, info, trace, warn};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the responder thread checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Methods listed in the `support` header by default, those of a real color bulb.
+/// MockLamp does not implement all of them; see MockState::execute().
+const COLOR_SUPPORT: [&str; 20] = [
+	"get_prop",
+	"set_default",
+	"set_power",
+	"toggle",
+	"set_bright",
+	"start_cf",
+	"stop_cf",
+	"set_scene",
+	"cron_add",
+	"cron_get",
+	"cron_del",
+	"set_ct_abx",
+	"set_rgb",
+	"set_hsv",
+	"set_adjust",
+	"adjust_bright",
+	"adjust_ct",
+	"adjust_color",
+	"set_music",
+	"set_name",
+];
+
+/// A simulated lamp as announced by MockSsdp.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Advert {
+	/// Unique ID of the lamp, sent as a 16 digit hex number.
+	pub id: u64,
+	/// Address the lamp accepts commands on, sent in the `Location` header.
+	pub location: SocketAddr,
+	/// Model of the lamp, e.g. `color`, `mono` or `stripe`.
+	pub model: String,
+	/// Firmware version.
+	pub fw_ver: u32,
+	/// Methods supported by the lamp.
+	pub support: Vec<String>,
+	/// State reported in the response.
+	pub state: MockState,
+}
+
+impl Advert {
+	/// Create the advert of a color bulb at the given address.
+	pub fn new(id: u64, location: SocketAddr, state: MockState) -> Self {
+		Self {
+			id,
+			location,
+			model: String::from("color"),
+			fw_ver: 18,
+			support: COLOR_SUPPORT.iter().map(|s| s.to_string()).collect(),
+			state,
+		}
+	}
+
+	/// Create the advert of a running MockLamp, with its current state.
+	pub fn from_mock(id: u64, mock: &MockLamp) -> Self {
+		Self::new(id, mock.addr(), mock.state())
+	}
+
+	/// Create the discovery response of the lamp, as sent by a real one.
+	pub fn response(&self) -> String {
+		let state = &self.state;
+		// MockState::prop() knows every property used here
+		let prop = |name| state.prop(name).unwrap_or_default();
+		format!(
+			concat!(
+				"HTTP/1.1 200 OK\r\n",
+				"Cache-Control: max-age=3600\r\n",
+				"Date: \r\n",
+				"Ext: \r\n",
+				"Location: yeelight://{}\r\n",
+				"Server: POSIX UPnP/1.0 YGLC/1\r\n",
+				"id: 0x{:016x}\r\n",
+				"model: {}\r\n",
+				"fw_ver: {}\r\n",
+				"support: {}\r\n",
+				"power: {}\r\n",
+				"bright: {}\r\n",
+				"color_mode: {}\r\n",
+				"ct: {}\r\n",
+				"rgb: {}\r\n",
+				"hue: {}\r\n",
+				"sat: {}\r\n",
+				"name: {}\r\n",
+			),
+			self.location,
+			self.id,
+			self.model,
+			self.fw_ver,
+			self.support.join(" "),
+			prop("power"),
+			prop("bright"),
+			prop("color_mode"),
+			prop("ct"),
+			prop("rgb"),
+			prop("hue"),
+			prop("sat"),
+			prop("name"),
+		)
+	}
+}
+
+/// Returns true if a datagram is a discovery request for Yeelight lamps.
+///
+/// A request looks like this (header names are case-insensitive):
+/// ```text
+/// M-SEARCH * HTTP/1.1
+/// HOST: 239.255.255.250:1982
+/// MAN: "ssdp:discover"
+/// ST: wifi_bulb
+/// ```
+pub fn is_search_request(datagram: &str) -> bool {
+	let mut lines = datagram.lines();
+	let Some(request_line) = lines.next() else {
+		return false;
+	};
+	if !request_line.trim().starts_with("M-SEARCH ") {
+		return false;
+	}
+	let headers: Vec<(String, &str)> = lines
+		.filter_map(|line| line.split_once(':'))
+		.map(|(name, val)| (name.trim().to_ascii_lowercase(), val.trim()))
+		.collect();
+	let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+	header("man") == Some("\"ssdp:discover\"") && header("st") == Some(SEARCH_TARGET)
+}
+
+/// State shared with the responder thread.
+#[derive(Debug)]
+struct Shared {
+	addr: SocketAddr,
+	adverts: Mutex<Vec<Advert>>,
+	stop: AtomicBool,
+}
+
+/// A stand-in for the discovery responders of real lamps.
+///
+/// The responder answers every M-SEARCH request for `wifi_bulb` it receives with one
+/// datagram per simulated lamp, sent back to the address of the request. Unlike real lamps,
+/// it listens on an ordinary UDP address, so discovery can be tested on the loopback
+/// interface; MockSsdp::join_multicast() makes it reachable by real discovery requests.
+///
+/// The responder runs in a background thread and is stopped when the MockSsdp is dropped.
+///
+/// Example:
+/// ```
+/// use yeerugina::mock::{MockLamp, MockState};
+/// use yeerugina::mock::ssdp::{Advert, MockSsdp};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let lamp = MockLamp::start(MockState::default())?;
+/// let ssdp = MockSsdp::start(vec![Advert::from_mock(1, &lamp)])?;
+/// // Send M-SEARCH requests to ssdp.addr()
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MockSsdp {
+	shared: Arc<Shared>,
+	socket: UdpSocket,
+	responder: Option<JoinHandle<()>>,
+}
+
+impl MockSsdp {
+	/// Start a responder on a free port of 127.0.0.1.
+	pub fn start(adverts: Vec<Advert>) -> io::Result<Self> {
+		Self::bind(SocketAddr::from(([127, 0, 0, 1], 0)), adverts)
+	}
+
+	/// Start a responder on the given address.
+	pub fn bind(addr: SocketAddr, adverts: Vec<Advert>) -> io::Result<Self> {
+		let socket = UdpSocket::bind(addr)?;
+		socket.set_read_timeout(Some(POLL_INTERVAL))?;
+		let shared = Arc::new(Shared {
+			addr: socket.local_addr()?,
+			adverts: Mutex::new(adverts),
+			stop: AtomicBool::new(false),
+		});
+		info!("ssdp {} | Listening", shared.addr);
+		let responder = {
+			let shared = Arc::clone(&shared);
+			let socket = socket.try_clone()?;
+			thread::spawn(move || shared.respond(socket))
+		};
+		Ok(Self {
+			shared,
+			socket,
+			responder: Some(responder),
+		})
+	}
+
+	/// Get the address the responder listens on.
+	pub fn addr(&self) -> SocketAddr {
+		self.shared.addr
+	}
+
+	/// Also listen on the multicast group of real lamps, on the given interface.
+	/// The responder should be bound to port discovery::SSDP_PORT for real requests to reach it.
+	pub fn join_multicast(&self, interface: Ipv4Addr) -> io::Result<()> {
+		self.socket.join_multicast_v4(&MULTICAST_ADDR, &interface)
+	}
+
+	/// Get the simulated lamps.
+	pub fn adverts(&self) -> Vec<Advert> {
+		super::lock(&self.shared.adverts).clone()
+	}
+
+	/// Replace the simulated lamps, e.g. to make a lamp appear or disappear.
+	pub fn set_adverts(&self, adverts: Vec<Advert>) {
+		*super::lock(&self.shared.adverts) = adverts;
+	}
+
+	/// Stop answering requests.
+	pub fn stop(&mut self) {
+		let Some(responder) = self.responder.take() else {
+			return;
+		};
+		info!("ssdp {} | Stopping", self.shared.addr);
+		self.shared.stop.store(true, Ordering::SeqCst);
+		if responder.join().is_err() {
+			warn!("ssdp {} | Responder thread panicked", self.shared.addr);
+		}
+	}
+}
+
+impl Drop for MockSsdp {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+impl Shared {
+	/// Answer requests until the responder is stopped.
+	fn respond(&self, socket: UdpSocket) {
+		let mut buf = [0u8; 2048];
+		while !self.stop.load(Ordering::SeqCst) {
+			let (len, from) = match socket.recv_from(&mut buf) {
+				Ok(received) => received,
+				Err(e)
+					if matches!(
+						e.kind(),
+						io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+					) =>
+				{
+					continue;
+				},
+				Err(e) => {
+					warn!("ssdp {} | Could not receive: {e}", self.addr);
+					continue;
+				},
+			};
+			let datagram = String::from_utf8_lossy(&buf[..len]);
+			trace!("ssdp {} | Received from {from}: {datagram:?}", self.addr);
+			if !is_search_request(&datagram) {
+				debug!("ssdp {} | Ignoring datagram from {from}", self.addr);
+				continue;
+			}
+			let adverts = super::lock(&self.adverts).clone();
+			debug!(
+				"ssdp {} | Answering {from} with {} lamps",
+				self.addr,
+				adverts.len()
+			);
+			for advert in adverts {
+				if let Err(e) = socket.send_to(advert.response().as_bytes(), from) {
+					warn!("ssdp {} | Could not answer {from}: {e}", self.addr);
+				}
+			}
+		}
+		debug!("ssdp {} | Responder stopped", self.addr);
+	}
+}
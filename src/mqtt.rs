@@ -1,24 +1,594 @@
-use crate::structs::{Command, Effect};
+use crate::structs::{Command, Effect, FlowAction, MIN_DURATION, Transition};
+use serde_json::{Map, Value};
+use std::str::FromStr;
+use std::time::Duration;
 
 #[cfg(feature = "mqtt")]
 use paho_mqtt::PropertyCode::*;
 #[cfg(feature = "mqtt")]
-use paho_mqtt::{Message, Properties, properties};
+use paho_mqtt::{Properties, properties};
 
-/// Parse a paho_mqtt::Message to a Command.
-/// Returns either the command or a failure message as a String.
-pub fn parse_mqtt_command(msg: String) -> Result<Command, String> {
-	todo!()
+/// Version of the JSON command schema understood by parse_mqtt_command().
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Commands received in one MQTT message, together with the transition requested for them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttCommand {
+	/// The commands to send to the lamp(s), in order.
+	/// Most messages contain a single command; Home Assistant may send several changes at once.
+	pub cmds: Vec<Command>,
+	/// Effect and duration overrides; None fields use the lamp's defaults.
+	pub transition: Transition,
+}
+
+/// Parse the payload of an MQTT message to Commands.
+/// Returns either the commands or a failure message as a String.
+///
+/// Payloads starting with `{` are parsed as JSON, anything else with the text syntax
+/// described below.
+///
+/// A JSON payload is an object. The "cmd" field selects the command,
+/// and the other fields depend on it:
+///
+/// | cmd          | fields                                              |
+/// |--------------|-----------------------------------------------------|
+/// | `toggle`     | -                                                   |
+/// | `set_power`  | `power`: `"on"`, `"off"`, true or false             |
+/// | `set_bright` | `bright`: 1-100                                     |
+/// | `set_rgb`    | `rgb`: `"#rrggbb"`, `"rrggbb"` or an integer        |
+/// | `set_ct_abx` | `ct`: 1700-6500 (Kelvin)                            |
+/// | `set_hsv`    | `hue`: 0-359, `sat`: 0-100                          |
+/// | `get_prop`   | `props`: a non-empty array of property names        |
+/// | `start_cf`   | `flow`: a flow expression (see Command::new_start_cf()), |
+/// |              | `count`: optional, 0 (forever) by default,          |
+/// |              | `action`: optional, `"recover"`, `"stay"` or `"off"` |
+/// | `stop_cf`    | -                                                   |
+/// | `raw`        | `method`: any method name,                          |
+/// |              | `params`: optional array of parameters, sent as they are |
+///
+/// Every command also accepts the optional fields
+/// - `effect`: `"sudden"` or `"smooth"`,
+/// - `duration`: a humantime string (`"500ms"`, `"2s"`) or an integer in milliseconds,
+/// - `v`: the schema version (currently 1).
+///
+/// If `effect` or `duration` is left out, the lamp's defaults are used.
+/// `raw` accepts neither, since its parameters are sent as they are.
+/// Unknown fields are rejected, and every error names the field that caused it.
+///
+/// A JSON object without a "cmd" field is read as a command of the Home Assistant
+/// JSON light schema: `state` (`"ON"`/`"OFF"`), `brightness` (1-100), `color`
+/// (`{"r","g","b"}` or `{"h","s"}`), `color_temp` (mireds), `transition` (seconds)
+/// and `effect`. Such a command may result in several Commands.
+///
+/// The text syntax is meant for simple clients that cannot build JSON.
+/// It consists of the command name, its arguments and optionally an effect
+/// and/or a duration, separated by whitespace:
+/// ```text
+/// toggle
+/// power on
+/// bright 40
+/// rgb ff8800 sudden
+/// ct 2700 2s
+/// hsv 120 80 smooth 500ms
+/// get power bright ct
+/// flow 0 recover 1000,2,2700,100,500,1,255,10
+/// stop_cf
+/// raw set_name kitchen
+/// raw set_adjust increase bright
+/// ```
+/// `raw` sends any method; each parameter is read as JSON, or else as a string.
+/// The full command names (`set_bright`, `set_rgb`...) are accepted as well.
+///
+/// Example:
+/// ```
+/// use yeerugina::mqtt::parse_mqtt_command;
+/// use yeerugina::structs::Command;
+///
+/// # fn main() -> Result<(), String> {
+/// let parsed = parse_mqtt_command(String::from(
+///     r##"{"cmd":"set_rgb","rgb":"#ff8800","effect":"smooth","duration":"500ms"}"##,
+/// ))?;
+/// assert_eq!(parsed.cmds, vec![Command::SetRgb(0xff8800)]);
+/// let parsed = parse_mqtt_command(String::from("rgb ff8800 smooth 500ms"))?;
+/// assert_eq!(parsed.cmds, vec![Command::SetRgb(0xff8800)]);
+/// let parsed = parse_mqtt_command(String::from(r#"{"state":"ON","brightness":40}"#))?;
+/// assert_eq!(parsed.cmds, vec![Command::SetPower(true), Command::SetBright(40)]);
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_mqtt_command(msg: String) -> Result<MqttCommand, String> {
+	if !msg.trim_start().starts_with('{') {
+		return parse_text_command(&msg);
+	}
+	let value: Value = serde_json::from_str(&msg).map_err(|e| format!("Invalid JSON: {e}"))?;
+	let Value::Object(obj) = value else {
+		return Err(String::from("Command must be a JSON object"));
+	};
+	if obj.contains_key("cmd") {
+		parse_json_command(JsonFields(obj))
+	} else {
+		parse_ha_command(JsonFields(obj))
+	}
+}
+
+/// Build an MqttCommand from the fields of a JSON object.
+fn parse_json_command(mut fields: JsonFields) -> Result<MqttCommand, String> {
+	if let Some(ver) = fields.take("v", as_u64)?
+		&& ver != SCHEMA_VERSION
+	{
+		return Err(format!(
+			"field \"v\": unsupported schema version {ver}; expected {SCHEMA_VERSION}"
+		));
+	}
+	let name = fields.require("cmd", as_str)?;
+	// EnumString fills the variant with default values, which are replaced below
+	let variant = Command::from_str(&name)
+		.map_err(|_| format!("field \"cmd\": unknown command \"{name}\""))?;
+	let cmd = match variant {
+		Command::Toggle => Command::Toggle,
+		Command::SetPower(_) => Command::SetPower(fields.require("power", as_power)?),
+		Command::SetBright(_) => {
+			let bright = fields.require("bright", as_usize)?;
+			Command::new_bright(bright).map_err(|e| field_err("bright", e))?
+		},
+		Command::SetRgb(_) => {
+			let rgb = fields.require("rgb", as_rgb)?;
+			Command::new_rgb(rgb).map_err(|e| field_err("rgb", e))?
+		},
+		Command::SetCtAbx(_) => {
+			let ct = fields.require("ct", as_usize)?;
+			Command::new_ct_abx(ct).map_err(|e| field_err("ct", e))?
+		},
+		Command::SetHsv(_, _) => {
+			let hue = fields.require("hue", as_usize)?;
+			let sat = fields.require("sat", as_usize)?;
+			// new_hsv() checks the hue first
+			let field = if Command::new_hsv(hue, 0).is_err() {
+				"hue"
+			} else {
+				"sat"
+			};
+			Command::new_hsv(hue, sat).map_err(|e| field_err(field, e))?
+		},
+		Command::GetProp(_) => {
+			let props = fields.require("props", as_str_vec)?;
+			Command::new_get_prop(props).map_err(|e| field_err("props", e))?
+		},
+		Command::StartCf(_, _, _) => {
+			let expr = fields.require("flow", as_str)?;
+			let count = fields.take("count", as_usize)?.unwrap_or_default();
+			let action = fields.take("action", as_flow_action)?.unwrap_or_default();
+			Command::new_start_cf(count, action, &expr).map_err(|e| field_err("flow", e))?
+		},
+		Command::StopCf => Command::StopCf,
+		Command::Raw { .. } => {
+			let method = fields.require("method", as_str)?;
+			let params = fields.take("params", as_array)?.unwrap_or_default();
+			let cmd = Command::new_raw(method, params).map_err(|e| field_err("method", e))?;
+			// The parameters are sent as they are, so effect and duration are unknown fields
+			fields.finish()?;
+			return Ok(MqttCommand {
+				cmds: vec![cmd],
+				transition: Transition::default(),
+			});
+		},
+		Command::SetOpaqueColor(_) => {
+			return Err(format!(
+				"field \"cmd\": command \"{name}\" is not supported over MQTT"
+			));
+		},
+	};
+	let transition = Transition {
+		effect: fields.take("effect", as_effect)?,
+		duration: fields.take("duration", as_duration)?,
+	};
+	check_transition(&transition).map_err(|e| field_err("duration", e))?;
+	fields.finish()?;
+	Ok(MqttCommand {
+		cmds: vec![cmd],
+		transition,
+	})
+}
+
+/// Build an MqttCommand from a command in the Home Assistant JSON light schema.
+///
+/// The lamp is turned on first, then the color and the brightness are set.
+fn parse_ha_command(mut fields: JsonFields) -> Result<MqttCommand, String> {
+	let mut cmds = Vec::new();
+	match fields.take("state", as_str)?.as_deref() {
+		Some("ON") => cmds.push(Command::SetPower(true)),
+		Some("OFF") => cmds.push(Command::SetPower(false)),
+		Some(other) => {
+			return Err(field_err(
+				"state",
+				format!("expected \"ON\" or \"OFF\", got \"{other}\""),
+			));
+		},
+		None => {},
+	}
+	if let Some(color) = fields.take("color", as_ha_color)? {
+		cmds.push(color);
+	}
+	if let Some(mireds) = fields.take("color_temp", as_u64)? {
+		if mireds == 0 {
+			return Err(field_err("color_temp", "must be greater than zero"));
+		}
+		// The lamp accepts 1700-6500 K; Home Assistant is told the same range
+		let kelvin = (1_000_000 / mireds).clamp(1700, 6500) as usize;
+		cmds.push(Command::SetCtAbx(kelvin));
+	}
+	if let Some(bright) = fields.take("brightness", as_usize)? {
+		// Home Assistant may send 0 to mean "as dark as possible"
+		cmds.push(Command::new_bright(bright.max(1)).map_err(|e| field_err("brightness", e))?);
+	}
+	let transition = Transition {
+		effect: fields.take("effect", as_effect)?,
+		duration: fields.take("transition", as_seconds)?,
+	};
+	check_transition(&transition).map_err(|e| field_err("transition", e))?;
+	fields.finish()?;
+	if cmds.is_empty() {
+		return Err(String::from(
+			"Command has no \"cmd\", \"state\", \"color\", \"color_temp\" or \"brightness\" field",
+		));
+	}
+	Ok(MqttCommand { cmds, transition })
+}
+
+/// Build an MqttCommand from the text syntax.
+fn parse_text_command(msg: &str) -> Result<MqttCommand, String> {
+	let mut words = msg.split_whitespace();
+	let name = words.next().ok_or("Empty command")?;
+	// EnumString fills the variant with default values, which are replaced below
+	let variant = Command::from_str(name).map_err(|_| format!("Unknown command \"{name}\""))?;
+	let mut args = TextArgs { name, words };
+	let cmd = match variant {
+		Command::Toggle => Command::Toggle,
+		Command::SetPower(_) => Command::SetPower(args.next("power", parse_power_word)?),
+		Command::SetBright(_) => {
+			let bright = args.next("brightness", parse_usize)?;
+			Command::new_bright(bright).map_err(|e| args.err(e))?
+		},
+		Command::SetRgb(_) => {
+			let rgb = args.next("color", parse_hex_rgb)?;
+			Command::new_rgb(rgb).map_err(|e| args.err(e))?
+		},
+		Command::SetCtAbx(_) => {
+			let ct = args.next("color temperature", parse_usize)?;
+			Command::new_ct_abx(ct).map_err(|e| args.err(e))?
+		},
+		Command::SetHsv(_, _) => {
+			let hue = args.next("hue", parse_usize)?;
+			let sat = args.next("saturation", parse_usize)?;
+			Command::new_hsv(hue, sat).map_err(|e| args.err(e))?
+		},
+		Command::GetProp(_) => {
+			// Every remaining word is a property, so there is no transition
+			let props = args.words.map(String::from).collect();
+			let cmd = Command::new_get_prop(props).map_err(|e| format!("{name}: {e}"))?;
+			return Ok(MqttCommand {
+				cmds: vec![cmd],
+				transition: Transition::default(),
+			});
+		},
+		Command::StartCf(_, _, _) => {
+			let count = args.next("count", parse_usize)?;
+			let action = args.next("action", |s| {
+				FlowAction::from_str(s).map_err(|_| FLOW_ACTION_ERR.to_string())
+			})?;
+			let expr = args.next("flow expression", |s| Ok(s.to_string()))?;
+			Command::new_start_cf(count, action, &expr).map_err(|e| args.err(e))?
+		},
+		Command::StopCf => Command::StopCf,
+		Command::Raw { .. } => {
+			let method = args.next("method", |s| Ok(s.to_string()))?;
+			// Every remaining word is a parameter, so there is no transition
+			let params = args.words.map(parse_raw_param).collect();
+			let cmd = Command::new_raw(method, params).map_err(|e| format!("{name}: {e}"))?;
+			return Ok(MqttCommand {
+				cmds: vec![cmd],
+				transition: Transition::default(),
+			});
+		},
+		Command::SetOpaqueColor(_) => {
+			return Err(format!("{name}: command is not supported over MQTT"));
+		},
+	};
+	let transition = args.transition()?;
+	Ok(MqttCommand {
+		cmds: vec![cmd],
+		transition,
+	})
+}
+
+/// Properties that can be set in the per-property topic mode,
+/// together with the text command their payloads are passed to.
+pub const PROPERTIES: [(&str, &str); 6] = [
+	("power", "power"),
+	("brightness", "bright"),
+	("rgb", "rgb"),
+	("ct", "ct"),
+	("hsv", "hsv"),
+	("flow", "flow"),
+];
+
+/// Parse the payload of a per-property topic (`<topic>/<lamp>/<property>/set`) to a Command.
+///
+/// The payload holds only the arguments of the matching text command (see parse_mqtt_command()),
+/// so an effect and a duration may follow the value:
+///
+/// | property     | payload examples                                 |
+/// |--------------|--------------------------------------------------|
+/// | `power`      | `on`, `OFF`, `toggle`, `on smooth 1s`            |
+/// | `brightness` | `40`, `40 sudden`                                |
+/// | `rgb`        | `ff8800`, `#ff8800 500ms`                        |
+/// | `ct`         | `2700`, `2700 2s`                                |
+/// | `hsv`        | `120 80`, `120,80`                               |
+/// | `flow`       | `0 recover 1000,2,2700,100,500,1,255,10`, `stop` |
+pub fn parse_property_command(property: &str, payload: &str) -> Result<MqttCommand, String> {
+	let Some((_, name)) = PROPERTIES.iter().find(|(prop, _)| *prop == property) else {
+		return Err(format!("Unknown property \"{property}\""));
+	};
+	let payload = payload.trim();
+	let text = match (property, payload.to_lowercase().as_str()) {
+		("power", "toggle") => String::from("toggle"),
+		("power", lower) => format!("power {lower}"),
+		("flow", "stop") => String::from("stop_cf"),
+		("hsv", _) => format!("hsv {}", payload.replacen(',', " ", 1)),
+		_ => format!("{name} {payload}"),
+	};
+	parse_text_command(&text).map_err(|e| format!("Invalid payload for {property}: {e}"))
+}
+
+/// The arguments of a text command, consumed one word at a time.
+struct TextArgs<'a> {
+	name: &'a str,
+	words: std::str::SplitWhitespace<'a>,
+}
+
+impl TextArgs<'_> {
+	/// Convert the next word, failing if there are no words left.
+	fn next<T>(
+		&mut self, what: &str, conv: impl FnOnce(&str) -> Result<T, String>,
+	) -> Result<T, String> {
+		let word = self
+			.words
+			.next()
+			.ok_or_else(|| self.err(format!("missing {what}")))?;
+		conv(word).map_err(|e| self.err(format!("invalid {what} \"{word}\": {e}")))
+	}
+
+	/// Parse the remaining words as an effect and/or a duration, in any order.
+	fn transition(mut self) -> Result<Transition, String> {
+		let mut trans = Transition::default();
+		while let Some(word) = self.words.next() {
+			if let Ok(effect) = Effect::from_str(word)
+				&& trans.effect.is_none()
+			{
+				trans.effect = Some(effect);
+			} else if let Ok(dur) = humantime::parse_duration(word)
+				&& trans.duration.is_none()
+			{
+				trans.duration = Some(dur);
+			} else {
+				return Err(self.err(format!(
+					"unexpected argument \"{word}\"; expected an effect or a duration"
+				)));
+			}
+		}
+		check_transition(&trans).map_err(|e| self.err(e))?;
+		Ok(trans)
+	}
+
+	/// Format an error message for this command.
+	fn err(&self, msg: impl std::fmt::Display) -> String {
+		format!("{}: {msg}", self.name)
+	}
+}
+
+/// Check that the lamp will accept the duration of a transition.
+fn check_transition(trans: &Transition) -> Result<(), String> {
+	match trans.duration {
+		Some(dur) if dur < MIN_DURATION && trans.effect != Some(Effect::Sudden) => Err(format!(
+			"smooth transitions must take at least {MIN_DURATION:?}"
+		)),
+		_ => Ok(()),
+	}
+}
+
+/// The fields of a JSON command. Fields are removed as they are read,
+/// so that any fields left over at the end can be reported as unknown.
+struct JsonFields(Map<String, Value>);
+
+impl JsonFields {
+	/// Remove and convert an optional field.
+	fn take<T>(
+		&mut self, key: &str, conv: impl FnOnce(&Value) -> Result<T, String>,
+	) -> Result<Option<T>, String> {
+		match self.0.remove(key) {
+			Some(val) => conv(&val).map(Some).map_err(|e| field_err(key, e)),
+			None => Ok(None),
+		}
+	}
+
+	/// Remove and convert a required field.
+	fn require<T>(
+		&mut self, key: &str, conv: impl FnOnce(&Value) -> Result<T, String>,
+	) -> Result<T, String> {
+		self.take(key, conv)?
+			.ok_or_else(|| field_err(key, "missing"))
+	}
+
+	/// Fail if any fields were not used by the command.
+	fn finish(self) -> Result<(), String> {
+		match self.0.keys().next() {
+			Some(key) => Err(field_err(key, "unknown field for this command")),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Format an error message for a single field.
+fn field_err(key: &str, msg: impl std::fmt::Display) -> String {
+	format!("field \"{key}\": {msg}")
+}
+
+fn as_u64(val: &Value) -> Result<u64, String> {
+	val.as_u64()
+		.ok_or_else(|| format!("expected a non-negative integer, got {val}"))
+}
+
+fn as_usize(val: &Value) -> Result<usize, String> {
+	as_u64(val).map(|n| n as usize)
+}
+
+fn as_str(val: &Value) -> Result<String, String> {
+	val.as_str()
+		.map(String::from)
+		.ok_or_else(|| format!("expected a string, got {val}"))
+}
+
+fn as_str_vec(val: &Value) -> Result<Vec<String>, String> {
+	let Value::Array(vals) = val else {
+		return Err(format!("expected an array of strings, got {val}"));
+	};
+	vals.iter().map(as_str).collect()
+}
+
+/// Accepts an array of any values, such as the params of a raw command.
+fn as_array(val: &Value) -> Result<Vec<Value>, String> {
+	val.as_array()
+		.cloned()
+		.ok_or_else(|| format!("expected an array, got {val}"))
+}
+
+/// Reads a parameter of a raw text command as JSON,
+/// or as a string if it is not valid JSON (so that `set_name kitchen` needs no quotes).
+pub fn parse_raw_param(word: &str) -> Value {
+	serde_json::from_str(word).unwrap_or_else(|_| Value::String(word.to_string()))
+}
+
+/// Accepts "on"/"off" or a boolean.
+fn as_power(val: &Value) -> Result<bool, String> {
+	match val {
+		Value::Bool(on) => Ok(*on),
+		Value::String(s) if s == "on" => Ok(true),
+		Value::String(s) if s == "off" => Ok(false),
+		_ => Err(format!("expected \"on\", \"off\" or a boolean, got {val}")),
+	}
+}
+
+/// Accepts "#rrggbb", "rrggbb" or an integer.
+fn as_rgb(val: &Value) -> Result<usize, String> {
+	match val {
+		Value::String(s) => parse_hex_rgb(s),
+		_ => as_usize(val),
+	}
+}
+
+/// Accepts "#rrggbb" or "rrggbb".
+fn parse_hex_rgb(s: &str) -> Result<usize, String> {
+	let hex = s.strip_prefix('#').unwrap_or(s);
+	if hex.len() != 6 {
+		return Err(format!("expected a color like \"#ff8800\", got \"{s}\""));
+	}
+	usize::from_str_radix(hex, 16).map_err(|e| format!("invalid hex color \"{s}\": {e}"))
+}
+
+fn parse_usize(s: &str) -> Result<usize, String> {
+	s.parse().map_err(|e| format!("{e}"))
+}
+
+fn parse_power_word(s: &str) -> Result<bool, String> {
+	match s {
+		"on" => Ok(true),
+		"off" => Ok(false),
+		_ => Err(String::from("expected \"on\" or \"off\"")),
+	}
+}
+
+/// Error message for an invalid FlowAction.
+const FLOW_ACTION_ERR: &str = "expected \"recover\", \"stay\" or \"off\"";
+
+fn as_flow_action(val: &Value) -> Result<FlowAction, String> {
+	let action = as_str(val)?;
+	FlowAction::from_str(&action).map_err(|_| format!("{FLOW_ACTION_ERR}, got {val}"))
+}
+
+/// Accepts `{"r":255,"g":136,"b":0}` or `{"h":120.0,"s":80.0}`.
+fn as_ha_color(val: &Value) -> Result<Command, String> {
+	let component = |key: &str| val.get(key).and_then(Value::as_f64);
+	if let (Some(r), Some(g), Some(b)) = (component("r"), component("g"), component("b")) {
+		let [r, g, b] = [r, g, b].map(|c| c.round().clamp(0.0, 255.0) as usize);
+		return Command::new_rgb((r << 16) + (g << 8) + b);
+	}
+	match (component("h"), component("s")) {
+		(Some(h), Some(s)) => Command::new_hsv(h.round() as usize % 360, s.round() as usize),
+		_ => Err(format!(
+			"expected an object with r, g, b or h, s, got {val}"
+		)),
+	}
+}
+
+/// Accepts a non-negative number of seconds.
+fn as_seconds(val: &Value) -> Result<Duration, String> {
+	val.as_f64()
+		.and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+		.ok_or_else(|| format!("expected a non-negative number of seconds, got {val}"))
+}
+
+fn as_effect(val: &Value) -> Result<Effect, String> {
+	let effect = as_str(val)?;
+	Effect::from_str(&effect).map_err(|_| format!("expected \"sudden\" or \"smooth\", got {val}"))
 }
 
-/// Create required MQTT properties.
+/// Accepts a humantime string or an integer in milliseconds.
+fn as_duration(val: &Value) -> Result<Duration, String> {
+	match val {
+		Value::String(s) => humantime::parse_duration(s).map_err(|e| format!("{e}")),
+		_ => as_u64(val).map(Duration::from_millis),
+	}
+}
+
+/// Check whether a topic matches a topic filter, which may contain the wildcards `+` and `#`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+	let mut topic_levels = topic.split('/');
+	for level in filter.split('/') {
+		match (level, topic_levels.next()) {
+			("#", _) => return true,
+			("+", Some(_)) => {},
+			(level, Some(topic_level)) if level == topic_level => {},
+			_ => return false,
+		}
+	}
+	topic_levels.next().is_none()
+}
+
+/// Create the properties of the MQTT 5 CONNECT packet.
+/// The broker keeps the session for `session_expiry` after the program disconnects.
 #[cfg(feature = "mqtt")]
-pub fn mqtt_props() -> Properties {
-	properties![
-		MessageExpiryInterval => 3600,
-		ContentType => "application/json",
-		//SubscriptionIdentifier => 1i32,
-	]
+pub fn connect_props(session_expiry: Duration) -> paho_mqtt::Result<Properties> {
+	let mut props = Properties::new();
+	props.push_u32(SessionExpiryInterval, duration_secs(session_expiry))?;
+	Ok(props)
+}
+
+/// Create the MQTT 5 properties of a published JSON message.
+/// The message is dropped by the broker if it cannot be delivered within `message_expiry`;
+/// None means it never expires.
+#[cfg(feature = "mqtt")]
+pub fn message_props(message_expiry: Option<Duration>) -> paho_mqtt::Result<Properties> {
+	let mut props = properties![ContentType => "application/json"];
+	if let Some(expiry) = message_expiry {
+		props.push_u32(MessageExpiryInterval, duration_secs(expiry))?;
+	}
+	Ok(props)
+}
+
+/// Convert a Duration to whole seconds for an MQTT property.
+#[cfg(feature = "mqtt")]
+fn duration_secs(dur: Duration) -> u32 {
+	u32::try_from(dur.as_secs()).unwrap_or(u32::MAX)
 }
 
 /// Convert an i32 to a subscription ID property.
@@ -28,3 +598,356 @@ pub fn sub_id(id: i32) -> Properties {
 			SubscriptionIdentifier => id
 	]
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(msg: &str) -> Result<MqttCommand, String> {
+		parse_mqtt_command(String::from(msg))
+	}
+
+	/// Parse a command that must be rejected, and check that the error names `field`.
+	fn assert_field_err(msg: &str, field: &str) {
+		let err = parse(msg).expect_err(msg);
+		assert!(
+			err.starts_with(&format!("field \"{field}\":")),
+			"{msg}: {err}"
+		);
+	}
+
+	#[test]
+	fn json_command_reads_its_fields() {
+		let parsed = parse(r#"{"v":1,"cmd":"set_hsv","hue":120,"sat":80,"duration":500}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetHsv(120, 80)]);
+		assert_eq!(parsed.transition.duration, Some(Duration::from_millis(500)));
+		let parsed = parse(r#"{"cmd":"set_power","power":"off","effect":"sudden"}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetPower(false)]);
+		assert_eq!(parsed.transition.effect, Some(Effect::Sudden));
+		let parsed = parse(r#"{"cmd":"get_prop","props":["power","bright"]}"#).unwrap();
+		assert_eq!(
+			parsed.cmds,
+			vec![Command::GetProp(vec![
+				String::from("power"),
+				String::from("bright")
+			])]
+		);
+	}
+
+	#[test]
+	fn json_errors_name_the_field() {
+		for (msg, field) in [
+			(r#"{"v":"1","cmd":"toggle"}"#, "v"),
+			(r#"{"v":2,"cmd":"toggle"}"#, "v"),
+			(r#"{"cmd":1}"#, "cmd"),
+			(r#"{"cmd":"dance"}"#, "cmd"),
+			(r#"{"cmd":"set_power"}"#, "power"),
+			(r#"{"cmd":"set_power","power":1}"#, "power"),
+			(r#"{"cmd":"set_power","power":"maybe"}"#, "power"),
+			(r#"{"cmd":"set_bright","bright":"40"}"#, "bright"),
+			(r#"{"cmd":"set_bright","bright":-1}"#, "bright"),
+			(r#"{"cmd":"set_bright","bright":0}"#, "bright"),
+			(r#"{"cmd":"set_bright","bright":101}"#, "bright"),
+			(r#"{"cmd":"set_rgb","rgb":true}"#, "rgb"),
+			(r#"{"cmd":"set_rgb","rgb":"ff88"}"#, "rgb"),
+			(r#"{"cmd":"set_rgb","rgb":"gg8800"}"#, "rgb"),
+			(r#"{"cmd":"set_rgb","rgb":16777216}"#, "rgb"),
+			(r#"{"cmd":"set_ct_abx","ct":"2700"}"#, "ct"),
+			(r#"{"cmd":"set_ct_abx","ct":1000}"#, "ct"),
+			(r#"{"cmd":"set_ct_abx","ct":7000}"#, "ct"),
+			(r#"{"cmd":"set_hsv","hue":"red","sat":80}"#, "hue"),
+			(r#"{"cmd":"set_hsv","hue":360,"sat":80}"#, "hue"),
+			(r#"{"cmd":"set_hsv","hue":120,"sat":-5}"#, "sat"),
+			(r#"{"cmd":"set_hsv","hue":120,"sat":101}"#, "sat"),
+			(r#"{"cmd":"get_prop","props":"power"}"#, "props"),
+			(r#"{"cmd":"get_prop","props":[1]}"#, "props"),
+			(r#"{"cmd":"get_prop","props":[]}"#, "props"),
+			(r#"{"cmd":"start_cf","flow":1}"#, "flow"),
+			(r#"{"cmd":"start_cf","flow":"1000,2"}"#, "flow"),
+			(
+				r#"{"cmd":"start_cf","flow":"1000,2,2700,100","count":"x"}"#,
+				"count",
+			),
+			(
+				r#"{"cmd":"start_cf","flow":"1000,2,2700,100","count":-1}"#,
+				"count",
+			),
+			(
+				r#"{"cmd":"start_cf","flow":"1000,2,2700,100","action":1}"#,
+				"action",
+			),
+			(
+				r#"{"cmd":"start_cf","flow":"1000,2,2700,100","action":"pause"}"#,
+				"action",
+			),
+			(r#"{"cmd":"raw"}"#, "method"),
+			(r#"{"cmd":"raw","method":1}"#, "method"),
+			(r#"{"cmd":"raw","method":"set name"}"#, "method"),
+			(
+				r#"{"cmd":"raw","method":"set_name","params":"x"}"#,
+				"params",
+			),
+			(r#"{"cmd":"toggle","effect":1}"#, "effect"),
+			(r#"{"cmd":"toggle","effect":"fade"}"#, "effect"),
+			(r#"{"cmd":"toggle","duration":true}"#, "duration"),
+			(r#"{"cmd":"toggle","duration":-5}"#, "duration"),
+			(r#"{"cmd":"toggle","duration":"soon"}"#, "duration"),
+			(r#"{"cmd":"toggle","duration":"10ms"}"#, "duration"),
+			(r#"{"cmd":"toggle","colour":"red"}"#, "colour"),
+			// The parameters of raw are sent as they are, so there is no transition
+			(
+				r#"{"cmd":"raw","method":"set_name","effect":"smooth"}"#,
+				"effect",
+			),
+		] {
+			assert_field_err(msg, field);
+		}
+	}
+
+	#[test]
+	fn json_sudden_transitions_may_be_short() {
+		let parsed = parse(r#"{"cmd":"toggle","effect":"sudden","duration":"10ms"}"#).unwrap();
+		assert_eq!(parsed.transition.duration, Some(Duration::from_millis(10)));
+	}
+
+	#[test]
+	fn ha_command_turns_on_then_sets_color_and_brightness() {
+		let parsed = parse(
+			r#"{"brightness":40,"color":{"r":255,"g":136,"b":0},"state":"ON","transition":2}"#,
+		)
+		.unwrap();
+		assert_eq!(
+			parsed.cmds,
+			vec![
+				Command::SetPower(true),
+				Command::SetRgb(0xff8800),
+				Command::SetBright(40)
+			]
+		);
+		assert_eq!(parsed.transition.duration, Some(Duration::from_secs(2)));
+		let parsed = parse(r#"{"state":"OFF","effect":"sudden","transition":0.5}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetPower(false)]);
+		assert_eq!(
+			parsed.transition,
+			Transition {
+				effect: Some(Effect::Sudden),
+				duration: Some(Duration::from_millis(500)),
+			}
+		);
+	}
+
+	#[test]
+	fn ha_color_takes_rgb_or_hue_and_saturation() {
+		let parsed = parse(r#"{"color":{"h":120.4,"s":79.6}}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetHsv(120, 80)]);
+		// A hue of 360 wraps around to 0
+		let parsed = parse(r#"{"color":{"h":359.6,"s":80}}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetHsv(0, 80)]);
+		let parsed = parse(r#"{"color":{"r":300,"g":-1,"b":0}}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetRgb(0xff0000)]);
+	}
+
+	#[test]
+	fn ha_color_temp_is_converted_from_mireds() {
+		let parsed = parse(r#"{"color_temp":370}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetCtAbx(2702)]);
+		// Out of range temperatures are clamped to what the lamp accepts
+		let parsed = parse(r#"{"color_temp":1000}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetCtAbx(1700)]);
+		let parsed = parse(r#"{"color_temp":100}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetCtAbx(6500)]);
+	}
+
+	#[test]
+	fn ha_brightness_zero_is_the_lowest_brightness() {
+		let parsed = parse(r#"{"brightness":0}"#).unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetBright(1)]);
+	}
+
+	#[test]
+	fn ha_errors_name_the_field() {
+		for (msg, field) in [
+			(r#"{"state":"on"}"#, "state"),
+			(r#"{"state":true}"#, "state"),
+			(r#"{"brightness":101}"#, "brightness"),
+			(r#"{"brightness":"40"}"#, "brightness"),
+			(r#"{"color_temp":0}"#, "color_temp"),
+			(r#"{"color_temp":-370}"#, "color_temp"),
+			(r#"{"color":{"x":0.3,"y":0.3}}"#, "color"),
+			(r#"{"color":{"h":120,"s":101}}"#, "color"),
+			(r#"{"state":"ON","effect":"colorloop"}"#, "effect"),
+			(r#"{"state":"ON","transition":-1}"#, "transition"),
+			(r#"{"state":"ON","transition":"2s"}"#, "transition"),
+			(r#"{"state":"ON","transition":0.01}"#, "transition"),
+			(r#"{"state":"ON","flash":"short"}"#, "flash"),
+		] {
+			assert_field_err(msg, field);
+		}
+		assert!(parse(r#"{"transition":2}"#).is_err());
+		assert!(parse("{}").is_err());
+	}
+
+	#[test]
+	fn text_command_takes_a_transition_suffix() {
+		let smooth = Transition {
+			effect: Some(Effect::Smooth),
+			duration: Some(Duration::from_secs(2)),
+		};
+		for msg in ["bright 40 smooth 2s", "bright 40 2s smooth"] {
+			let parsed = parse(msg).unwrap();
+			assert_eq!(parsed.cmds, vec![Command::SetBright(40)], "{msg}");
+			assert_eq!(parsed.transition, smooth, "{msg}");
+		}
+		let parsed = parse("rgb #ff8800 500ms").unwrap();
+		assert_eq!(parsed.cmds, vec![Command::SetRgb(0xff8800)]);
+		assert_eq!(
+			parsed.transition,
+			Transition {
+				effect: None,
+				duration: Some(Duration::from_millis(500)),
+			}
+		);
+		let parsed = parse("toggle sudden 10ms").unwrap();
+		assert_eq!(parsed.transition.effect, Some(Effect::Sudden));
+		assert_eq!(parse("ct 2700").unwrap().transition, Transition::default());
+	}
+
+	#[test]
+	fn text_command_rejects_bad_transitions() {
+		for msg in [
+			"bright 40 smooth smooth",
+			"bright 40 1s 2s",
+			"bright 40 fade",
+			"bright 40 smooth 10ms",
+			"bright 40 10ms",
+			"toggle 40",
+		] {
+			assert!(parse(msg).is_err(), "{msg}");
+		}
+	}
+
+	#[test]
+	fn text_get_and_raw_have_no_transition() {
+		let parsed = parse("get power smooth").unwrap();
+		assert_eq!(
+			parsed.cmds,
+			vec![Command::GetProp(vec![
+				String::from("power"),
+				String::from("smooth")
+			])]
+		);
+		assert_eq!(parsed.transition, Transition::default());
+	}
+
+	#[test]
+	fn property_payloads_are_accepted() {
+		let flow = Command::new_start_cf(0, FlowAction::Recover, "1000,2,2700,100").unwrap();
+		for (property, payload, cmd) in [
+			("power", "on", Command::SetPower(true)),
+			("power", "OFF", Command::SetPower(false)),
+			("power", "Toggle", Command::Toggle),
+			("brightness", "40", Command::SetBright(40)),
+			("rgb", "ff8800", Command::SetRgb(0xff8800)),
+			("rgb", "#ff8800", Command::SetRgb(0xff8800)),
+			("ct", "2700", Command::SetCtAbx(2700)),
+			("hsv", "120 80", Command::SetHsv(120, 80)),
+			("hsv", "120,80", Command::SetHsv(120, 80)),
+			("flow", "0 recover 1000,2,2700,100", flow),
+			("flow", "STOP", Command::StopCf),
+		] {
+			let parsed = parse_property_command(property, payload).unwrap();
+			assert_eq!(parsed.cmds, vec![cmd], "{property} {payload}");
+			assert_eq!(
+				parsed.transition,
+				Transition::default(),
+				"{property} {payload}"
+			);
+		}
+	}
+
+	#[test]
+	fn property_payloads_take_a_transition() {
+		let smooth = Transition {
+			effect: Some(Effect::Smooth),
+			duration: Some(Duration::from_secs(1)),
+		};
+		for (property, payload) in [
+			("power", "on smooth 1s"),
+			("brightness", "40 1s smooth"),
+			("rgb", "#ff8800 smooth 1s"),
+			("ct", "2700 smooth 1s"),
+			("hsv", "120,80 smooth 1s"),
+		] {
+			let parsed = parse_property_command(property, payload).unwrap();
+			assert_eq!(parsed.transition, smooth, "{property} {payload}");
+		}
+	}
+
+	#[test]
+	fn property_payloads_are_rejected() {
+		for (property, payload) in [
+			("power", ""),
+			("power", "maybe"),
+			("power", "on fade"),
+			("brightness", "0"),
+			("brightness", "bright"),
+			("rgb", "ff88"),
+			("rgb", "red"),
+			("ct", "1000"),
+			("ct", "warm"),
+			("hsv", "360,80"),
+			("hsv", "120"),
+			("flow", "0 pause 1000,2,2700,100"),
+			("flow", "0 recover 1000,2"),
+		] {
+			let err = parse_property_command(property, payload).expect_err(payload);
+			assert!(
+				err.starts_with(&format!("Invalid payload for {property}:")),
+				"{property} {payload}: {err}"
+			);
+		}
+		assert!(parse_property_command("color", "red").is_err());
+	}
+
+	#[test]
+	fn topic_matches_exact_topics() {
+		assert!(topic_matches(
+			"yeelight/kitchen/set",
+			"yeelight/kitchen/set"
+		));
+		assert!(!topic_matches("yeelight/kitchen/set", "yeelight/hall/set"));
+		assert!(!topic_matches("yeelight/kitchen/set", "yeelight/kitchen"));
+	}
+
+	#[test]
+	fn topic_matches_single_level_wildcard() {
+		let filter = "yeelight/kitchen/+/set";
+		assert!(topic_matches(filter, "yeelight/kitchen/brightness/set"));
+		assert!(topic_matches(filter, "yeelight/kitchen//set"));
+		assert!(!topic_matches(filter, "yeelight/kitchen/set"));
+		assert!(!topic_matches(filter, "yeelight/kitchen/rgb/x/set"));
+		assert!(topic_matches("+", "kitchen"));
+		assert!(!topic_matches("+", "yeelight/kitchen"));
+	}
+
+	#[test]
+	fn topic_matches_multi_level_wildcard() {
+		assert!(topic_matches("yeelight/#", "yeelight/kitchen/set"));
+		assert!(topic_matches("yeelight/#", "yeelight/kitchen"));
+		// `#` also matches the parent level
+		assert!(topic_matches("yeelight/#", "yeelight"));
+		assert!(topic_matches("#", "yeelight/kitchen/set"));
+		assert!(!topic_matches("yeelight/#", "homeassistant/status"));
+	}
+
+	#[test]
+	fn topic_matches_trailing_levels() {
+		assert!(!topic_matches("yeelight/kitchen", "yeelight/kitchen/set"));
+		assert!(!topic_matches("yeelight/+", "yeelight/kitchen/set"));
+		assert!(!topic_matches(
+			"yeelight/kitchen/set",
+			"yeelight/kitchen/set/"
+		));
+		assert!(topic_matches("yeelight/kitchen/+", "yeelight/kitchen/"));
+	}
+}
@@ -0,0 +1,275 @@
+use crate::lamp::{Lamp, Response};
+use crate::mock::MockLamp;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How long Recording::replay() waits for the mock to send more lines.
+const REPLAY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Which way a recorded line went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+	/// Written to the lamp.
+	Sent,
+	/// Received from the lamp.
+	Received,
+}
+
+/// A line of a recording: one request written to a lamp, or one line received from it.
+///
+/// Stored as one JSON object per line:
+/// ```json
+/// {"time":"2025-01-01T12:00:00.123Z","lamp":"Kitchen","conn":1,"dir":"sent",
+///  "line":"{\"id\":0,\"method\":\"toggle\",\"params\":[]}\r\n"}
+/// ```
+/// `line` holds the exact bytes, including the line terminator.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Record {
+	/// When the line was written or received.
+	#[serde(with = "humantime_serde")]
+	pub time: SystemTime,
+	/// Name of the lamp.
+	pub lamp: String,
+	/// Number of the connection; see Lamp::connection_number().
+	pub conn: u64,
+	/// Whether the line was sent or received.
+	pub dir: Direction,
+	/// The line itself. Bytes that are not valid UTF-8 are replaced.
+	pub line: String,
+}
+
+/// Writes the traffic of lamps to a JSONL file.
+///
+/// Attach a recorder to a lamp with Lamp::set_recorder(). Every request the lamp writes and
+/// every line its LampReader receives is then appended to the file as a Record.
+/// A recorder can be cloned and shared by several lamps; each record is written at once,
+/// so the lines of different lamps are never mixed up.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+	file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+	/// Open a file for recording. New records are appended to the file.
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(Self {
+			file: Arc::new(Mutex::new(file)),
+		})
+	}
+
+	/// Append a line to the recording.
+	///
+	/// Failures are logged but otherwise ignored, so that a full disk does not stop the lamp.
+	pub fn record(&self, lamp: &str, conn: u64, dir: Direction, bytes: &[u8]) {
+		let record = Record {
+			time: SystemTime::now(),
+			lamp: lamp.to_string(),
+			conn,
+			dir,
+			line: String::from_utf8_lossy(bytes).into_owned(),
+		};
+		let mut line = match serde_json::to_string(&record) {
+			Ok(line) => line,
+			Err(e) => {
+				warn!("{lamp} | Could not serialize record: {e}");
+				return;
+			},
+		};
+		line.push('\n');
+		trace!("{lamp} | Recording {dir:?} line");
+		let res = match self.file.lock() {
+			Ok(mut file) => file.write_all(line.as_bytes()),
+			Err(_) => Err(io::Error::other("Recorder mutex poisoned")),
+		};
+		if let Err(e) = res {
+			warn!("{lamp} | Could not write record: {e}");
+		}
+	}
+}
+
+/// A recorded session, loaded from a file written by a Recorder.
+///
+/// The recording can be checked offline with Recording::parse_responses(), or its requests
+/// can be sent to a MockLamp with Recording::replay() to compare the replies of the mock with
+/// the recorded ones.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recording {
+	/// The records in the order they were written.
+	pub records: Vec<Record>,
+}
+
+/// The outcome of Recording::replay().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Replay {
+	/// The lines received from the lamp during the recording.
+	pub recorded: Vec<String>,
+	/// The lines received from the mock during the replay.
+	pub replayed: Vec<String>,
+}
+
+impl Replay {
+	/// Returns true if the mock answered exactly like the recorded lamp.
+	pub fn matches(&self) -> bool {
+		self.recorded == self.replayed
+	}
+
+	/// Get the index and both versions of the first line that differs.
+	/// A missing line is None.
+	pub fn first_difference(&self) -> Option<(usize, Option<&str>, Option<&str>)> {
+		let len = self.recorded.len().max(self.replayed.len());
+		(0..len)
+			.map(|idx| {
+				let recorded = self.recorded.get(idx).map(String::as_str);
+				let replayed = self.replayed.get(idx).map(String::as_str);
+				(idx, recorded, replayed)
+			})
+			.find(|(_, recorded, replayed)| recorded != replayed)
+	}
+}
+
+impl Recording {
+	/// Load a recording from a JSONL file.
+	/// Empty lines are skipped; any other line that is not a Record is an error.
+	pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+		let reader = BufReader::new(File::open(path)?);
+		let mut records = Vec::new();
+		for (idx, line) in reader.lines().enumerate() {
+			let line = line?;
+			if line.trim().is_empty() {
+				continue;
+			}
+			let record = serde_json::from_str(&line).map_err(|e| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Invalid record on line {}: {e}", idx + 1),
+				)
+			})?;
+			records.push(record);
+		}
+		Ok(Self { records })
+	}
+
+	/// Get the records of one lamp.
+	pub fn lamp(&self, name: &str) -> Self {
+		Self {
+			records: self
+				.records
+				.iter()
+				.filter(|record| record.lamp == name)
+				.cloned()
+				.collect(),
+		}
+	}
+
+	/// Pass every received line through Lamp::parse_response(), in the recorded order.
+	pub fn parse_responses(&self) -> Vec<(&Record, Result<Response, String>)> {
+		self.records
+			.iter()
+			.filter(|record| record.dir == Direction::Received)
+			.map(|record| {
+				(
+					record,
+					Lamp::parse_response(record.line.trim_end().as_bytes()),
+				)
+			})
+			.collect()
+	}
+
+	/// Send the recorded requests to a mock lamp and collect what it answers.
+	///
+	/// The requests are sent byte for byte in the recorded order, each one after the
+	/// previous one has been answered. Every recorded connection is replayed on a new
+	/// connection to the mock, so that the state and faults of the mock decide the outcome,
+	/// not the timing of the recording. The recording should contain a single lamp;
+	/// see Recording::lamp().
+	pub fn replay(&self, mock: &MockLamp) -> io::Result<Replay> {
+		let mut replay = Replay {
+			recorded: self
+				.records
+				.iter()
+				.filter(|record| record.dir == Direction::Received)
+				.map(|record| record.line.clone())
+				.collect(),
+			replayed: Vec::new(),
+		};
+		let mut conn: Option<(u64, TcpStream, BufReader<TcpStream>)> = None;
+		for record in self.records.iter() {
+			if record.dir != Direction::Sent {
+				continue;
+			}
+			if conn
+				.as_ref()
+				.is_none_or(|(number, ..)| *number != record.conn)
+			{
+				if let Some((_, _, ref mut reader)) = conn {
+					read_lines(reader, None, &mut replay.replayed)?;
+				}
+				debug!("Replaying connection {} of {}", record.conn, record.lamp);
+				let stream = TcpStream::connect(mock.addr())?;
+				stream.set_read_timeout(Some(REPLAY_TIMEOUT))?;
+				let reader = BufReader::new(stream.try_clone()?);
+				conn = Some((record.conn, stream, reader));
+			}
+			let Some((_, ref mut stream, ref mut reader)) = conn else {
+				unreachable!("connection opened above")
+			};
+			// A connection closed by the mock loses the rest of its requests,
+			// as it would with a real lamp
+			if let Err(e) = stream.write_all(record.line.as_bytes()) {
+				debug!("Could not replay request: {e}");
+				continue;
+			}
+			let id = request_id(&record.line);
+			read_lines(reader, id, &mut replay.replayed)?;
+		}
+		if let Some((_, _, ref mut reader)) = conn {
+			read_lines(reader, None, &mut replay.replayed)?;
+		}
+		Ok(replay)
+	}
+}
+
+/// Get the ID of a recorded request, if it has one.
+fn request_id(line: &str) -> Option<u64> {
+	let req: serde_json::Value = serde_json::from_str(line.trim_end()).ok()?;
+	req["id"].as_u64()
+}
+
+/// Read lines from the mock until the reply to `id` arrives, or until it goes quiet.
+/// The connection being closed also ends the reading.
+fn read_lines(
+	reader: &mut BufReader<TcpStream>, id: Option<u64>, lines: &mut Vec<String>,
+) -> io::Result<()> {
+	loop {
+		let mut line = String::new();
+		match reader.read_line(&mut line) {
+			Ok(0) => return Ok(()),
+			Ok(_) => {
+				let done = id.is_some() && request_id(&line) == id;
+				lines.push(line);
+				if done {
+					return Ok(());
+				}
+			},
+			Err(e)
+				if matches!(
+					e.kind(),
+					io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+				) =>
+			{
+				return Ok(());
+			},
+			Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+			Err(e) => return Err(e),
+		}
+	}
+}
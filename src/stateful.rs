@@ -1,63 +1,247 @@
-use crate::lamp::Lamp;
+use crate::group::{SharedLamp, lock_lamp};
+use crate::lamp::Response;
 use crate::structs::Command;
+use serde_json::{Map, Value, json};
 use std::io;
 
-enum LampColorState {
-	RGB(u32),
-	ColorTemp(usize),
-	Hsv(u8, u8),
+/// Properties requested by StatefulLamp::refresh(), in the order the lamp returns them.
+pub const STATE_PROPS: [&str; 8] = [
+	"power",
+	"bright",
+	"color_mode",
+	"ct",
+	"rgb",
+	"hue",
+	"sat",
+	"flowing",
+];
+
+/// The color mode a lamp is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+	/// The color is set by an RGB value.
+	Rgb,
+	/// The color is set by a color temperature.
+	Ct,
+	/// The color is set by hue and saturation.
+	Hsv,
 }
 
-enum LampStateChange {
-	SetRgb(u32),
-	AddTemp(isize),
-	SetTemp(usize),
+impl ColorMode {
+	/// Convert the color_mode property of the lamp (1, 2 or 3) to a ColorMode.
+	fn from_prop(val: u64) -> Option<Self> {
+		match val {
+			1 => Some(Self::Rgb),
+			2 => Some(Self::Ct),
+			3 => Some(Self::Hsv),
+			_ => None,
+		}
+	}
+
+	/// Get the value of the color_mode property of the lamp for this ColorMode.
+	pub fn to_prop(self) -> u64 {
+		match self {
+			Self::Rgb => 1,
+			Self::Ct => 2,
+			Self::Hsv => 3,
+		}
+	}
+
+	/// Get the name used in the state document.
+	/// These are the color mode names used by Home Assistant.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Rgb => "rgb",
+			Self::Ct => "color_temp",
+			Self::Hsv => "hs",
+		}
+	}
+}
 
-	AddBright(isize),
-	SetBright(usize),
-	Toggle,
+/// The last known state of a lamp.
+///
+/// Every property is None until the lamp has reported it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LampState {
+	/// Whether the lamp is on.
+	pub power: Option<bool>,
+	/// Brightness in percent.
+	pub bright: Option<u8>,
+	/// Which of the color properties is in use.
+	pub color_mode: Option<ColorMode>,
+	/// Color as 0xRRGGBB.
+	pub rgb: Option<u32>,
+	/// Color temperature in Kelvin.
+	pub ct: Option<u16>,
+	/// Hue, 0-359.
+	pub hue: Option<u16>,
+	/// Saturation, 0-100.
+	pub sat: Option<u8>,
+	/// Whether a color flow is running.
+	pub flowing: Option<bool>,
+	/// Whether the program is connected to the lamp.
+	pub connected: bool,
 }
 
-// THIS WON'T WORK. WE NEED THE CURRENT CHANGE FOR ADDITIVE CHANGES.
-/*
-impl From<LampStateChange> for Command {
-	fn from(change: LampStateChange) -> Self {
-		type Chan = LampStateChange;
-		match change {
-			Chan::SetRgb(rgb) => Command::SetRgb(rgb),
-			_ => todo!(),
+impl LampState {
+	/// Update the state from property values reported by the lamp.
+	///
+	/// The lamp sends numbers either as JSON numbers or as strings;
+	/// both are accepted. Unknown properties and invalid values are ignored.
+	/// Returns true if anything changed.
+	pub fn update<'a>(&mut self, props: impl IntoIterator<Item = (&'a str, &'a Value)>) -> bool {
+		let old = self.clone();
+		for (key, val) in props {
+			let num = prop_number(val);
+			match key {
+				"power" => self.power = val.as_str().map(|p| p == "on").or(self.power),
+				"bright" => self.bright = num.and_then(|n| n.try_into().ok()).or(self.bright),
+				"color_mode" => {
+					self.color_mode = num.and_then(ColorMode::from_prop).or(self.color_mode)
+				},
+				"ct" => self.ct = num.and_then(|n| n.try_into().ok()).or(self.ct),
+				"rgb" => self.rgb = num.and_then(|n| n.try_into().ok()).or(self.rgb),
+				"hue" => self.hue = num.and_then(|n| n.try_into().ok()).or(self.hue),
+				"sat" => self.sat = num.and_then(|n| n.try_into().ok()).or(self.sat),
+				"flowing" => self.flowing = num.map(|n| n == 1).or(self.flowing),
+				_ => {},
+			}
+		}
+		*self != old
+	}
+
+	/// Create the JSON document published on the state topic of the lamp.
+	///
+	/// Example:
+	/// ```json
+	/// {"power":"on","bright":40,"color_mode":"rgb","rgb":"#ff8800","ct":2700,
+	///  "hue":120,"sat":80,"flowing":false,"connectivity":"online",
+	///  "state":"ON","brightness":40,"color":{"r":255,"g":136,"b":0,"h":120,"s":80},
+	///  "color_temp":370}
+	/// ```
+	/// `state`, `brightness`, `color` and `color_temp` (in mireds) repeat the other values
+	/// in the format of the Home Assistant JSON light schema.
+	/// Properties the lamp has not reported yet are left out.
+	pub fn to_json(&self) -> Value {
+		let mut doc = Map::new();
+		let mut color = Map::new();
+		if let Some(power) = self.power {
+			doc.insert("power".into(), json!(if power { "on" } else { "off" }));
+			doc.insert("state".into(), json!(if power { "ON" } else { "OFF" }));
+		}
+		if let Some(bright) = self.bright {
+			doc.insert("bright".into(), json!(bright));
+			doc.insert("brightness".into(), json!(bright));
+		}
+		if let Some(mode) = self.color_mode {
+			doc.insert("color_mode".into(), json!(mode.name()));
+		}
+		if let Some(rgb) = self.rgb {
+			doc.insert("rgb".into(), json!(format!("#{rgb:06x}")));
+			let [_, r, g, b] = rgb.to_be_bytes();
+			color.extend([
+				("r".into(), json!(r)),
+				("g".into(), json!(g)),
+				("b".into(), json!(b)),
+			]);
+		}
+		if let Some(ct) = self.ct {
+			doc.insert("ct".into(), json!(ct));
+			if ct > 0 {
+				doc.insert("color_temp".into(), json!(1_000_000 / u32::from(ct)));
+			}
+		}
+		if let Some(hue) = self.hue {
+			doc.insert("hue".into(), json!(hue));
+			color.insert("h".into(), json!(hue));
 		}
+		if let Some(sat) = self.sat {
+			doc.insert("sat".into(), json!(sat));
+			color.insert("s".into(), json!(sat));
+		}
+		if !color.is_empty() {
+			doc.insert("color".into(), Value::Object(color));
+		}
+		if let Some(flowing) = self.flowing {
+			doc.insert("flowing".into(), json!(flowing));
+		}
+		let connectivity = if self.connected { "online" } else { "offline" };
+		doc.insert("connectivity".into(), json!(connectivity));
+		Value::Object(doc)
 	}
 }
-*/
-
-impl LampColorState {
-	/// Consume the current LampColorState and output a new LampColorState.
-	pub fn do_change(self, change: LampStateChange) -> io::Result<Self> {
-		type Chan = LampStateChange;
-		match (self, change) {
-			(Self::RGB(_), Chan::SetRgb(rgb)) => Ok(Self::RGB(rgb)),
-			(Self::ColorTemp(temp), Chan::AddTemp(dtemp)) => Ok(Self::ColorTemp(temp + dtemp)),
-			(Self::ColorTemp(_), Chan::SetTemp(temp)) => Ok(Self::ColorTemp(temp)),
-			_ => Err(io::Error::new(
-				io::ErrorKind::InvalidInput,
-				"State change inconsistent with current state",
-			)),
-		}
-	}
-
-	// TODO maybe add some method that takes &self and a LampStateChange
-	// and outputs a tuple consisting of a new LampColorState
-	// together with the Command needed to get to this state
+
+/// Read a numeric property, which the lamp may send as a number or as a string.
+fn prop_number(val: &Value) -> Option<u64> {
+	match val {
+		Value::Number(n) => n.as_u64(),
+		Value::String(s) => s.parse().ok(),
+		_ => None,
+	}
 }
 
+/// A lamp together with its last known state.
+///
+/// The state is kept up to date by passing every Response read from the lamp
+/// to StatefulLamp::handle_response(). Notifications update the state directly,
+/// and the reply to StatefulLamp::refresh() fills in every property at once.
 #[derive(Debug)]
-struct StatefulLamp {
-	lamp: Lamp,
-	color: LampColorState,
-	bright: u8,
+pub struct StatefulLamp {
+	lamp: SharedLamp,
+	state: LampState,
+	refresh_id: Option<u8>,
 }
 
 impl StatefulLamp {
-	pub fn change_state(&mut self, change: LampStateChange) -> io::Result<()> {}
+	/// Start tracking the state of a lamp. Nothing is known about the lamp yet.
+	pub fn new(lamp: SharedLamp) -> Self {
+		Self {
+			lamp,
+			state: LampState::default(),
+			refresh_id: None,
+		}
+	}
+
+	/// Get the tracked lamp.
+	pub fn lamp(&self) -> &SharedLamp {
+		&self.lamp
+	}
+
+	/// Get the last known state of the lamp.
+	pub fn state(&self) -> &LampState {
+		&self.state
+	}
+
+	/// Ask the lamp for all properties in STATE_PROPS.
+	/// The state is updated once the reply is passed to StatefulLamp::handle_response().
+	pub fn refresh(&mut self) -> io::Result<()> {
+		let props = STATE_PROPS.iter().map(|p| p.to_string()).collect();
+		let id = lock_lamp(&self.lamp)?.send_cmd(Command::GetProp(props))?;
+		self.refresh_id = Some(id);
+		Ok(())
+	}
+
+	/// Update the state from a response of the lamp.
+	/// Returns true if the state changed.
+	pub fn handle_response(&mut self, resp: &Response) -> bool {
+		match resp {
+			Response::Notification(props) => self
+				.state
+				.update(props.iter().map(|(k, v)| (k.as_str(), v))),
+			Response::Result { id, values } if Some(*id) == self.refresh_id => {
+				self.refresh_id = None;
+				self.state
+					.update(STATE_PROPS.into_iter().zip(values.iter()))
+			},
+			_ => false,
+		}
+	}
+
+	/// Record whether the program is connected to the lamp.
+	/// Returns true if the state changed.
+	pub fn set_connected(&mut self, connected: bool) -> bool {
+		let changed = self.state.connected != connected;
+		self.state.connected = connected;
+		changed
+	}
 }
@@ -1,11 +1,10 @@
 use color::{ColorSpace, OpaqueColor, Rgba8, Srgb};
-use log::debug;
-use serde::Deserialize;
-use std::net::SocketAddr;
-use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fmt;
 use std::time::Duration;
 use strum_macros;
-use strum_macros::EnumString;
+use strum_macros::{EnumIter, EnumMessage, EnumString};
 
 /* TODO list here:
  * - Integrate OpaqueColor into our ecosystem better
@@ -19,146 +18,10 @@ use strum_macros::EnumString;
  *   because clang lib missing...
  */
 
-/// Struct that stores settings of the program.
-///
-/// The struct is divided into two parts:
-/// One for the lamp, another for the MQTT connection.
-#[derive(Debug, Deserialize)]
-pub struct Config {
-	/// Sub-struct containing lamp-related settings.
-	pub lamp: LampConfig,
-	/// Sub-struct containing settings for the MQTT connection.
-	pub mqtt: MqttConfig,
-}
-
-/// Struct containing the IP address and several timeout values.
-///
-/// The default_duration pertains to the length of the smooth color transition of the lamp.
-/// The read/write timeouts are related to the TcpStream. None means the corresponding functions
-/// can block indefinitely.
-/// connection_tries indicates how many times the program should attempt to connect before giving
-/// up. The _wait variable is the time between attempts, while connection_timeout is related to the
-/// TcpStream::connect_timeout() function.
-#[derive(Debug, Deserialize)]
-#[serde(rename = "lamp", rename_all = "kebab-case")]
-pub struct LampConfig {
-	/// A name for identifying the lamp.
-	pub name: String,
-	/// IP address and port of the lamp.
-	pub ip: SocketAddr,
-	/// How long a smooth color transition takes
-	#[serde(with = "humantime_serde")]
-	pub default_duration: Duration,
-	/// How long TcpStream waits for incoming data.
-	#[serde(
-		deserialize_with = "humantime_serde_opt",
-		default = "default_timeout_opt"
-	)]
-	pub read_timeout: Option<Duration>,
-	/// How long TcpStream takes to send data (at maximum).
-	#[serde(
-		deserialize_with = "humantime_serde_opt",
-		default = "default_timeout_opt"
-	)]
-	pub write_timeout: Option<Duration>,
-	/// How many tries to attempt to connect before giving up.
-	pub connection_tries: u8,
-	/// For how long to wait between connection attempts.
-	#[serde(with = "humantime_serde", default = "default_wait")]
-	pub connection_tries_wait: Duration,
-	/// How long each connection attempt takes (at maximum).
-	#[serde(with = "humantime_serde", default = "default_wait")]
-	pub connection_timeout: Duration,
-}
-
-/// The default value for connection_tries_{wait,timeout}.
-fn default_wait() -> Duration {
-	Duration::from_secs(5)
-}
-
-/// The default value for {read,write}_timeout
-fn default_timeout_opt() -> Option<Duration> {
-	Some(Duration::from_secs(5))
-}
-
-/// Custom deserializer function for Option<Duration>
-fn humantime_serde_opt<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
-where
-	D: serde::Deserializer<'de>,
-{
-	let opt = Option::<String>::deserialize(deserializer)?;
-	debug!("Deserialized option {opt:?}");
-	match opt {
-		None => Ok(None), // don't care, will be replaced by default_timeout()
-		Some(s) if s.is_empty() => Ok(None), // this one will actually be None
-		Some(s) => humantime::parse_duration(&s)
-			.map(Some)
-			.map_err(serde::de::Error::custom),
-	}
-}
-
-impl LampConfig {
-	/// Get a tuple containing the read and write timeouts of the lamp.
-	pub fn get_read_write_timeouts(&self) -> (Option<Duration>, Option<Duration>) {
-		(self.read_timeout, self.write_timeout)
-	}
-
-	/// Return a ConnectionSettings struct.
-	pub fn get_connection_settings(&self) -> ConnectionSettings {
-		ConnectionSettings {
-			read_timeout: self.read_timeout,
-			write_timeout: self.write_timeout,
-			conn_timeout: self.connection_timeout,
-			conn_tries: self.connection_tries,
-			conn_wait: self.connection_tries_wait,
-		}
-	}
-}
-
-/// Struct containing settings that are used to define the MQTT connection.
-#[derive(Debug, Deserialize)]
-#[serde(rename = "mqtt", rename_all = "kebab-case")]
-pub struct MqttConfig {
-	/// IP address and port of the MQTT broker.
-	pub ip: SocketAddr,
-	/// Client identifier used as the name of this program:
-	#[serde(default = "default_id")]
-	pub client_id: String,
-	/// What topic the program uses as input.
-	pub topic: String,
-	/// Subscription ID used for this topic.
-	pub sub_id: i32,
-	/// Define the QoS value for the subscription.
-	#[serde(default = "default_qos")]
-	pub qos: u32,
-	/// Last will and testament (LWT) payload.
-	pub lwt_payload: String,
-}
-
-/// Default client ID.
-fn default_id() -> String {
-	String::from("yeerugina")
-}
-
-/// Default QoS value
-fn default_qos() -> u32 {
-	1u32
-}
-
-impl Config {
-	/// Deserialize a .toml file containing the settings and produce a Config struct.
-	pub fn read_file(path: String) -> Result<Self, String> {
-		debug!("Reading config from {path}");
-		let cont = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
-		debug!("File read successfully");
-		toml::from_str(&cont).map_err(|e| e.to_string())
-	}
-}
+type OptDuration = Option<Duration>;
 
 /// A struct containing settings that is passed to Lamp::connect().
-
-type OptDuration = Option<Duration>;
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ConnectionSettings {
 	/// Read timeout for TcpStream
 	pub read_timeout: OptDuration,
@@ -172,13 +35,37 @@ pub struct ConnectionSettings {
 	pub conn_wait: Duration,
 }
 
+/// The shortest duration the lamps accept for smooth transitions.
+pub const MIN_DURATION: Duration = Duration::from_millis(30);
+
+/// Effect and duration to use for a single command instead of the lamp's defaults.
+///
+/// A None field means the default configured for the lamp is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transition {
+	/// Effect of the transition.
+	pub effect: Option<Effect>,
+	/// Duration of the transition.
+	pub duration: Option<Duration>,
+}
+
 // need default due to EnumString trait bound
 /// Enum that indicates the two possible color transitions supported in YeeLight lamps.
 /// Sudden means that the color will change without any time (i.e. instantly),
 /// while Smooth transitions take place over some length of time.
 #[derive(
-	Clone, Copy, Debug, Default, PartialEq, Eq, strum_macros::Display, strum_macros::EnumString,
+	Clone,
+	Copy,
+	Debug,
+	Default,
+	PartialEq,
+	Eq,
+	Deserialize,
+	Serialize,
+	strum_macros::Display,
+	strum_macros::EnumString,
 )]
+#[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum Effect {
 	/// Instant transition.
@@ -188,11 +75,37 @@ pub enum Effect {
 	Smooth,
 }
 
+/// What the lamp does after a color flow (start_cf) ends.
+#[derive(
+	Clone,
+	Copy,
+	Debug,
+	Default,
+	PartialEq,
+	Eq,
+	Deserialize,
+	Serialize,
+	strum_macros::Display,
+	strum_macros::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum FlowAction {
+	/// Return to the state before the flow started.
+	#[default]
+	Recover = 0,
+	/// Stay in the state at the end of the flow.
+	Stay = 1,
+	/// Turn the lamp off.
+	Off = 2,
+}
+
 // I'm sorry for this clusterduck.
 // OpaqueColor<CS> doesn't implement PartialEq, Eq, or Default
 // which are all needed for strum_macros::EnumString
+/// Wrapper around an OpaqueColor, used by Command::SetOpaqueColor.
 #[derive(Clone, Debug)]
-struct OpaqueColorWrapper<CS> {
+pub struct OpaqueColorWrapper<CS> {
 	color: OpaqueColor<CS>,
 }
 
@@ -216,101 +129,347 @@ impl<CS: ColorSpace> Default for OpaqueColorWrapper<CS> {
 ///
 /// Note that parsing logic is NOT included in the Command enum. Instead, the user is responsible
 /// for parsing any Strings to Commands. See mqtt.rs.
-#[derive(Clone, Debug, PartialEq, Eq, strum_macros::Display, EnumString)]
+#[derive(
+	Clone, Debug, PartialEq, Eq, strum_macros::AsRefStr, EnumString, EnumIter, EnumMessage,
+)]
 #[strum(serialize_all = "snake_case")]
 // TODO either do newtype struct or just don't overcomplicate stuff and have the MQTT parser deal
 // with creating each enum... but we cannot verify the values cos enums are public
-enum Command { // TODO create a newtype struct containing only InnerCommand
+// The short names (get, ct, rgb...) are only used when parsing the text command syntax.
+// to_string keeps the method names used in requests; see the Display impl.
+pub enum Command {
+	// TODO create a newtype struct containing only InnerCommand
 	/// Get properties of the lamp (i.e. current color temperature, brightness...)
+	#[strum(to_string = "get_prop", serialize = "get")]
 	GetProp(Vec<String>),
 	/// Set the color temperature of the lamp.
+	#[strum(to_string = "set_ct_abx", serialize = "ct")]
 	SetCtAbx(usize),
 	/// Set the color of the lamp using a 24 bit hexadecimal value.
 	/// 0xRRGGBB
+	#[strum(to_string = "set_rgb", serialize = "rgb")]
 	SetRgb(usize),
 	/// Set the color of the lamp by hue and saturation.
+	#[strum(to_string = "set_hsv", serialize = "hsv")]
 	SetHsv(usize, usize),
 	/// Additional command: Set the color of the lamp by passing in an OpaqueColor.
 	SetOpaqueColor(OpaqueColorWrapper<Srgb>), // this doesn't implement PartialEq or Eq
 	/// Set the brightness of the lamp in percentages.
+	#[strum(to_string = "set_bright", serialize = "bright")]
 	SetBright(usize),
-	/// TODO write documentation here
-	//SetPower(bool, Effect, usize, Option<usize>),
+	/// Turn the lamp on (true) or off (false).
+	#[strum(to_string = "set_power", serialize = "power")]
+	SetPower(bool),
+	/// Start a color flow: the number of transitions to run (0 = forever),
+	/// what to do after the flow, and the flow expression.
+	/// See Command::new_start_cf() for the expression format.
+	#[strum(to_string = "start_cf", serialize = "flow")]
+	StartCf(usize, FlowAction, String),
+	/// Stop a running color flow.
+	#[strum(to_string = "stop_cf", serialize = "stop")]
+	StopCf,
 	/// Toggle the state of the lamp (i.e. off -> on, on -> off)
 	Toggle,
+	/// Send any method with the given parameters, for methods not covered by the other commands.
+	/// The name of the method is used as the name of the command.
+	#[strum(serialize = "raw")]
+	Raw {
+		/// Name of the method.
+		method: String,
+		/// Parameters of the method, sent as they are.
+		params: Vec<Value>,
+	},
 }
 
 impl Command {
-        /// Create a new Command::SetCtAbx enum.
-        pub fn new_ct_abx(val: usize) -> Result<Self,String> {
-            if (val < 1000) | (val > 7000) { // TODO get the actual values
-                Err(String::from("Color temperature out of bounds"))
-            } else {
-                Ok(Self::SetCtAbx(val))
-            }
-        }
+	/// Create a new Command::SetCtAbx enum.
+	/// The color temperature must be between 1700 and 6500 K.
+	pub fn new_ct_abx(val: usize) -> Result<Self, String> {
+		if !(1700..=6500).contains(&val) {
+			Err(String::from(
+				"Color temperature out of bounds; must be between 1700 and 6500",
+			))
+		} else {
+			Ok(Self::SetCtAbx(val))
+		}
+	}
+
+	/// Create a new Command::SetRgb enum.
+	/// The value must fit in 24 bits (0xRRGGBB).
+	pub fn new_rgb(val: usize) -> Result<Self, String> {
+		if val > 0xFFFFFF {
+			Err(String::from("Invalid RGB value; must be at most 0xFFFFFF"))
+		} else {
+			Ok(Self::SetRgb(val))
+		}
+	}
+
+	/// Create a new Command::SetHsv enum.
+	/// The hue must be between 0 and 359, and the saturation between 0 and 100.
+	pub fn new_hsv(hue: usize, sat: usize) -> Result<Self, String> {
+		if hue > 359 {
+			Err(String::from("Hue out of bounds; must be between 0 and 359"))
+		} else if sat > 100 {
+			Err(String::from(
+				"Saturation out of bounds; must be between 0 and 100",
+			))
+		} else {
+			Ok(Self::SetHsv(hue, sat))
+		}
+	}
 
-        pub fn new_rgb(val: usize) -> Result<Self,String> {
-            if val > 0xFFFFFF { // TODO get the actual values
-                Err(String::from("Invalid RGB value; must be less than 0xFFFFFF"))
-            } else {
-                Ok(Self::SetRgb(val))
-            }
-        }
+	/// Create a new Command::SetBright enum.
+	/// The brightness must be between 1 and 100 percent.
+	pub fn new_bright(val: usize) -> Result<Self, String> {
+		if !(1..=100).contains(&val) {
+			Err(String::from(
+				"Brightness out of bounds; must be between 1 and 100",
+			))
+		} else {
+			Ok(Self::SetBright(val))
+		}
+	}
 
-        pub fn new_hsv(hue: usize, sat: usize) -> Result<Self,String> {
-            if (hue > 255) | (sat > 255) { // TODO get the actual values
-                Err(String::from("Hue and/or saturation out of bounds"))
-            } else {
-                Ok(Self::SetHsv(hue,sat))
-            }
-        }
+	/// Create a new Command::GetProp enum.
+	/// At least one property must be requested.
+	pub fn new_get_prop(props: Vec<String>) -> Result<Self, String> {
+		if props.is_empty() {
+			Err(String::from("At least one property must be requested"))
+		} else {
+			Ok(Self::GetProp(props))
+		}
+	}
 
-        // TODO finish the rest of the new_ methods
+	/// Create a new Command::StartCf enum.
+	///
+	/// The expression is a comma-separated list of tuples `duration,mode,value,brightness`:
+	/// - duration: length of the step in milliseconds, at least 50
+	/// - mode: 1 for RGB color, 2 for color temperature, 7 for a pause
+	/// - value: RGB value or color temperature (ignored for pauses)
+	/// - brightness: 1-100, or -1 to keep the current brightness (ignored for pauses)
+	///
+	/// Example: `1000,2,2700,100,500,1,255,10` fades to 2700 K and then to dim blue.
+	pub fn new_start_cf(count: usize, action: FlowAction, expr: &str) -> Result<Self, String> {
+		let vals = expr
+			.split(',')
+			.map(|v| v.trim().parse::<isize>())
+			.collect::<Result<Vec<isize>, _>>()
+			.map_err(|e| format!("Invalid flow expression: {e}"))?;
+		if vals.is_empty() || vals.len() % 4 != 0 {
+			return Err(String::from(
+				"Invalid flow expression; must consist of duration,mode,value,brightness tuples",
+			));
+		}
+		for (idx, tuple) in vals.chunks(4).enumerate() {
+			let &[dur, mode, val, bright] = tuple else {
+				unreachable!("chunks of 4")
+			};
+			let valid = match mode {
+				1 => (0..=0xFFFFFF).contains(&val),
+				2 => (1700..=6500).contains(&val),
+				7 => true,
+				_ => false,
+			};
+			if dur < 50 {
+				return Err(format!("Flow step {idx}: duration must be at least 50 ms"));
+			} else if !valid {
+				return Err(format!(
+					"Flow step {idx}: invalid mode {mode} or value {val} for that mode"
+				));
+			} else if mode != 7 && bright != -1 && !(1..=100).contains(&bright) {
+				return Err(format!(
+					"Flow step {idx}: brightness must be between 1 and 100, or -1"
+				));
+			}
+		}
+		let expr: Vec<String> = vals.iter().map(|v| v.to_string()).collect();
+		Ok(Self::StartCf(count, action, expr.join(",")))
+	}
 
-	/// Convert a Command to a String, given an integer to use as an ID.
-	pub fn to_request(&self, id: u8, eff: &Effect, dur: &Duration) -> String {
-		// Shadow dur (we care only about the millisecond value)
-		//let dur = dur.as_millis();
-		// Create a comma-separated list of parameters.
-		// For example, "on","smooth",500
-		// or 60,30,"sudden"
-		// If a method does NOT expect parameters, use an EMPTY STRING.
-		let param_part: String = match self {
-			Command::GetProp(params) => format!("\"{}\"", params.join("\",\"")), // quotes
-			Command::SetCtAbx(val) | Command::SetRgb(val) | Command::SetBright(val) => format!(r#"{},"{}",{}"#, val, eff, dur.as_millis()),
-                        //Command::SetCtAbx(ct_val) => format!(r#"{},"{}",{}"#, ct_val, eff, dur),
-			//Command::SetRgb(rgb) => format!(r#"{},"{}",{}"#, rgb, eff, dur),
-			Command::SetHsv(hue, sat) => format!(r#"{},{},"{}",{}"#, hue, sat, eff, dur.as_millis()),
+	/// Create a new Command::Raw enum.
+	/// The method name may only contain letters, digits and underscores, like those of the lamp.
+	pub fn new_raw(method: String, params: Vec<Value>) -> Result<Self, String> {
+		if method.is_empty()
+			|| !method
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '_')
+		{
+			Err(format!(
+				"Invalid method \"{method}\"; must consist of letters, digits and underscores"
+			))
+		} else {
+			Ok(Self::Raw { method, params })
+		}
+	}
+
+	/// Get the parameters of the request for a Command.
+	/// The effect and duration are only used by commands that change the color or the power.
+	pub fn params(&self, eff: &Effect, dur: &Duration) -> Vec<Value> {
+		let ms = dur.as_millis() as u64;
+		match self {
+			Command::GetProp(props) => props.iter().map(|prop| json!(prop)).collect(),
+			Command::SetCtAbx(val) | Command::SetRgb(val) | Command::SetBright(val) => {
+				vec![json!(val), json!(eff), json!(ms)]
+			},
+			Command::SetHsv(hue, sat) => vec![json!(hue), json!(sat), json!(eff), json!(ms)],
+			Command::SetPower(on) => {
+				let power = if *on { "on" } else { "off" };
+				vec![json!(power), json!(eff), json!(ms)]
+			},
 			// Convert OpaqueColor to r,g,b values
 			// combine them with u32::from_be_bytes
-			// and recurse back thru SetRgb enum
+			// and use the parameters of SetRgb
 			Command::SetOpaqueColor(col_wrap) => {
-				//let rgba: Rgba8 = col.to_rgba8();
 				let Rgba8 {
 					r: red,
 					g: green,
 					b: blue,
 					a: _,
 				} = col_wrap.color.to_rgba8();
-				//let rgb: u32 = (red << 16) + (green << 8) + blue;
 				let rgb = u32::from_be_bytes([0x0, red, green, blue]) as usize;
-				let rgb_cmd = Command::SetRgb(rgb);
-				rgb_cmd.to_request(id, eff, dur)
+				Command::SetRgb(rgb).params(eff, dur)
 			},
-			//Command::SetBright(bri) => format!(r#"{},"{}",{}"#, bri, eff, dur),
-			/*
-						Command::SetPower(pow, eff, dur, maybe_mod) => {
-				// handle optional
-				let mode = maybe_mod.unwrap_or_default(); // can't use mod
-				format!(r#"{},{},"{}",{}"#, pow, eff, dur, mode)
+			Command::StartCf(count, action, expr) => {
+				vec![json!(count), json!(*action as u8), json!(expr)]
 			},
-						*/
-			Command::Toggle => String::new(),
+			Command::StopCf | Command::Toggle => Vec::new(),
+			Command::Raw { params, .. } => params.clone(),
+		}
+	}
+
+	/// Convert a Command to a request line, given an integer to use as an ID.
+	pub fn to_request(&self, id: u8, eff: &Effect, dur: &Duration) -> String {
+		// The lamps only know the RGB value of an OpaqueColor
+		let method = match self {
+			Command::SetOpaqueColor(_) => Command::SetRgb(0).to_string(),
+			_ => self.to_string(),
 		};
-		format!(
-			concat!(r#"{{"id":{},"method":"{}","params":[{}]}}"#, "\r\n"),
-			id, self, param_part
-		)
+		let req = Request {
+			id: id.into(),
+			method,
+			params: self.params(eff, dur),
+		};
+		req.to_line()
+	}
+}
+
+/// Writes the name of the method sent to the lamp, e.g. `set_bright`.
+/// Command::Raw writes its own method, so any method can be sent.
+impl fmt::Display for Command {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Command::Raw { method, .. } => f.write_str(method),
+			_ => f.write_str(self.as_ref()),
+		}
+	}
+}
+
+/// A request sent to a lamp: `{"id":1,"method":"set_power","params":["on","smooth",500]}`.
+///
+/// Commands are turned into requests by Command::to_request().
+/// Deserializing reads the requests of clients, as the mock lamp does.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Request {
+	/// ID of the request, repeated in the reply of the lamp.
+	pub id: u64,
+	/// Name of the method.
+	pub method: String,
+	/// Parameters of the method.
+	#[serde(default)]
+	pub params: Vec<Value>,
+}
+
+impl Request {
+	/// Serialize the request as a line, terminated by CRLF as the lamps expect.
+	pub fn to_line(&self) -> String {
+		// Serializing a struct of strings, integers and Values cannot fail
+		let json = serde_json::to_string(self).expect("request is serializable");
+		format!("{json}\r\n")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	/// Read a request line back, as the mock lamp does.
+	fn parse(line: &str) -> Request {
+		assert!(line.ends_with("\r\n"), "{line:?} does not end with CRLF");
+		serde_json::from_str(line.trim_end()).unwrap()
+	}
+
+	#[test]
+	fn property_names_are_escaped() {
+		let name = r#"my "lamp" \ name"#;
+		let line = Command::GetProp(vec![name.to_string()]).to_request(
+			1,
+			&Effect::Smooth,
+			&Duration::ZERO,
+		);
+		assert_eq!(
+			parse(&line),
+			Request {
+				id: 1,
+				method: String::from("get_prop"),
+				params: vec![json!(name)]
+			}
+		);
+	}
+
+	#[test]
+	fn raw_params_are_sent_as_they_are() {
+		let params = vec![json!("color"), json!(65280), json!([1, "two"])];
+		let cmd = Command::new_raw(String::from("set_scene"), params.clone()).unwrap();
+		let line = cmd.to_request(2, &Effect::Smooth, &Duration::from_millis(500));
+		assert_eq!(
+			line,
+			"{\"id\":2,\"method\":\"set_scene\",\"params\":[\"color\",65280,[1,\"two\"]]}\r\n"
+		);
+		assert_eq!(parse(&line).params, params);
+	}
+
+	#[test]
+	fn opaque_color_is_sent_as_set_rgb() {
+		let color = OpaqueColorWrapper {
+			color: OpaqueColor::<Srgb>::new([1.0, 0.2, 0.0]),
+		};
+		let line = Command::SetOpaqueColor(color).to_request(
+			3,
+			&Effect::Smooth,
+			&Duration::from_millis(500),
+		);
+		assert_eq!(
+			parse(&line),
+			Request {
+				id: 3,
+				method: String::from("set_rgb"),
+				params: vec![json!(0xFF3300), json!("smooth"), json!(500)]
+			}
+		);
+	}
+
+	#[test]
+	fn raw_is_named_after_its_method() {
+		let cmd = Command::new_raw(String::from("set_scene"), Vec::new()).unwrap();
+		assert_eq!(cmd.to_string(), "set_scene");
+		assert_eq!(Command::SetBright(40).to_string(), "set_bright");
+		assert_eq!(Command::Toggle.to_string(), "toggle");
+		assert!(matches!(Command::from_str("raw"), Ok(Command::Raw { .. })));
+		assert!(Command::from_str("{method}").is_err());
+		assert!(Command::from_str("set_scene").is_err());
+	}
+
+	#[test]
+	fn missing_params_are_empty() {
+		let req: Request = serde_json::from_str(r#"{"id":4,"method":"toggle"}"#).unwrap();
+		assert_eq!(
+			req,
+			Request {
+				id: 4,
+				method: String::from("toggle"),
+				params: Vec::new()
+			}
+		);
 	}
 }
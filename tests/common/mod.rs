@@ -0,0 +1,100 @@
+//! Helpers shared by the tests that run a Lamp against a MockLamp.
+
+// Every test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use std::io;
+use std::time::{Duration, Instant};
+use yeerugina::lamp::{Lamp, LampReader, Response};
+use yeerugina::mock::MockLamp;
+use yeerugina::structs::{ConnectionSettings, Effect, Request};
+
+/// How long a test waits for the mock before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings that fail fast, so that a broken test does not hang.
+pub fn settings() -> ConnectionSettings {
+	ConnectionSettings {
+		read_timeout: Some(Duration::from_millis(100)),
+		write_timeout: Some(TIMEOUT),
+		conn_timeout: Duration::from_secs(1),
+		conn_tries: 1,
+		conn_wait: Duration::from_millis(10),
+	}
+}
+
+/// Create a Lamp for a mock, without connecting it.
+pub fn lamp(mock: &MockLamp) -> Lamp {
+	Lamp::new(
+		String::from("mock"),
+		mock.addr().to_string(),
+		Effect::Sudden,
+		Duration::from_millis(100),
+	)
+	.unwrap()
+}
+
+/// Connect a Lamp to a mock and create a reader for the connection.
+pub fn connect(mock: &MockLamp) -> (Lamp, LampReader) {
+	let mut lamp = lamp(mock);
+	lamp.connect(settings()).unwrap();
+	let reader = lamp.reader().unwrap();
+	(lamp, reader)
+}
+
+/// Read the next line sent by the lamp, waiting at most TIMEOUT.
+pub fn next_line(reader: &mut LampReader) -> Vec<u8> {
+	let deadline = Instant::now() + TIMEOUT;
+	while Instant::now() < deadline {
+		if let Some(line) = reader.read_line().unwrap() {
+			return line;
+		}
+	}
+	panic!("no line from the lamp within {TIMEOUT:?}");
+}
+
+/// Read the next response of the lamp, waiting at most TIMEOUT.
+pub fn next_response(reader: &mut LampReader) -> Response {
+	Lamp::parse_response(&next_line(reader)).unwrap()
+}
+
+/// Read until the answer to the request with the given ID, skipping notifications.
+pub fn reply(reader: &mut LampReader, id: u8) -> Response {
+	loop {
+		match next_response(reader) {
+			Response::Notification(_) => {},
+			resp @ (Response::Result { id: resp_id, .. } | Response::Error { id: resp_id, .. })
+				if resp_id == id =>
+			{
+				return resp;
+			},
+			resp => panic!("expected the answer to {id}, got {resp:?}"),
+		}
+	}
+}
+
+/// Read until the lamp closes the connection, failing if a line arrives instead.
+pub fn wait_closed(reader: &mut LampReader) -> io::Error {
+	let deadline = Instant::now() + TIMEOUT;
+	while Instant::now() < deadline {
+		match reader.read_line() {
+			Ok(None) => {},
+			Ok(Some(line)) => panic!("expected the connection to close, got {line:?}"),
+			Err(e) => return e,
+		}
+	}
+	panic!("the connection is still open after {TIMEOUT:?}");
+}
+
+/// Wait until the mock has received `count` requests and return them, waiting at most TIMEOUT.
+pub fn wait_requests(mock: &MockLamp, count: usize) -> Vec<Request> {
+	let deadline = Instant::now() + TIMEOUT;
+	while Instant::now() < deadline {
+		let requests = mock.requests();
+		if requests.len() >= count {
+			return requests;
+		}
+		std::thread::sleep(Duration::from_millis(10));
+	}
+	panic!("the mock did not get {count} requests within {TIMEOUT:?}");
+}
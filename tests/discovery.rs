@@ -0,0 +1,64 @@
+use std::time::Duration;
+use yeerugina::discovery::discover;
+use yeerugina::mock::ssdp::{Advert, MockSsdp};
+use yeerugina::mock::{MockLamp, MockState};
+use yeerugina::stateful::ColorMode;
+
+/// How long discover() collects responses; the mock answers at once.
+const WAIT: Duration = Duration::from_millis(300);
+
+#[test]
+fn discover_finds_mock_lamps() {
+	let kitchen = MockLamp::start(MockState::default()).unwrap();
+	let hall = MockLamp::start(MockState {
+		power: false,
+		bright: 40,
+		color_mode: ColorMode::Rgb,
+		rgb: 0xFF8800,
+		name: String::from("Hall"),
+		..MockState::default()
+	})
+	.unwrap();
+	let ssdp = MockSsdp::start(vec![
+		Advert::from_mock(0x15243f, &kitchen),
+		Advert::from_mock(0x15243e, &hall),
+	])
+	.unwrap();
+
+	let lamps = discover(ssdp.addr(), WAIT).unwrap();
+	assert_eq!(lamps.len(), 2);
+	let kitchen_found = &lamps[0];
+	assert_eq!(kitchen_found.id, "0x000000000015243f");
+	assert_eq!(kitchen_found.location, kitchen.addr());
+	assert_eq!(kitchen_found.model, "color");
+	assert_eq!(kitchen_found.props["power"], "on");
+	assert_eq!(kitchen_found.props["bright"], "100");
+	assert_eq!(kitchen_found.props["ct"], "4000");
+	assert!(
+		kitchen_found
+			.support
+			.iter()
+			.any(|method| method == "set_hsv")
+	);
+	assert!(
+		kitchen_found
+			.support
+			.iter()
+			.any(|method| method == "set_name")
+	);
+
+	let hall_found = &lamps[1];
+	assert_eq!(hall_found.id, "0x000000000015243e");
+	assert_eq!(hall_found.location, hall.addr());
+	assert_eq!(hall_found.name, "Hall");
+	assert_eq!(hall_found.props["power"], "off");
+	assert_eq!(hall_found.props["bright"], "40");
+	assert_eq!(hall_found.props["color_mode"], "1");
+	assert_eq!(hall_found.props["rgb"], (0xFF8800).to_string());
+}
+
+#[test]
+fn discover_without_lamps_finds_nothing() {
+	let ssdp = MockSsdp::start(Vec::new()).unwrap();
+	assert!(discover(ssdp.addr(), WAIT).unwrap().is_empty());
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use common::{connect, next_line, next_response, reply, wait_closed};
+use serde_json::json;
+use std::io;
+use std::time::{Duration, Instant};
+use yeerugina::lamp::{Lamp, Response};
+use yeerugina::mock::{Faults, MockLamp, MockState, QUOTA_PER_MINUTE};
+use yeerugina::structs::Command;
+
+/// A request that changes nothing, so that no notification follows its result.
+fn get_power() -> Command {
+	Command::GetProp(vec![String::from("power")])
+}
+
+/// Check that a request is answered with a result.
+fn assert_result(resp: Response, id: u8) {
+	assert!(
+		matches!(resp, Response::Result { id: resp_id, .. } if resp_id == id),
+		"expected a result for {id}, got {resp:?}"
+	);
+}
+
+/// Check that a request is answered with the error of a lamp over its quota.
+fn assert_quota_error(resp: Response, id: u8) {
+	match resp {
+		Response::Error {
+			id: resp_id,
+			message,
+			..
+		} => {
+			assert_eq!(resp_id, id);
+			assert_eq!(message, "client quota exceeded");
+		},
+		resp => panic!("expected a quota error for {id}, got {resp:?}"),
+	}
+}
+
+#[test]
+fn drop_after_closes_the_connection() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	mock.set_faults(Faults {
+		drop_after: Some(2),
+		..Faults::default()
+	});
+	let (mut lamp, mut reader) = connect(&mock);
+	for _ in 0..2 {
+		let id = lamp.send_cmd(get_power()).unwrap();
+		assert_result(reply(&mut reader, id), id);
+	}
+	assert_eq!(
+		wait_closed(&mut reader).kind(),
+		io::ErrorKind::UnexpectedEof
+	);
+
+	// Every connection gets the same number of answers
+	lamp.reconnect().unwrap();
+	let mut reader = lamp.reader().unwrap();
+	let id = lamp.send_cmd(get_power()).unwrap();
+	assert_result(reply(&mut reader, id), id);
+}
+
+#[test]
+fn reply_delay_holds_back_the_answer() {
+	let delay = Duration::from_millis(300);
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	mock.set_faults(Faults {
+		reply_delay: delay,
+		..Faults::default()
+	});
+	let (mut lamp, mut reader) = connect(&mock);
+	let sent = Instant::now();
+	let id = lamp.send_cmd(get_power()).unwrap();
+	// The read timeout of the lamp is shorter than the delay
+	assert_eq!(reader.read_line().unwrap(), None);
+	assert_result(reply(&mut reader, id), id);
+	assert!(sent.elapsed() >= delay);
+
+	mock.set_faults(Faults::default());
+	let sent = Instant::now();
+	let id = lamp.send_cmd(get_power()).unwrap();
+	assert_result(reply(&mut reader, id), id);
+	assert!(sent.elapsed() < delay);
+}
+
+#[test]
+fn malformed_reply_cannot_be_parsed() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	mock.set_faults(Faults {
+		malformed_replies: 1,
+		..Faults::default()
+	});
+	let (mut lamp, mut reader) = connect(&mock);
+	lamp.send_cmd(Command::SetPower(false)).unwrap();
+	assert!(Lamp::parse_response(&next_line(&mut reader)).is_err());
+	// The request was still executed
+	assert!(matches!(
+		next_response(&mut reader),
+		Response::Notification(_)
+	));
+	assert!(!mock.state().power);
+
+	let id = lamp.send_cmd(Command::SetPower(true)).unwrap();
+	assert_eq!(
+		next_response(&mut reader),
+		Response::Result {
+			id,
+			values: vec![json!("ok")]
+		}
+	);
+}
+
+#[test]
+fn quota_errors_skip_the_request() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	mock.set_faults(Faults {
+		quota_errors: 2,
+		..Faults::default()
+	});
+	let (mut lamp, mut reader) = connect(&mock);
+	for _ in 0..2 {
+		let id = lamp.send_cmd(Command::SetPower(false)).unwrap();
+		assert_quota_error(next_response(&mut reader), id);
+	}
+	assert!(mock.state().power);
+
+	let id = lamp.send_cmd(Command::SetPower(false)).unwrap();
+	assert_result(reply(&mut reader, id), id);
+	assert!(!mock.state().power);
+}
+
+#[test]
+fn close_on_connect_drops_new_connections() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	mock.set_faults(Faults {
+		close_on_connect: 1,
+		..Faults::default()
+	});
+	let (mut lamp, mut reader) = connect(&mock);
+	wait_closed(&mut reader);
+	assert!(mock.requests().is_empty());
+
+	lamp.reconnect().unwrap();
+	let mut reader = lamp.reader().unwrap();
+	let id = lamp.send_cmd(get_power()).unwrap();
+	assert_result(reply(&mut reader, id), id);
+}
+
+#[test]
+fn quota_limits_requests_per_connection() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	mock.set_faults(Faults {
+		quota: Some(QUOTA_PER_MINUTE),
+		..Faults::default()
+	});
+	let (mut lamp, mut reader) = connect(&mock);
+	for _ in 0..QUOTA_PER_MINUTE {
+		let id = lamp.send_cmd(get_power()).unwrap();
+		assert_result(reply(&mut reader, id), id);
+	}
+	let id = lamp.send_cmd(get_power()).unwrap();
+	assert_quota_error(reply(&mut reader, id), id);
+
+	// The quota is counted per connection, like on a real lamp
+	lamp.reconnect().unwrap();
+	let mut reader = lamp.reader().unwrap();
+	let id = lamp.send_cmd(get_power()).unwrap();
+	assert_result(reply(&mut reader, id), id);
+}
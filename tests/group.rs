@@ -0,0 +1,148 @@
+mod common;
+
+use common::{TIMEOUT, lamp, settings, wait_requests};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use yeerugina::config::Config;
+use yeerugina::group::{LampGroup, SharedLamp, connect_shared, lock_lamp};
+use yeerugina::lamp::Lamp;
+use yeerugina::mock::{MockLamp, MockState};
+use yeerugina::structs::{Command, Transition};
+
+/// Start a mock and connect a SharedLamp to it.
+fn shared(mock: &MockLamp) -> SharedLamp {
+	let mut lamp = lamp(mock);
+	lamp.connect(settings()).unwrap();
+	Arc::new(Mutex::new(lamp))
+}
+
+#[test]
+fn synced_groups_sharing_lamps_do_not_deadlock() {
+	let mocks: Vec<MockLamp> = (0..3)
+		.map(|_| MockLamp::start(MockState::default()).unwrap())
+		.collect();
+	let lamps: Vec<(String, SharedLamp)> = ["a", "b", "c"]
+		.into_iter()
+		.zip(mocks.iter())
+		.map(|(id, mock)| (String::from(id), shared(mock)))
+		.collect();
+	// The members are listed in opposite orders
+	let first = LampGroup::new(String::from("first"), lamps.clone());
+	let second = LampGroup::new(String::from("second"), lamps.into_iter().rev().collect());
+
+	let (tx, rx) = mpsc::channel();
+	for group in [first, second] {
+		let tx = tx.clone();
+		thread::spawn(move || {
+			for _ in 0..50 {
+				let res = group.send_cmd_synced(&Command::Toggle, &Transition::default());
+				assert!(res.result.is_ok(), "{res}");
+			}
+			tx.send(()).unwrap();
+		});
+	}
+	for _ in 0..2 {
+		rx.recv_timeout(TIMEOUT * 4)
+			.expect("the groups did not finish; are they waiting on each other?");
+	}
+	for mock in mocks.iter() {
+		assert_eq!(wait_requests(mock, 100).len(), 100);
+	}
+}
+
+#[test]
+fn synced_send_connects_members_first() {
+	let mocks: Vec<MockLamp> = (0..2)
+		.map(|_| MockLamp::start(MockState::default()).unwrap())
+		.collect();
+	let lamps: Vec<(String, SharedLamp)> = ["a", "b"]
+		.into_iter()
+		.zip(mocks.iter())
+		.map(|(id, mock)| (String::from(id), shared(mock)))
+		.collect();
+	lock_lamp(&lamps[1].1).unwrap().disconnect();
+	let group = LampGroup::new(String::from("group"), lamps.clone());
+
+	let res = group.send_cmd_synced(&Command::SetPower(false), &Transition::default());
+	assert!(res.result.is_ok(), "{res}");
+	assert!(res.skew.is_some());
+	let ids: Vec<&str> = res
+		.result
+		.results
+		.iter()
+		.map(|(id, _)| id.as_str())
+		.collect();
+	assert_eq!(ids, ["a", "b"]);
+	assert!(lock_lamp(&lamps[1].1).unwrap().is_connected());
+	for mock in mocks.iter() {
+		assert_eq!(wait_requests(mock, 1)[0].method, "set_power");
+	}
+}
+
+#[test]
+fn lamps_from_config_connect_without_connect_call() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let conf = Config::parse(&format!(
+		r#"
+[[lamp]]
+id = "desk"
+name = "Desk"
+ip = "{}"
+default-duration = "100ms"
+connection-tries = 1
+
+[mqtt]
+ip = "127.0.0.1:1883"
+topic = "yeelight"
+sub-id = 1
+"#,
+		mock.addr()
+	))
+	.unwrap();
+	let lamp: SharedLamp = Arc::new(Mutex::new(Lamp::from_config(&conf.lamps[0])));
+	assert!(!lock_lamp(&lamp).unwrap().is_connected());
+
+	let mut lamp = connect_shared(&lamp).unwrap();
+	assert!(lamp.is_connected());
+	lamp.send_cmd(Command::Toggle).unwrap();
+	assert_eq!(wait_requests(&mock, 1)[0].method, "toggle");
+}
+
+#[test]
+fn prepared_commands_are_written_with_their_ids() {
+	let mocks: Vec<MockLamp> = (0..2)
+		.map(|_| MockLamp::start(MockState::default()).unwrap())
+		.collect();
+	let lamps: Vec<(String, SharedLamp)> = ["a", "b"]
+		.into_iter()
+		.zip(mocks.iter())
+		.map(|(id, mock)| (String::from(id), shared(mock)))
+		.collect();
+	// Use up an ID on the first lamp, so that the members have different IDs
+	lock_lamp(&lamps[0].1)
+		.unwrap()
+		.prepare_cmd(Command::Toggle, &Transition::default());
+	let group = LampGroup::new(String::from("group"), lamps);
+
+	let prepared = group.prepare_cmd(&Command::SetBright(40), &Transition::default());
+	let ids: Vec<(String, u8)> = prepared
+		.iter()
+		.map(|(id, prep)| (id.clone(), prep.as_ref().unwrap().id))
+		.collect();
+	assert_eq!(ids, [(String::from("a"), 1), (String::from("b"), 0)]);
+	assert!(mocks.iter().all(|mock| mock.requests().is_empty()));
+
+	let res = group.write_prepared(prepared);
+	let written: Vec<(String, u8)> = res
+		.results
+		.into_iter()
+		.map(|(id, res)| (id, res.unwrap()))
+		.collect();
+	assert_eq!(written, ids);
+	for (mock, (_, id)) in mocks.iter().zip(ids) {
+		let requests = wait_requests(mock, 1);
+		assert_eq!(requests[0].id, u64::from(id));
+		assert_eq!(requests[0].method, "set_bright");
+	}
+}
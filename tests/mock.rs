@@ -0,0 +1,90 @@
+mod common;
+
+use common::{connect, next_response, reply};
+use serde_json::{Map, Value, json};
+use std::sync::{Arc, Mutex};
+use yeerugina::lamp::Response;
+use yeerugina::mock::{MockLamp, MockState};
+use yeerugina::stateful::StatefulLamp;
+use yeerugina::structs::Command;
+
+#[test]
+fn command_gets_ok() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (mut lamp, mut reader) = connect(&mock);
+	let id = lamp.send_cmd(Command::SetPower(false)).unwrap();
+	assert_eq!(
+		reply(&mut reader, id),
+		Response::Result {
+			id,
+			values: vec![json!("ok")]
+		}
+	);
+	assert!(!mock.state().power);
+	assert_eq!(mock.requests()[0].method, "set_power");
+}
+
+#[test]
+fn get_prop_returns_values() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (mut lamp, mut reader) = connect(&mock);
+	let props = vec![String::from("power"), String::from("bright")];
+	let id = lamp.send_cmd(Command::GetProp(props)).unwrap();
+	assert_eq!(
+		reply(&mut reader, id),
+		Response::Result {
+			id,
+			values: vec![json!("on"), json!("100")]
+		}
+	);
+}
+
+#[test]
+fn out_of_range_gets_error() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (mut lamp, mut reader) = connect(&mock);
+	let id = lamp.send_cmd(Command::SetBright(101)).unwrap();
+	match reply(&mut reader, id) {
+		Response::Error { id: resp_id, .. } => assert_eq!(resp_id, id),
+		resp => panic!("expected an error, got {resp:?}"),
+	}
+	// A failed command leaves the lamp as it was
+	assert_eq!(mock.state().bright, 100);
+}
+
+#[test]
+fn change_sends_notification() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (mut lamp, mut reader) = connect(&mock);
+	let id = lamp.send_cmd(Command::Toggle).unwrap();
+	assert!(
+		matches!(next_response(&mut reader), Response::Result { id: resp_id, .. } if resp_id == id)
+	);
+	let mut props = Map::new();
+	props.insert(String::from("power"), Value::from("off"));
+	assert_eq!(next_response(&mut reader), Response::Notification(props));
+}
+
+#[test]
+fn stateful_lamp_follows_changes() {
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (lamp, mut reader) = connect(&mock);
+	let mut stateful = StatefulLamp::new(Arc::new(Mutex::new(lamp)));
+
+	stateful.refresh().unwrap();
+	assert!(stateful.handle_response(&next_response(&mut reader)));
+	assert_eq!(stateful.state().power, Some(true));
+	assert_eq!(stateful.state().bright, Some(100));
+
+	let id = stateful
+		.lamp()
+		.lock()
+		.unwrap()
+		.send_cmd(Command::SetBright(40))
+		.unwrap();
+	// The result itself does not change the state, the notification does
+	assert!(!stateful.handle_response(&reply(&mut reader, id)));
+	assert!(stateful.handle_response(&next_response(&mut reader)));
+	assert_eq!(stateful.state().bright, Some(40));
+	assert_eq!(stateful.state().power, Some(true));
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::{lamp, reply, settings};
+use std::fs;
+use std::path::PathBuf;
+use yeerugina::lamp::{Lamp, LampReader, Response};
+use yeerugina::mock::{MockLamp, MockState};
+use yeerugina::record::{Direction, Recorder, Recording};
+use yeerugina::structs::Command;
+
+/// A file in the temporary directory, removed when the test ends.
+struct TempFile(PathBuf);
+
+impl TempFile {
+	fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("yeerugina-{}-{name}", std::process::id()));
+		let _ = fs::remove_file(&path);
+		Self(path)
+	}
+}
+
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.0);
+	}
+}
+
+/// Connect a Lamp to a mock, recording its traffic to a file.
+fn recorded(mock: &MockLamp, file: &TempFile) -> (Lamp, LampReader) {
+	let mut lamp = lamp(mock);
+	lamp.set_recorder(Some(Recorder::open(&file.0).unwrap()));
+	lamp.connect(settings()).unwrap();
+	let reader = lamp.reader().unwrap();
+	(lamp, reader)
+}
+
+#[test]
+fn recorded_session_replays_the_same() {
+	let file = TempFile::new("session.jsonl");
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (mut lamp, mut reader) = recorded(&mock, &file);
+	for cmd in [
+		Command::SetPower(false),
+		Command::SetBright(101),
+		Command::GetProp(vec![String::from("power")]),
+	] {
+		let id = lamp.send_cmd(cmd).unwrap();
+		reply(&mut reader, id);
+	}
+
+	let recording = Recording::load(&file.0).unwrap();
+	let sent = recording
+		.records
+		.iter()
+		.filter(|record| record.dir == Direction::Sent)
+		.count();
+	assert_eq!(sent, 3);
+	let responses = recording.parse_responses();
+	// The replies, and the notification about the power
+	assert_eq!(responses.len(), 4);
+	assert!(responses.iter().all(|(_, res)| res.is_ok()));
+	assert!(matches!(
+		responses.last().unwrap().1,
+		Ok(Response::Result { ref values, .. }) if values == &[serde_json::json!("off")]
+	));
+
+	let replay = recording
+		.replay(&MockLamp::start(MockState::default()).unwrap())
+		.unwrap();
+	assert!(replay.matches(), "{:?}", replay.first_difference());
+	assert_eq!(replay.replayed.len(), 4);
+}
+
+#[test]
+fn replay_shows_where_the_mock_differs() {
+	let file = TempFile::new("differs.jsonl");
+	let mock = MockLamp::start(MockState::default()).unwrap();
+	let (mut lamp, mut reader) = recorded(&mock, &file);
+	let id = lamp
+		.send_cmd(Command::GetProp(vec![String::from("power")]))
+		.unwrap();
+	reply(&mut reader, id);
+
+	let recording = Recording::load(&file.0).unwrap();
+	let off = MockState {
+		power: false,
+		..MockState::default()
+	};
+	let replay = recording.replay(&MockLamp::start(off).unwrap()).unwrap();
+	assert!(!replay.matches());
+	let (idx, recorded, replayed) = replay.first_difference().unwrap();
+	assert_eq!(idx, 0);
+	assert!(recorded.unwrap().contains("\"on\""));
+	assert!(replayed.unwrap().contains("\"off\""));
+}
+
+#[test]
+fn malformed_lines_are_rejected() {
+	let file = TempFile::new("malformed.jsonl");
+	let record =
+		r#"{"time":"2025-01-01T12:00:00Z","lamp":"Kitchen","conn":1,"dir":"sent","line":"{}\r\n"}"#;
+	for (content, line) in [
+		(format!("{record}\nnot json\n"), 2),
+		(format!("\n{}\n", record.replace("sent", "sideways")), 2),
+		(format!("{}\n", record.replace(r#""conn":1,"#, "")), 1),
+		(
+			format!("{}\n", record.replace("2025-01-01T12:00:00Z", "noon")),
+			1,
+		),
+	] {
+		fs::write(&file.0, content).unwrap();
+		let err = Recording::load(&file.0).unwrap_err();
+		assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+		assert!(
+			err.to_string()
+				.starts_with(&format!("Invalid record on line {line}:")),
+			"{err}"
+		);
+	}
+	// Empty lines are skipped
+	fs::write(&file.0, format!("\n{record}\n\n")).unwrap();
+	assert_eq!(Recording::load(&file.0).unwrap().records.len(), 1);
+}